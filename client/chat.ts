@@ -5,11 +5,69 @@ interface KolloquyAuthor {
     handle: string,
 }
 
-interface KolloquyMessageData {
-    action: "PUT" | "RENEW"
-    content?: string,
-    author: KolloquyAuthor,
-    chat?: string,
+// A file sent with a message, which is downloaded through the chat rather than from storage
+interface KolloquyAttachment {
+    id: string,
+    uploader: string,
+    name: string,
+    mime: string,
+    size: number,
+    thumbnail: boolean,
+    uploaded: string,
+}
+
+const PROTOCOL_VERSION = 1
+
+// The author id of messages sent by the server, like who joined or left
+const SYSTEM_AUTHOR = "system"
+
+type KolloquyClientFrame =
+    | { v: typeof PROTOCOL_VERSION, type: "put", chat: string, content: string, attachments?: string[], client_id?: string }
+    | { v: typeof PROTOCOL_VERSION, type: "edit", chat: string, id: number, content: string, client_id?: string }
+    | { v: typeof PROTOCOL_VERSION, type: "delete", chat: string, id: number, client_id?: string }
+    | { v: typeof PROTOCOL_VERSION, type: "renew" }
+
+type KolloquyServerFrame =
+    | { v: number, type: "message", chat: string, id: number, sent: string, author: KolloquyAuthor, content: string, attachments?: KolloquyAttachment[] }
+    | { v: number, type: "edited", chat: string, id: number, content: string, edited: string }
+    | { v: number, type: "removed", chat: string, id: number, redacted: boolean }
+    | { v: number, type: "ack", client_id?: string, chat: string, id: number, sent: string, source?: string }
+    | { v: number, type: "joined", chat: string }
+    | { v: number, type: "left", chat: string }
+    | { v: number, type: "renewed" }
+    | { v: number, type: "error", client_id?: string, code: number, message: string, details?: string }
+
+interface KolloquyHistoryMessage {
+    id: number,
+    sent: string,
+    is_sender: boolean,
+    author: KolloquyAuthor | null,
+    // Rendered from Kolloquy Markup, already sanitised by the server
+    content: string,
+    // The Kolloquy Markup it was written in, only on your own messages
+    source?: string,
+    edited?: string,
+    deleted: boolean,
+    redacted: boolean,
+    system: boolean,
+    attachments?: KolloquyAttachment[],
+}
+
+interface KolloquyHistoryPage {
+    success: boolean,
+    messages?: KolloquyHistoryMessage[],
+    has_more?: boolean,
+}
+
+interface KolloquyUpload {
+    success: boolean,
+    attachment?: KolloquyAttachment,
+    error?: { code: number, message: string, details?: string },
+}
+
+interface KolloquyRevisions {
+    success: boolean,
+    revisions?: { content: string, written: string }[],
 }
 
 const chatID = (document.getElementById("chatid")!! as HTMLDataElement).value;
@@ -20,6 +78,222 @@ const socket = new WebSocket("wss://kolloquy.com/chatws");
 const messages = document.getElementById("info")!! as HTMLDivElement;
 const sendButton = document.getElementById("send")!! as HTMLButtonElement;
 const messageInput = document.getElementById("messageInput")!! as HTMLInputElement;
+const attachButton = document.getElementById("attach")!! as HTMLButtonElement;
+const attachInput = document.getElementById("attachInput")!! as HTMLInputElement;
+const loadOlderButton = document.getElementById("loadOlder")!! as HTMLButtonElement;
+
+let oldestID = (document.getElementById("oldest")!! as HTMLDataElement).value;
+
+function tombstoneText(redacted: boolean): string {
+    return redacted ? "This message was removed by a moderator." : "This message was deleted."
+}
+
+// Replace a rendered message's content with a tombstone
+function markRemoved(message: Element, redacted: boolean) {
+    const content = message.querySelector(".content")!! as HTMLElement
+
+    content.textContent = tombstoneText(redacted)
+    content.style.fontStyle = "italic"
+
+    const marker = message.querySelector(".edited")!! as HTMLElement
+
+    marker.hidden = true
+    message.querySelector(".attachments")?.remove()
+    message.classList.add("removed")
+}
+
+// Link to each attachment, showing images by their thumbnails
+function renderAttachments(attachments: KolloquyAttachment[]): HTMLDivElement {
+    const div = document.createElement("div")
+
+    div.classList.add("attachments")
+
+    for (const attachment of attachments) {
+        const a = document.createElement("a")
+
+        a.href = `/chat/${chatID}/attachments/${attachment.id}`
+        a.target = "_blank"
+        a.rel = "noopener"
+
+        if (attachment.thumbnail) {
+            const img = document.createElement("img")
+
+            img.src = `/chat/${chatID}/attachments/${attachment.id}/thumbnail`
+            img.alt = attachment.name
+            img.title = attachment.name
+
+            a.appendChild(img)
+        } else {
+            a.textContent = attachment.name
+        }
+
+        div.appendChild(a)
+    }
+
+    return div
+}
+
+function renderSystemMessage(id: number, html: string): HTMLParagraphElement {
+    const p = document.createElement("p")
+
+    p.classList.add("system")
+    p.dataset.id = id.toString()
+    p.innerHTML = html
+
+    return p
+}
+
+function renderMessage(id: number, messageAuthor: KolloquyAuthor, source: string | undefined, html: string, isSelf: boolean, attachments: KolloquyAttachment[] = [], edited?: string, removed?: boolean): HTMLDivElement {
+    const div = document.createElement("div")
+
+    div.classList.add("chat")
+    div.dataset.id = id.toString()
+    div.dataset.self = isSelf.toString()
+    div.dataset.source = source ?? ""
+
+    if (isSelf) {
+        div.style.marginLeft = "5vw"
+    } else {
+        div.style.marginRight = "5vw"
+    }
+
+    const div2 = document.createElement("div")
+
+    div2.style.display = "grid"
+    div2.style.justifyContent = "left"
+    div2.style.alignItems = "center"
+    div2.style.textAlign = "left"
+    div2.style.verticalAlign = "central"
+    div2.style.marginTop = "auto"
+    div2.style.marginLeft = "1vmin"
+
+    div2.innerHTML = `<b style="margin: 0">${messageAuthor.handle}</b><div class="content">${html}</div><small class="edited" hidden>(edited)</small>`
+
+    if (attachments.length > 0) {
+        div2.querySelector(".content")!!.after(renderAttachments(attachments))
+    }
+
+    if (edited) {
+        const marker = div2.querySelector(".edited")!! as HTMLElement
+
+        marker.hidden = false
+        marker.title = edited
+    }
+
+    const avatar = document.createElement("svg")
+
+    avatar.innerHTML = messageAuthor.avatar
+
+    div.appendChild(div2)
+    div.appendChild(avatar)
+
+    // Only once the content is in the message, since this replaces it
+    if (removed !== undefined) {
+        markRemoved(div, removed)
+    }
+
+    return div
+}
+
+loadOlderButton.addEventListener("click", async () => {
+    loadOlderButton.disabled = true
+
+    const response = await fetch(`/chat/${chatID}/messages?before=${oldestID}`)
+    const page = await response.json() as KolloquyHistoryPage
+
+    loadOlderButton.disabled = false
+
+    if (!page.success || !page.messages) {
+        return
+    }
+
+    // Messages come oldest first, so insert them in reverse just after the button
+    for (const message of page.messages.slice().reverse()) {
+        if (message.system) {
+            loadOlderButton.after(renderSystemMessage(message.id, message.content))
+        } else {
+            // Messages from deleted users are shown without an author, as they are when the page loads
+            const messageAuthor = message.author ?? { avatar: "", id: "", is_self: false, handle: "" }
+
+            loadOlderButton.after(renderMessage(message.id, messageAuthor, message.source, message.content, message.is_sender, message.attachments, message.edited, message.deleted ? message.redacted : undefined))
+        }
+    }
+
+    if (page.messages.length > 0) {
+        oldestID = page.messages[0].id.toString()
+    }
+
+    loadOlderButton.hidden = !page.has_more
+})
+
+// Double clicking one of your own messages edits it
+messages.addEventListener("dblclick", e => {
+    const message = (e.target as HTMLElement).closest(".chat[data-id]") as HTMLDivElement | null
+
+    if (!message || message.dataset.self !== "true" || message.classList.contains("removed")) {
+        return
+    }
+
+    // Edit the markup the message was written in, rather than how it's shown
+    const current = message.dataset.source ?? ""
+    const content = prompt("Edit message", current)
+
+    if (content === null || content === current) {
+        return
+    }
+
+    sendFrame({
+        v: PROTOCOL_VERSION,
+        type: "edit",
+        chat: chatID,
+        id: parseInt(message.dataset.id!!),
+        content,
+    })
+})
+
+// Right clicking a message removes it, which only works for your own messages unless your role in the chat lets you redact
+messages.addEventListener("contextmenu", e => {
+    const message = (e.target as HTMLElement).closest(".chat[data-id]") as HTMLDivElement | null
+
+    if (!message || message.classList.contains("removed")) {
+        return
+    }
+
+    e.preventDefault()
+
+    if (!confirm("Remove this message?")) {
+        return
+    }
+
+    sendFrame({
+        v: PROTOCOL_VERSION,
+        type: "delete",
+        chat: chatID,
+        id: parseInt(message.dataset.id!!),
+    })
+})
+
+// Clicking the edited marker shows every revision of the message
+messages.addEventListener("click", async e => {
+    const marker = e.target as HTMLElement
+
+    if (!marker.classList.contains("edited")) {
+        return
+    }
+
+    const message = marker.closest(".chat[data-id]") as HTMLDivElement
+    const response = await fetch(`/chat/${chatID}/messages/${message.dataset.id}/history`)
+    const history = await response.json() as KolloquyRevisions
+
+    if (!history.success || !history.revisions) {
+        return
+    }
+
+    // Revisions are sanitised HTML, so only their text is shown
+    const text = (html: string) => new DOMParser().parseFromString(html, "text/html").body.textContent
+
+    alert(history.revisions.map(r => `${new Date(r.written).toLocaleString()}: ${text(r.content)}`).join("\n"))
+})
 
 let sendNotifications = false;
 
@@ -31,54 +305,141 @@ window.onclick = async _ => {
     window.onclick = _ => true
 }
 
+function sendFrame(frame: KolloquyClientFrame) {
+    socket.send(JSON.stringify(frame))
+}
+
 {
     (function try_send_renew() {
         try {
-            socket.send(JSON.stringify({
-                action: "RENEW",
-                author,
-            } satisfies KolloquyMessageData))
+            sendFrame({ v: PROTOCOL_VERSION, type: "renew" })
         } catch {
             setTimeout(try_send_renew, 1000)
         }
     })()
 }
 
+// Messages that haven't been acknowledged yet, by client id
+const pendingMessages = new Map<string, { content: string, attachments: KolloquyAttachment[] }>()
+let nextClientID = 0
+
+// The markup your own messages were written in, when it's acknowledged before the message is shown
+const ownSources = new Map<number, string>()
+
+// Files uploaded to the chat, which are sent with the next message
+let pendingAttachments: KolloquyAttachment[] = []
+
+function showPendingAttachments() {
+    attachButton.textContent = pendingAttachments.length > 0 ? `+${pendingAttachments.length}` : "+"
+    attachButton.title = pendingAttachments.length > 0 ? pendingAttachments.map(a => a.name).join("\n") : "Attach files"
+}
+
+attachButton.addEventListener("click", () => attachInput.click())
+
+attachInput.addEventListener("change", async () => {
+    attachButton.disabled = true
+
+    for (const file of Array.from(attachInput.files ?? [])) {
+        const response = await fetch(`/chat/${chatID}/attachments?name=${encodeURIComponent(file.name)}`, {
+            method: "POST",
+            body: file,
+        })
+        const upload = await response.json() as KolloquyUpload
+
+        if (upload.success && upload.attachment) {
+            pendingAttachments.push(upload.attachment)
+        } else {
+            alert(`${file.name}: ${upload.error?.message ?? "Couldn't upload this file."} ${upload.error?.details ?? ""}`)
+        }
+    }
+
+    attachInput.value = ""
+    attachButton.disabled = false
+    showPendingAttachments()
+})
+
 messageInput.onchange = _ => {
-    const data = {
-        content: messageInput.value,
-        action: "PUT",
-        author,
-        chat: chatID
+    if (messageInput.value === "" && pendingAttachments.length === 0) {
+        return
     }
 
-    socket.send(JSON.stringify(data))
+    const clientID = (nextClientID++).toString()
+
+    pendingMessages.set(clientID, { content: messageInput.value, attachments: pendingAttachments })
+
+    sendFrame({
+        v: PROTOCOL_VERSION,
+        type: "put",
+        chat: chatID,
+        content: messageInput.value,
+        attachments: pendingAttachments.map(a => a.id),
+        client_id: clientID,
+    })
 
     messageInput.value = ""
+    pendingAttachments = []
+    showPendingAttachments()
 }
 
 sendButton.addEventListener("click", messageInput.onchange)
 
 socket.addEventListener("message", (e) => {
-    const data = JSON.parse(e.data) as KolloquyMessageData
+    const data = JSON.parse(e.data) as KolloquyServerFrame
 
     console.log(data)
 
-    if (data.chat != chatID) {
-        return false
-    }
+    switch (data.type) {
+        case "renewed":
+            setTimeout(() => sendFrame({ v: PROTOCOL_VERSION, type: "renew" }), 1000)
 
-    switch (data.action) {
-        case "RENEW":
-            setInterval(() => {
-                socket.send(JSON.stringify({
-                    action: "RENEW",
-                    author,
-                } satisfies KolloquyMessageData))
-            }, 1000)
+            break
+        case "ack": {
+            pendingMessages.delete(data.client_id ?? "")
+
+            // Only the author is sent the markup their message was written in, which may be before or after the message itself
+            if (data.source !== undefined) {
+                const message = messages.querySelector(`.chat[data-id="${data.id}"]`) as HTMLDivElement | null
+
+                if (message) {
+                    message.dataset.source = data.source
+                } else {
+                    ownSources.set(data.id, data.source)
+                }
+            }
 
             break
-        case "PUT":
+        }
+        case "error":
+            console.error(`Kolloquy error ${data.code}: ${data.message}`, data.details)
+
+            // Give the failed message back, so it isn't lost
+            if (data.client_id !== undefined && pendingMessages.has(data.client_id)) {
+                const failed = pendingMessages.get(data.client_id)!!
+
+                if (messageInput.value === "") {
+                    messageInput.value = failed.content
+                }
+
+                if (pendingAttachments.length === 0) {
+                    pendingAttachments = failed.attachments
+                    showPendingAttachments()
+                }
+
+                pendingMessages.delete(data.client_id)
+            }
+
+            break
+        case "message":
+            if (data.chat != chatID) {
+                return false
+            }
+
+            if (data.author.id == SYSTEM_AUTHOR) {
+                messages.append(renderSystemMessage(data.id, data.content))
+
+                break
+            }
+
             setTimeout(async () => {
                 if (Notification && typeof Notification === "function" && !document.hasFocus()) {
                     const n = new Notification(`@${data.author.handle}`, {
@@ -94,38 +455,51 @@ socket.addEventListener("message", (e) => {
                 }
             }, 0)
 
-            const div = document.createElement("div")
-
-            div.classList.add("chat")
+            messages.append(renderMessage(data.id, data.author, ownSources.get(data.id), data.content, data.author.is_self || data.author.id == author.id, data.attachments))
+            ownSources.delete(data.id)
 
-            if (data.author.is_self || data.author.id == author.id) {
-                div.style.marginLeft = "5vw"
-            } else {
-                div.style.marginRight = "5vw"
+            break;
+        case "edited": {
+            if (data.chat != chatID) {
+                return false
             }
 
-            const div2 = document.createElement("div")
+            const message = messages.querySelector(`.chat[data-id="${data.id}"]`) as HTMLDivElement | null
 
-            div2.style.display = "grid"
-            div2.style.justifyContent = "left"
-            div2.style.alignItems = "center"
-            div2.style.textAlign = "left"
-            div2.style.verticalAlign = "central"
-            div2.style.marginTop = "auto"
-            div2.style.marginLeft = "1vmin"
+            if (!message) {
+                return false
+            }
 
-            div2.innerHTML = `<b style="margin: 0">${data.author.handle}</b><p style="margin: 0">${data.content}</p>`
+            const marker = message.querySelector(".edited")!! as HTMLElement
 
-            const avatar = document.createElement("svg")
+            message.querySelector(".content")!!.innerHTML = data.content
+            marker.hidden = false
+            marker.title = data.edited
 
-            avatar.innerHTML = data.author.avatar
+            break;
+        }
+        case "left":
+            // Messages can't be sent here anymore, but what's already shown stays
+            if (data.chat == chatID) {
+                messageInput.disabled = true
+                sendButton.disabled = true
+                attachButton.disabled = true
+            }
+
+            break
+        case "removed": {
+            if (data.chat != chatID) {
+                return false
+            }
 
-            div.appendChild(div2)
-            div.appendChild(avatar)
+            const message = messages.querySelector(`.chat[data-id="${data.id}"]`)
 
-            messages.append(div)
+            if (message) {
+                markRemoved(message, data.redacted)
+            }
 
             break;
+        }
     }
 
     return false
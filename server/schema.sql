@@ -0,0 +1,103 @@
+-- Kolloquy database schema
+--
+-- This is applied automatically to local SQLite databases when the server starts.
+-- For Cloudflare D1, apply it with `wrangler d1 execute <database> --file=schema.sql`.
+--
+-- The column order of each table matters, as some queries insert positionally.
+
+CREATE TABLE IF NOT EXISTS users (
+    email TEXT NOT NULL UNIQUE,
+    handle TEXT NOT NULL UNIQUE,
+    password TEXT NOT NULL,
+    age INTEGER NOT NULL,
+    country TEXT NOT NULL,
+    preferences TEXT NOT NULL,
+    suspended INTEGER NOT NULL,
+    age_verified INTEGER NOT NULL,
+    userid TEXT NOT NULL PRIMARY KEY,
+    phone_number TEXT NOT NULL,
+    joined TEXT NOT NULL,
+    description TEXT NOT NULL,
+    last_agent TEXT NOT NULL,
+    last_approx_country TEXT NOT NULL,
+    avatar_url TEXT NOT NULL,
+    email_verified INTEGER NOT NULL,
+    last_login TEXT NOT NULL,
+    failed_login_attempts INTEGER NOT NULL,
+    locked_until TEXT NOT NULL,
+    timezone TEXT NOT NULL,
+    -- Comma separated chat ids, only read by `server migrate` now that chat_members is used instead
+    enrolled_chats TEXT NOT NULL
+);
+
+-- Messages are append-only: each is given the next id in its chat when it's inserted
+CREATE TABLE IF NOT EXISTS messages (
+    chat_id TEXT NOT NULL,
+    id INTEGER NOT NULL,
+    author TEXT NOT NULL,
+    sent TEXT NOT NULL,
+    -- JSON array of revisions, newest first, each rendered and sanitised before it was stored
+    content TEXT NOT NULL,
+    -- JSON array of the Kolloquy Markup each revision was written in, only kept so its author can edit it again
+    source TEXT NOT NULL DEFAULT '[]',
+    -- JSON array of when each edit was made, newest first
+    edited TEXT NOT NULL DEFAULT '[]',
+    -- JSON tombstone once the message is removed, whose content is then emptied
+    deleted TEXT,
+    PRIMARY KEY (chat_id, id)
+);
+
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT NOT NULL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    created TEXT NOT NULL,
+    last_seen TEXT NOT NULL,
+    user_agent TEXT NOT NULL,
+    ip TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS sessions_by_user ON sessions (user_id);
+
+-- Personal access tokens for /api/v1, looked up by the SHA-256 of the token
+CREATE TABLE IF NOT EXISTS access_tokens (
+    id TEXT NOT NULL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    -- Comma separated, e.g. 'read,write'
+    scopes TEXT NOT NULL,
+    hash TEXT NOT NULL UNIQUE,
+    created TEXT NOT NULL,
+    last_used TEXT
+);
+
+CREATE INDEX IF NOT EXISTS access_tokens_by_user ON access_tokens (user_id);
+
+-- Files uploaded to chats, which are kept in the chats bucket under /attachments/<chat_id>/<id>
+CREATE TABLE IF NOT EXISTS attachments (
+    id TEXT NOT NULL PRIMARY KEY,
+    chat_id TEXT NOT NULL,
+    uploader TEXT NOT NULL,
+    name TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    thumbnail INTEGER NOT NULL,
+    uploaded TEXT NOT NULL,
+    -- The message it was sent with, or NULL until it's sent
+    message_id INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS attachments_by_message ON attachments (chat_id, message_id);
+
+-- Unsent attachments, which are limited per uploader and swept once they're old
+CREATE INDEX IF NOT EXISTS attachments_pending ON attachments (uploader, uploaded) WHERE message_id IS NULL;
+
+-- Who is a part of each chat. Chat objects in the chats bucket don't list their participants
+CREATE TABLE IF NOT EXISTS chat_members (
+    chat_id TEXT NOT NULL,
+    user_id TEXT NOT NULL,
+    -- 'owner', 'admin', 'member' or 'read_only'
+    role TEXT NOT NULL,
+    PRIMARY KEY (chat_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS chat_members_by_user ON chat_members (user_id);
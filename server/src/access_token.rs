@@ -0,0 +1,198 @@
+use crate::data::{column, datetime_column, DBQuery, FromRow, Query, QueryError, Row};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use utoipa::ToSchema;
+
+/// What an access token can do. Each scope includes the ones before it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Read chats, messages and users
+    Read,
+    /// Send, edit and remove messages, and create chats
+    Write,
+    /// Manage the user's access tokens
+    Admin,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Write => "write",
+            Self::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read" => Ok(Self::Read),
+            "write" => Ok(Self::Write),
+            "admin" => Ok(Self::Admin),
+            other => Err(format!("unknown scope '{other}'")),
+        }
+    }
+}
+
+/// A personal access token, which lets bots and integrations use `/api/v1` as a user.
+///
+/// Only a hash of the token is kept, so it's shown to the user once when it's created.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AccessToken {
+    pub id: String,
+    pub user_id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(skip_serializing)]
+    pub hash: String,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+}
+
+impl AccessToken {
+    /// A new token for a user, along with the secret to give them.
+    pub fn generate(user_id: &str, name: String, scopes: Vec<Scope>) -> (Self, String) {
+        let mut rng = rand::rng();
+        let mut id = [0; 9];
+        let mut secret = [0; 32];
+
+        rng.fill_bytes(&mut id);
+        rng.fill_bytes(&mut secret);
+
+        let secret = format!("kq_{}", URL_SAFE_NO_PAD.encode(secret));
+
+        let token = Self {
+            id: URL_SAFE_NO_PAD.encode(id),
+            user_id: user_id.to_string(),
+            name,
+            scopes,
+            hash: Self::hash(&secret),
+            created: Utc::now(),
+            last_used: None,
+        };
+
+        (token, secret)
+    }
+
+    /// The hash a token's secret is stored and looked up by.
+    ///
+    /// Secrets are random, so a fast hash is enough, unlike passwords.
+    pub fn hash(secret: &str) -> String {
+        URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+    }
+
+    /// Whether this token has `scope`, or one that includes it
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|granted| *granted >= scope)
+    }
+}
+
+impl FromRow for AccessToken {
+    fn from_row(row: &Row) -> Result<Self, QueryError<'static>> {
+        let scopes = column::<String>(row, "scopes")?
+            .split(',')
+            .filter(|scope| !scope.is_empty())
+            .map(Scope::from_str)
+            .collect::<Result<_, _>>()
+            .map_err(QueryError::MalformedRow)?;
+
+        let last_used = column::<Option<String>>(row, "last_used")?
+            .map(|_| datetime_column(row, "last_used"))
+            .transpose()?;
+
+        Ok(Self {
+            id: column(row, "id")?,
+            user_id: column(row, "user_id")?,
+            name: column(row, "name")?,
+            scopes,
+            hash: column(row, "hash")?,
+            created: datetime_column(row, "created")?,
+            last_used,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AccessTokenQuery {
+    Create(AccessToken),
+    GetByHash(String),
+    Touch(String, DateTime<Utc>),
+    ListForUser(String),
+    /// Delete a token, only if it belongs to the user (user id, token id)
+    Revoke(String, String),
+}
+
+impl Query for AccessTokenQuery {
+    fn has_result(&self) -> bool {
+        false
+    }
+}
+
+impl DBQuery for AccessTokenQuery {
+    type Output = Vec<AccessToken>;
+
+    fn to_sql_query_string(&self) -> (String, Vec<String>) {
+        match self {
+            Self::Create(token) => (
+                "INSERT INTO access_tokens (id, user_id, name, scopes, hash, created, last_used) VALUES (?, ?, ?, ?, ?, ?, NULL);".to_string(),
+                vec![
+                    token.id.clone(),
+                    token.user_id.clone(),
+                    token.name.clone(),
+                    token.scopes.iter().map(Scope::as_str).collect::<Vec<_>>().join(","),
+                    token.hash.clone(),
+                    token.created.to_rfc3339(),
+                ]
+            ),
+            Self::GetByHash(hash) => ("SELECT * FROM access_tokens WHERE hash = ?;".to_string(), vec![hash.clone()]),
+            Self::Touch(id, at) => ("UPDATE access_tokens SET last_used = ? WHERE id = ?;".to_string(), vec![at.to_rfc3339(), id.clone()]),
+            Self::ListForUser(user_id) => ("SELECT * FROM access_tokens WHERE user_id = ? ORDER BY created DESC;".to_string(), vec![user_id.clone()]),
+            Self::Revoke(user_id, id) => ("DELETE FROM access_tokens WHERE id = ? AND user_id = ?\nRETURNING *;".to_string(), vec![id.clone(), user_id.clone()]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::memory_db;
+
+    #[test]
+    fn scopes_include_lower_ones() {
+        let (token, _) = AccessToken::generate("xy12abc", "Bot".to_string(), vec![Scope::Write]);
+
+        assert!(token.allows(Scope::Read));
+        assert!(token.allows(Scope::Write));
+        assert!(!token.allows(Scope::Admin));
+    }
+
+    #[tokio::test]
+    async fn tokens_are_stored_hashed() {
+        let db = memory_db();
+
+        let (token, secret) = AccessToken::generate("xy12abc", "Bot".to_string(), vec![Scope::Read, Scope::Admin]);
+
+        assert!(secret.starts_with("kq_"));
+        assert_ne!(token.hash, secret);
+
+        db.execute(&AccessTokenQuery::Create(token.clone())).await.unwrap();
+
+        let found = db.execute(&AccessTokenQuery::GetByHash(AccessToken::hash(&secret))).await.unwrap();
+
+        assert_eq!(found, vec![token.clone()]);
+
+        // Users can only revoke their own tokens
+        assert!(db.execute(&AccessTokenQuery::Revoke("zz99zzz".to_string(), token.id.clone())).await.unwrap().is_empty());
+        assert_eq!(db.execute(&AccessTokenQuery::Revoke("xy12abc".to_string(), token.id.clone())).await.unwrap().len(), 1);
+        assert!(db.execute(&AccessTokenQuery::GetByHash(token.hash)).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,1336 @@
+//! The versioned JSON API at `/api/v1`, for bots and integrations.
+//!
+//! Requests are authenticated with a personal access token (see [`AccessToken`]) in an
+//! `Authorization: Bearer` header, rather than a session cookie.
+
+use crate::access_token::{AccessToken, AccessTokenQuery, Scope};
+use crate::attachment::{Attachment, AttachmentQuery, UploadParams, MAX_ATTACHMENT_BYTES};
+use crate::chat::{self, enrolled_chats, AddParticipantBody, Chat, ChatQuery, CreateChatBody, EditMessageBody, MessagePageParams, MessageQuery, RenameChatBody, SetRoleBody, SocketChatAuthor, MESSAGE_EDIT_WINDOW};
+use crate::data::{KolloquyDB, KolloquyR2, QueryError, KOLLOQUY_CHATS_BUCKET};
+use crate::error::ApiError;
+use crate::openapi::{invalid_body, ErrorResponse, SuccessResponse};
+use crate::protocol::ServerFrame;
+use crate::role::{Permission, Role};
+use crate::user::{User, UserQuery};
+use crate::ServerState;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures::future::join_all;
+use poem::http::{header, StatusCode};
+use poem::web::{Data, Path, Query};
+use poem::{delete, get, handler, post, put, Body, FromRequest, IntoResponse, Request, RequestBody, Response, Route};
+use s3::error::S3Error;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use utoipa::{OpenApi, ToSchema};
+
+/// The body of a request to create an access token.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct CreateTokenBody {
+    /// 1 to 40 characters
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+/// The body of a request to send a message.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SendMessageBody {
+    /// Can be empty if there are attachments
+    pub content: String,
+    /// The ids of files uploaded to the chat to send with the message, at most 10
+    #[serde(default)]
+    pub attachments: Vec<String>,
+}
+
+/// A user as anyone can see them, with their email if it's the user making the request.
+#[derive(Serialize, ToSchema)]
+pub struct UserView {
+    pub id: String,
+    pub handle: String,
+    pub description: String,
+    pub joined: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub email_verified: Option<bool>,
+}
+
+impl UserView {
+    fn public(user: &User) -> Self {
+        Self {
+            id: user.user_id.clone(),
+            handle: user.handle.clone(),
+            description: user.description.clone(),
+            joined: user.joined,
+            email: None,
+            email_verified: None,
+        }
+    }
+
+    fn private(user: &User) -> Self {
+        Self {
+            email: Some(user.email.clone()),
+            email_verified: Some(user.email_verified),
+            ..Self::public(user)
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChatView {
+    pub id: String,
+    pub name: String,
+    pub icon_url: String,
+    /// The user ids of everyone who is a part of the chat
+    pub participants: Vec<String>,
+    /// Everyone's role, by user id
+    pub roles: BTreeMap<String, Role>,
+}
+
+impl From<&Chat> for ChatView {
+    fn from(chat: &Chat) -> Self {
+        Self {
+            id: chat.id.clone(),
+            name: chat.name.clone(),
+            icon_url: chat.icon_url.clone(),
+            participants: chat.members.keys().cloned().collect(),
+            roles: chat.members.clone(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MessageView {
+    pub id: u64,
+    /// The author's user id
+    pub author: String,
+    pub sent: DateTime<Utc>,
+    /// The latest revision as HTML, rendered from Kolloquy Markup and sanitised when it was sent, or empty if the message was removed
+    pub content: String,
+    /// The latest revision as plain text
+    pub text: String,
+    /// The Kolloquy Markup the latest revision was written in, only on the user's own messages so they can edit it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// When the message was last edited
+    pub edited: Option<DateTime<Utc>>,
+    pub deleted: bool,
+    /// Whether the message was removed by an admin rather than its author
+    pub redacted: bool,
+    /// Files sent with the message, downloaded from `/chats/{id}/attachments/{attachment}`
+    pub attachments: Vec<Attachment>,
+    /// Every revision, oldest first, only when a single message is requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revisions: Option<Vec<RevisionView>>,
+}
+
+impl MessageView {
+    /// A message as `user_id` sees it.
+    pub fn new(message: &chat::Message, user_id: &str) -> Self {
+        Self {
+            id: message.id,
+            author: message.author.clone(),
+            sent: message.sent,
+            content: message.current().to_string(),
+            text: message.text(),
+            source: (message.author == user_id && message.deleted.is_none()).then(|| message.current_source().to_string()),
+            edited: message.edited.first().copied(),
+            deleted: message.deleted.is_some(),
+            redacted: message.is_redacted(),
+            attachments: message.attachments.clone(),
+            revisions: None,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RevisionView {
+    pub content: String,
+    pub written: DateTime<Utc>,
+}
+
+/// An access token as it's shown to its user, without its hash.
+#[derive(Serialize, ToSchema)]
+pub struct TokenView {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub created: DateTime<Utc>,
+    pub last_used: Option<DateTime<Utc>>,
+    /// The token itself, only when it's created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+impl From<&AccessToken> for TokenView {
+    fn from(token: &AccessToken) -> Self {
+        Self {
+            id: token.id.clone(),
+            name: token.name.clone(),
+            scopes: token.scopes.clone(),
+            created: token.created,
+            last_used: token.last_used,
+            token: None,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UserResponse {
+    pub success: bool,
+    pub user: UserView,
+}
+
+/// A user, with their role in a chat.
+#[derive(Serialize, ToSchema)]
+pub struct ParticipantView {
+    #[serde(flatten)]
+    pub user: UserView,
+    pub role: Role,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ParticipantsResponse {
+    pub success: bool,
+    pub participants: Vec<ParticipantView>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChatResponse {
+    pub success: bool,
+    pub chat: ChatView,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChatsResponse {
+    pub success: bool,
+    pub chats: Vec<ChatView>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MessageResponse {
+    pub success: bool,
+    pub message: MessageView,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AttachmentResponse {
+    pub success: bool,
+    pub attachment: Attachment,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct MessagesResponse {
+    pub success: bool,
+    /// Oldest first
+    pub messages: Vec<MessageView>,
+    /// Whether there are more messages past these, in the direction they were loaded
+    pub has_more: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokenResponse {
+    pub success: bool,
+    pub token: TokenView,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TokensResponse {
+    pub success: bool,
+    /// Newest first
+    pub tokens: Vec<TokenView>,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        current_user,
+        user_by_handle,
+        list_chats,
+        create_chat,
+        get_chat,
+        rename_chat,
+        delete_chat,
+        list_participants,
+        add_participant,
+        remove_participant,
+        set_role,
+        leave_chat,
+        list_messages,
+        send_message,
+        get_message,
+        edit_message,
+        delete_message,
+        upload_attachment,
+        get_attachment,
+        get_thumbnail,
+        list_tokens,
+        create_token,
+        revoke_token,
+    ),
+    components(schemas(CreateTokenBody, SendMessageBody, Scope, Role)),
+)]
+pub struct ApiV1Doc;
+
+pub fn routes() -> Route {
+    Route::new()
+        .at("/users/me", get(current_user))
+        .at("/users/:handle", get(user_by_handle))
+        .at("/chats", get(list_chats).post(create_chat))
+        .at("/chats/:id", get(get_chat).patch(rename_chat).delete(delete_chat))
+        .at("/chats/:id/participants", get(list_participants).post(add_participant))
+        .at("/chats/:id/participants/:handle", delete(remove_participant))
+        .at("/chats/:id/participants/:handle/role", put(set_role))
+        .at("/chats/:id/leave", post(leave_chat))
+        .at("/chats/:id/messages", get(list_messages).post(send_message))
+        .at("/chats/:id/messages/:message", get(get_message).patch(edit_message).delete(delete_message))
+        .at("/chats/:id/attachments", post(upload_attachment))
+        .at("/chats/:id/attachments/:attachment", get(get_attachment))
+        .at("/chats/:id/attachments/:attachment/thumbnail", get(get_thumbnail))
+        .at("/tokens", get(list_tokens).post(create_token))
+        .at("/tokens/:id", delete(revoke_token))
+}
+
+fn json_response(status: StatusCode, body: impl Serialize) -> Response {
+    Response::builder()
+        .body(serde_json::to_string(&body).unwrap())
+        .set_content_type("application/json")
+        .with_status(status)
+        .into_response()
+}
+
+/// Parse a JSON body, describing the schema of `T` if it doesn't match.
+async fn json_body<T: DeserializeOwned + ToSchema>(body: Body) -> Result<T, ApiError> {
+    let body_str = body.into_string().await?;
+
+    serde_json::from_str(&body_str).map_err(|_| invalid_body::<T>(&body_str))
+}
+
+/// How stale a token's `last_used` may get before a request updates it.
+const TOKEN_TOUCH_INTERVAL: TimeDelta = TimeDelta::minutes(1);
+
+/// The user an access token belongs to, from the request's `Authorization: Bearer` header.
+pub struct ApiUser {
+    pub token: AccessToken,
+    pub user: User,
+}
+
+impl ApiUser {
+    async fn from_header(req: &Request) -> Option<Self> {
+        let secret = req.header(header::AUTHORIZATION)?.strip_prefix("Bearer ")?.trim();
+
+        Self::from_secret_in(&KolloquyDB::new(), secret).await
+    }
+
+    async fn from_secret_in(db: &KolloquyDB<'_>, secret: &str) -> Option<Self> {
+        let token = db.execute(&AccessTokenQuery::GetByHash(AccessToken::hash(secret))).await.ok()?.pop()?;
+
+        // Only record use once a minute, rather than writing on every request
+        let now = Utc::now();
+
+        if token.last_used.is_none_or(|last_used| now - last_used >= TOKEN_TOUCH_INTERVAL) {
+            db.execute(&AccessTokenQuery::Touch(token.id.clone(), now)).await.ok();
+        }
+
+        let user = db.execute(&UserQuery::GetByID(token.user_id.clone())).await.ok()??;
+
+        Some(Self { token, user })
+    }
+
+    /// Reject the request unless the token has `scope`
+    pub fn require(&self, scope: Scope) -> Result<(), ApiError> {
+        if self.token.allows(scope) {
+            return Ok(());
+        }
+
+        Err(ApiError::MissingScope(scope))
+    }
+
+    /// Load a chat, rejecting the request unless the user's role in it has `permission`
+    async fn require_role(&self, chat: &str, permission: Permission) -> Result<(Chat, Role), ApiError> {
+        self.require_role_in(&KolloquyDB::new(), &KOLLOQUY_CHATS_BUCKET, chat, permission).await
+    }
+
+    async fn require_role_in(&self, db: &KolloquyDB<'_>, bucket: &KolloquyR2, chat: &str, permission: Permission) -> Result<(Chat, Role), ApiError> {
+        let chat = Chat::from_remote_in(db, bucket, chat.to_string()).await?;
+        let role = chat.require(&self.user.user_id, permission)?;
+
+        Ok((chat, role))
+    }
+}
+
+impl<'a> FromRequest<'a> for ApiUser {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> poem::Result<Self> {
+        if let Some(api_user) = Self::from_header(req).await {
+            return Ok(api_user);
+        }
+
+        Err(ApiError::NeedsAccessToken.into())
+    }
+}
+
+async fn find_user(handle: &str) -> Result<User, ApiError> {
+    let handle = handle.strip_prefix('@').unwrap_or(handle);
+
+    match KolloquyDB::new().execute(&UserQuery::GetByHandle(handle.to_string())).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) | Err(QueryError::NotFound) => Err(ApiError::UserNotFound),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The user the access token belongs to, with their email.
+#[utoipa::path(
+    get,
+    path = "/users/me",
+    tag = "users",
+    security(("token" = ["read"])),
+    responses(
+        (status = 200, body = UserResponse),
+        (status = 401, body = ErrorResponse, description = "No valid access token (6)"),
+    ),
+)]
+#[handler]
+async fn current_user(api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+
+    Ok(json_response(StatusCode::OK, UserResponse {
+        success: true,
+        user: UserView::private(&api.user),
+    }))
+}
+
+/// A user, by handle.
+#[utoipa::path(
+    get,
+    path = "/users/{handle}",
+    tag = "users",
+    params(("handle" = String, Path, description = "With or without the `@`")),
+    security(("token" = ["read"])),
+    responses(
+        (status = 200, body = UserResponse),
+        (status = 404, body = ErrorResponse, description = "No user with this handle (102)"),
+    ),
+)]
+#[handler]
+async fn user_by_handle(Path(handle): Path<String>, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+
+    let user = find_user(&handle).await?;
+
+    Ok(json_response(StatusCode::OK, UserResponse {
+        success: true,
+        user: UserView::public(&user),
+    }))
+}
+
+/// Every chat the user is a part of.
+#[utoipa::path(
+    get,
+    path = "/chats",
+    tag = "chats",
+    security(("token" = ["read"])),
+    responses((status = 200, body = ChatsResponse)),
+)]
+#[handler]
+async fn list_chats(api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+
+    let mut chats = Vec::new();
+
+    for chat in join_all(enrolled_chats(&api.user.user_id).await?.into_iter().map(Chat::from_remote)).await {
+        match chat {
+            Ok(chat) => chats.push(ChatView::from(&chat)),
+            // Skip chats that have been deleted since the user joined them
+            Err(ApiError::ChatNotFound) => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(json_response(StatusCode::OK, ChatsResponse {
+        success: true,
+        chats,
+    }))
+}
+
+/// Create a chat, with the user as its owner.
+#[utoipa::path(
+    post,
+    path = "/chats",
+    tag = "chats",
+    request_body = CreateChatBody,
+    security(("token" = ["write"])),
+    responses(
+        (status = 201, body = ChatResponse),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200) or name too long (206)"),
+        (status = 403, body = ErrorResponse, description = "Email not verified (5)"),
+        (status = 404, body = ErrorResponse, description = "A participant doesn't exist (102)"),
+    ),
+)]
+#[handler]
+async fn create_chat(body: Body, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    let body = json_body::<CreateChatBody>(body).await?;
+
+    if !api.user.email_verified {
+        return Err(ApiError::EmailNotVerified);
+    }
+
+    if body.name.len() > 20 {
+        return Err(ApiError::ChatNameTooLong);
+    }
+
+    let mut participants = Vec::new();
+
+    for handle in &body.participants {
+        let participant = find_user(&ammonia::clean_text(handle)).await?;
+
+        if participant.user_id != api.user.user_id {
+            participants.push(participant);
+        }
+    }
+
+    let (chat, _) = Chat::create(ammonia::clean_text(&body.name), &api.user, participants).await?;
+
+    join_sockets(&state, &chat).await;
+
+    Ok(json_response(StatusCode::CREATED, ChatResponse {
+        success: true,
+        chat: ChatView::from(&chat),
+    }))
+}
+
+/// A chat the user is a part of.
+#[utoipa::path(
+    get,
+    path = "/chats/{id}",
+    tag = "chats",
+    params(("id" = String, Path)),
+    security(("token" = ["read"])),
+    responses(
+        (status = 200, body = ChatResponse),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+        (status = 404, body = ErrorResponse, description = "No chat with this id (205)"),
+    ),
+)]
+#[handler]
+async fn get_chat(Path(id): Path<String>, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+
+    let (chat, _) = api.require_role(&id, Permission::Read).await?;
+
+    Ok(json_response(StatusCode::OK, ChatResponse {
+        success: true,
+        chat: ChatView::from(&chat),
+    }))
+}
+
+/// Rename a chat, if the user is one of its admins.
+#[utoipa::path(
+    patch,
+    path = "/chats/{id}",
+    tag = "chats",
+    params(("id" = String, Path)),
+    request_body = RenameChatBody,
+    security(("token" = ["write"])),
+    responses(
+        (status = 200, body = ChatResponse),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200) or name too long (206)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or not allowed by their role (8)"),
+        (status = 404, body = ErrorResponse, description = "No chat with this id (205)"),
+    ),
+)]
+#[handler]
+async fn rename_chat(Path(id): Path<String>, body: Body, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    rename_chat_response(&state, &api.user, &id, body).await
+}
+
+/// Delete a chat and all of its messages, if the user is its owner.
+#[utoipa::path(
+    delete,
+    path = "/chats/{id}",
+    tag = "chats",
+    params(("id" = String, Path)),
+    security(("token" = ["write"])),
+    responses(
+        (status = 200, body = SuccessResponse),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or not its owner (8)"),
+        (status = 404, body = ErrorResponse, description = "No chat with this id (205)"),
+    ),
+)]
+#[handler]
+async fn delete_chat(Path(id): Path<String>, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    delete_chat_response(&state, &api.user, &id).await
+}
+
+/// Everyone who is a part of a chat, with their roles.
+#[utoipa::path(
+    get,
+    path = "/chats/{id}/participants",
+    tag = "chats",
+    params(("id" = String, Path)),
+    security(("token" = ["read"])),
+    responses(
+        (status = 200, body = ParticipantsResponse),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+    ),
+)]
+#[handler]
+async fn list_participants(Path(id): Path<String>, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+
+    let (chat, _) = api.require_role(&id, Permission::Read).await?;
+
+    participants_response(StatusCode::OK, &chat).await
+}
+
+/// Add someone to a chat, if the user's role allows it.
+#[utoipa::path(
+    post,
+    path = "/chats/{id}/participants",
+    tag = "chats",
+    params(("id" = String, Path)),
+    request_body = AddParticipantBody,
+    security(("token" = ["write"])),
+    responses(
+        (status = 201, body = ParticipantsResponse, description = "Everyone who is now a part of the chat"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or not allowed by their role (8)"),
+        (status = 404, body = ErrorResponse, description = "No user with this handle (102)"),
+        (status = 409, body = ErrorResponse, description = "Already a part of this chat (219)"),
+    ),
+)]
+#[handler]
+async fn add_participant(Path(id): Path<String>, body: Body, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    add_participant_response(&state, &api.user, &id, body).await
+}
+
+/// Remove someone from a chat, if the user outranks them or is removing themselves.
+#[utoipa::path(
+    delete,
+    path = "/chats/{id}/participants/{handle}",
+    tag = "chats",
+    params(("id" = String, Path), ("handle" = String, Path)),
+    security(("token" = ["write"])),
+    responses(
+        (status = 200, body = ParticipantsResponse, description = "Everyone who is still a part of the chat"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1), not allowed by their role (8) or the owner leaving (9)"),
+        (status = 404, body = ErrorResponse, description = "No user with this handle (102), or they aren't a part of this chat (220)"),
+    ),
+)]
+#[handler]
+async fn remove_participant(Path((id, handle)): Path<(String, String)>, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    remove_participant_response(&state, &api.user, &id, &handle).await
+}
+
+/// Change someone's role in a chat, if the user outranks them and the new role. Only the owner can make someone else the owner.
+#[utoipa::path(
+    put,
+    path = "/chats/{id}/participants/{handle}/role",
+    tag = "chats",
+    params(("id" = String, Path), ("handle" = String, Path)),
+    request_body = SetRoleBody,
+    security(("token" = ["write"])),
+    responses(
+        (status = 200, body = ParticipantsResponse, description = "Everyone who is a part of the chat"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or not allowed by their role (8)"),
+        (status = 404, body = ErrorResponse, description = "No user with this handle (102), or they aren't a part of this chat (220)"),
+    ),
+)]
+#[handler]
+async fn set_role(Path((id, handle)): Path<(String, String)>, body: Body, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    set_role_response(&state, &api.user, &id, &handle, body).await
+}
+
+/// Leave a chat.
+#[utoipa::path(
+    post,
+    path = "/chats/{id}/leave",
+    tag = "chats",
+    params(("id" = String, Path)),
+    security(("token" = ["write"])),
+    responses(
+        (status = 200, body = SuccessResponse),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or its owner (9)"),
+    ),
+)]
+#[handler]
+async fn leave_chat(Path(id): Path<String>, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    leave_chat_response(&state, &api.user, &id).await
+}
+
+/// A page of a chat's messages.
+#[utoipa::path(
+    get,
+    path = "/chats/{id}/messages",
+    tag = "messages",
+    params(("id" = String, Path), MessagePageParams),
+    security(("token" = ["read"])),
+    responses(
+        (status = 200, body = MessagesResponse),
+        (status = 400, body = ErrorResponse, description = "Both before and after were given (207)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+    ),
+)]
+#[handler]
+async fn list_messages(Path(id): Path<String>, Query(params): Query<MessagePageParams>, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+
+    let (chat, _) = api.require_role(&id, Permission::Read).await?;
+
+    if params.before.is_some() && params.after.is_some() {
+        return Err(ApiError::ConflictingCursors);
+    }
+
+    let (messages, has_more) = chat.message_page(&params).await?;
+
+    Ok(json_response(StatusCode::OK, MessagesResponse {
+        success: true,
+        messages: messages.iter().map(|message| MessageView::new(message, &api.user.user_id)).collect(),
+        has_more,
+    }))
+}
+
+/// Send a message, which is also sent to everyone connected to the chat.
+#[utoipa::path(
+    post,
+    path = "/chats/{id}/messages",
+    tag = "messages",
+    params(("id" = String, Path)),
+    request_body = SendMessageBody,
+    security(("token" = ["write"])),
+    responses(
+        (status = 201, body = MessageResponse),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200), empty message (221) or message too long (222)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or read only (8)"),
+    ),
+)]
+#[handler]
+async fn send_message(Path(id): Path<String>, body: Body, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+    api.require_role(&id, Permission::Send).await?;
+
+    let body = json_body::<SendMessageBody>(body).await?;
+
+    let db = KolloquyDB::new();
+    let message = chat::Message::send(&db, &id, &api.user.user_id, &body.content, &body.attachments).await?;
+
+    state.rooms.send(&id, ServerFrame::Message {
+        chat: id.clone(),
+        id: message.id,
+        sent: message.sent,
+        author: SocketChatAuthor::for_user(&api.user).await,
+        content: message.current().to_string(),
+        attachments: message.attachments.clone(),
+    }).await;
+
+    Ok(json_response(StatusCode::CREATED, MessageResponse {
+        success: true,
+        message: MessageView::new(&message, &api.user.user_id),
+    }))
+}
+
+/// A single message, with every revision.
+#[utoipa::path(
+    get,
+    path = "/chats/{id}/messages/{message}",
+    tag = "messages",
+    params(("id" = String, Path), ("message" = u64, Path)),
+    security(("token" = ["read"])),
+    responses(
+        (status = 200, body = MessageResponse),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+        (status = 404, body = ErrorResponse, description = "No message with this id (209)"),
+    ),
+)]
+#[handler]
+async fn get_message(Path((id, message_id)): Path<(String, u64)>, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+    api.require_role(&id, Permission::Read).await?;
+
+    let db = KolloquyDB::new();
+    let query = MessageQuery::Get { chat: id.clone(), id: message_id };
+
+    let mut message = db.execute(&query).await?
+        .pop()
+        .ok_or(ApiError::MessageNotFound)?;
+
+    chat::Message::load_attachments(&db, &id, std::slice::from_mut(&mut message)).await?;
+
+    let revisions = message.revisions().into_iter().map(|(content, written)| RevisionView {
+        content: content.to_string(),
+        written,
+    }).collect();
+
+    Ok(json_response(StatusCode::OK, MessageResponse {
+        success: true,
+        message: MessageView {
+            revisions: Some(revisions),
+            ..MessageView::new(&message, &api.user.user_id)
+        },
+    }))
+}
+
+/// Edit one of the user's messages.
+#[utoipa::path(
+    patch,
+    path = "/chats/{id}/messages/{message}",
+    tag = "messages",
+    params(("id" = String, Path), ("message" = u64, Path)),
+    request_body = EditMessageBody,
+    security(("token" = ["write"])),
+    responses(
+        (status = 200, body = MessageResponse),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200), empty message (221) or message too long (222)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1), not the author (2), read only (8) or too late to edit (210)"),
+        (status = 404, body = ErrorResponse, description = "No message with this id (209)"),
+    ),
+)]
+#[handler]
+async fn edit_message(Path((id, message_id)): Path<(String, u64)>, body: Body, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+    api.require_role(&id, Permission::Send).await?;
+
+    let body = json_body::<EditMessageBody>(body).await?;
+
+    let db = KolloquyDB::new();
+    let message = chat::Message::edit(&db, &id, message_id, &api.user.user_id, body.content, *MESSAGE_EDIT_WINDOW).await?;
+
+    state.rooms.send(&id, ServerFrame::Edited {
+        chat: id.clone(),
+        id: message_id,
+        content: message.current().to_string(),
+        edited: message.edited[0],
+    }).await;
+
+    Ok(json_response(StatusCode::OK, MessageResponse {
+        success: true,
+        message: MessageView::new(&message, &api.user.user_id),
+    }))
+}
+
+/// Remove one of the user's messages, or anyone's if their role allows redacting.
+#[utoipa::path(
+    delete,
+    path = "/chats/{id}/messages/{message}",
+    tag = "messages",
+    params(("id" = String, Path), ("message" = u64, Path)),
+    security(("token" = ["write"])),
+    responses(
+        (status = 200, body = MessageResponse),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1), or not the author and unable to redact (2)"),
+        (status = 404, body = ErrorResponse, description = "No message with this id (209)"),
+    ),
+)]
+#[handler]
+async fn delete_message(Path((id, message_id)): Path<(String, u64)>, api: ApiUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    // Read only participants can still remove their own messages
+    let (_, role) = api.require_role(&id, Permission::Read).await?;
+    let can_redact = role.allows(Permission::Redact);
+
+    let db = KolloquyDB::new();
+    let message = chat::Message::delete(&db, &id, message_id, &api.user.user_id, can_redact).await?;
+
+    state.rooms.send(&id, ServerFrame::Removed {
+        chat: id.clone(),
+        id: message_id,
+        redacted: message.is_redacted(),
+    }).await;
+
+    Ok(json_response(StatusCode::OK, MessageResponse {
+        success: true,
+        message: MessageView::new(&message, &api.user.user_id),
+    }))
+}
+
+/// Upload a file to a chat, to send with a message.
+///
+/// The body is the file itself. Its type is worked out from its contents, and images get a thumbnail.
+#[utoipa::path(
+    post,
+    path = "/chats/{id}/attachments",
+    tag = "attachments",
+    params(("id" = String, Path), UploadParams),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "The file's contents"),
+    security(("token" = ["write"])),
+    responses(
+        (status = 201, body = AttachmentResponse),
+        (status = 400, body = ErrorResponse, description = "An image that couldn't be read (226)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or read only (8)"),
+        (status = 413, body = ErrorResponse, description = "The file is too large (224)"),
+        (status = 415, body = ErrorResponse, description = "The type of file can't be attached (225)"),
+    ),
+)]
+#[handler]
+async fn upload_attachment(Path(id): Path<String>, Query(params): Query<UploadParams>, body: Body, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Write)?;
+
+    upload_attachment_response(&api.user, &id, params, body).await
+}
+
+/// Download a file sent to a chat.
+#[utoipa::path(
+    get,
+    path = "/chats/{id}/attachments/{attachment}",
+    tag = "attachments",
+    params(("id" = String, Path), ("attachment" = String, Path)),
+    security(("token" = ["read"])),
+    responses(
+        (status = 200, content_type = "application/octet-stream", body = Vec<u8>, description = "The file, sent as its own type"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+        (status = 404, body = ErrorResponse, description = "No attachment with this id (223)"),
+    ),
+)]
+#[handler]
+async fn get_attachment(Path((id, attachment)): Path<(String, String)>, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+
+    attachment_response(&api.user, &id, &attachment, false).await
+}
+
+/// Download the thumbnail of an image sent to a chat.
+#[utoipa::path(
+    get,
+    path = "/chats/{id}/attachments/{attachment}/thumbnail",
+    tag = "attachments",
+    params(("id" = String, Path), ("attachment" = String, Path)),
+    security(("token" = ["read"])),
+    responses(
+        (status = 200, content_type = "image/png", body = Vec<u8>, description = "The thumbnail, at most 320 pixels wide and tall"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+        (status = 404, body = ErrorResponse, description = "No attachment with this id, or it isn't an image (223)"),
+    ),
+)]
+#[handler]
+async fn get_thumbnail(Path((id, attachment)): Path<(String, String)>, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Read)?;
+
+    attachment_response(&api.user, &id, &attachment, true).await
+}
+
+/// Open a new chat on the sockets of everyone in it.
+pub async fn join_sockets(state: &ServerState, chat: &Chat) {
+    for (user, role) in &chat.members {
+        state.sockets.join(&state.rooms, user, &chat.id, *role).await;
+    }
+}
+
+/// Post a system message to a chat, and send it to everyone connected to it.
+async fn announce(state: &ServerState, chat: &Chat, content: String) -> Result<(), ApiError> {
+    let message = chat.announce(content).await?;
+
+    state.rooms.send(&chat.id, ServerFrame::Message {
+        chat: chat.id.clone(),
+        id: message.id,
+        sent: message.sent,
+        author: SocketChatAuthor::system(),
+        content: message.current().to_string(),
+        attachments: Vec::new(),
+    }).await;
+
+    Ok(())
+}
+
+/// Everyone who is a part of a chat, with their roles.
+async fn participants_response(status: StatusCode, chat: &Chat) -> Result<Response, ApiError> {
+    let participants = chat.participants().await?;
+
+    Ok(json_response(status, ParticipantsResponse {
+        success: true,
+        participants: participants.iter().map(|user| ParticipantView {
+            user: UserView::public(user),
+            role: chat.role(&user.user_id).unwrap_or(Role::Member),
+        }).collect(),
+    }))
+}
+
+/// Add a user to a chat, if `inviter`'s role allows it, and open the chat on their sockets.
+pub async fn add_participant_response(state: &ServerState, inviter: &User, id: &str, body: Body) -> Result<Response, ApiError> {
+    let body = json_body::<AddParticipantBody>(body).await?;
+    let mut chat = Chat::from_remote(id.to_string()).await?;
+
+    chat.require(&inviter.user_id, Permission::Invite)?;
+
+    let user = find_user(&body.handle).await?;
+
+    chat.execute_as(&inviter.user_id, &mut ChatQuery::AddParticipant(&user)).await?;
+
+    announce(state, &chat, format!("@{} added @{}.", inviter.handle, user.handle)).await?;
+    state.sockets.join(&state.rooms, &user.user_id, &chat.id, Role::Member).await;
+
+    participants_response(StatusCode::CREATED, &chat).await
+}
+
+/// Remove a user from a chat, if `remover` outranks them or is removing themselves, and close the chat on their sockets.
+pub async fn remove_participant_response(state: &ServerState, remover: &User, id: &str, handle: &str) -> Result<Response, ApiError> {
+    let mut chat = Chat::from_remote(id.to_string()).await?;
+
+    chat.require(&remover.user_id, Permission::Read)?;
+
+    let user = find_user(handle).await?;
+
+    chat.execute_as(&remover.user_id, &mut ChatQuery::RemoveParticipant(&user)).await?;
+
+    let announcement = if user.user_id == remover.user_id {
+        format!("@{} left.", user.handle)
+    } else {
+        format!("@{} removed @{}.", remover.handle, user.handle)
+    };
+
+    announce(state, &chat, announcement).await?;
+    state.sockets.leave(&user.user_id, &chat.id).await;
+
+    participants_response(StatusCode::OK, &chat).await
+}
+
+/// Take a user out of a chat, and close the chat on their sockets.
+pub async fn leave_chat_response(state: &ServerState, user: &User, id: &str) -> Result<Response, ApiError> {
+    let mut chat = Chat::from_remote(id.to_string()).await?;
+    chat.execute_as(&user.user_id, &mut ChatQuery::RemoveParticipant(user)).await?;
+
+    announce(state, &chat, format!("@{} left.", user.handle)).await?;
+    state.sockets.leave(&user.user_id, &chat.id).await;
+
+    Ok(json_response(StatusCode::OK, SuccessResponse {
+        success: true,
+    }))
+}
+
+/// Change a user's role in a chat, if `actor` outranks both them and the role.
+pub async fn set_role_response(state: &ServerState, actor: &User, id: &str, handle: &str, body: Body) -> Result<Response, ApiError> {
+    let body = json_body::<SetRoleBody>(body).await?;
+    let mut chat = Chat::from_remote(id.to_string()).await?;
+
+    chat.require(&actor.user_id, Permission::Read)?;
+
+    let user = find_user(handle).await?;
+
+    chat.execute_as(&actor.user_id, &mut ChatQuery::SetRole { user: user.user_id.clone(), role: body.role }).await?;
+
+    let role = match body.role {
+        Role::ReadOnly => "read only",
+        Role::Member => "a member",
+        Role::Admin => "an admin",
+        Role::Owner => "the owner",
+    };
+
+    announce(state, &chat, format!("@{} made @{} {role}.", actor.handle, user.handle)).await?;
+
+    // Giving the chat away changes the old owner's role too
+    for id in [&user.user_id, &actor.user_id] {
+        if let Some(role) = chat.role(id) {
+            state.sockets.set_role(id, &chat.id, role).await;
+        }
+    }
+
+    participants_response(StatusCode::OK, &chat).await
+}
+
+/// Rename a chat, if `user`'s role allows it.
+pub async fn rename_chat_response(state: &ServerState, user: &User, id: &str, body: Body) -> Result<Response, ApiError> {
+    let body = json_body::<RenameChatBody>(body).await?;
+
+    if body.name.len() > 20 {
+        return Err(ApiError::ChatNameTooLong);
+    }
+
+    let mut chat = Chat::from_remote(id.to_string()).await?;
+    let name = ammonia::clean_text(&body.name);
+
+    chat.execute_as(&user.user_id, &mut ChatQuery::Rename(name.clone())).await?;
+
+    announce(state, &chat, format!("@{} renamed the chat to {name}.", user.handle)).await?;
+
+    Ok(json_response(StatusCode::OK, ChatResponse {
+        success: true,
+        chat: ChatView::from(&chat),
+    }))
+}
+
+/// Delete a chat, if `user` is its owner, and close it on everyone's sockets.
+pub async fn delete_chat_response(state: &ServerState, user: &User, id: &str) -> Result<Response, ApiError> {
+    let mut chat = Chat::from_remote(id.to_string()).await?;
+
+    chat.execute_as(&user.user_id, &mut ChatQuery::Delete).await?;
+
+    for participant in chat.members.keys() {
+        state.sockets.leave(participant, &chat.id).await;
+    }
+
+    Ok(json_response(StatusCode::OK, SuccessResponse {
+        success: true,
+    }))
+}
+
+/// Store a file `user` uploaded to a chat, if their role lets them send messages.
+pub async fn upload_attachment_response(user: &User, id: &str, params: UploadParams, body: Body) -> Result<Response, ApiError> {
+    let chat = Chat::from_remote(id.to_string()).await?;
+
+    // Check before reading the body, so people outside the chat can't make the server read their files
+    chat.require(&user.user_id, Permission::Send)?;
+
+    let data = body.into_bytes_limit(*MAX_ATTACHMENT_BYTES).await?;
+    let db = KolloquyDB::new();
+    let attachment = Attachment::upload(&db, &KOLLOQUY_CHATS_BUCKET, &chat.id, &user.user_id, params.name.as_deref(), data.to_vec()).await?;
+
+    Ok(json_response(StatusCode::CREATED, AttachmentResponse {
+        success: true,
+        attachment,
+    }))
+}
+
+/// Send a file from a chat, or its thumbnail, to `user` if they can read the chat.
+///
+/// Files are only ever sent through here rather than from the bucket, so leaving a chat loses access to its files.
+pub async fn attachment_response(user: &User, id: &str, attachment: &str, thumbnail: bool) -> Result<Response, ApiError> {
+    let chat = Chat::from_remote(id.to_string()).await?;
+
+    chat.require(&user.user_id, Permission::Read)?;
+
+    let query = AttachmentQuery::Get { chat: chat.id.clone(), id: attachment.to_string() };
+
+    let attachment = KolloquyDB::new().execute(&query).await?
+        .pop()
+        .filter(|attachment| attachment.visible_to(&user.user_id) && (attachment.thumbnail || !thumbnail))
+        .ok_or(ApiError::AttachmentNotFound)?;
+
+    let (path, content_type) = match (thumbnail, attachment.mime.as_str()) {
+        (true, _) => (attachment.thumbnail_path(), "image/png"),
+        // Text is only ever sniffed as text if it's UTF-8
+        (false, "text/plain") => (attachment.path(), "text/plain; charset=utf-8"),
+        (false, mime) => (attachment.path(), mime),
+    };
+
+    let data = match KOLLOQUY_CHATS_BUCKET.get_object(&path).await {
+        Ok(data) => data,
+        Err(S3Error::HttpFailWithBody(404, _)) => return Err(ApiError::AttachmentNotFound),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_DISPOSITION, attachment.content_disposition())
+        .header(header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+        // Nothing in a file can run, even if a browser is talked into opening it as a page
+        .header(header::CONTENT_SECURITY_POLICY, "default-src 'none'; sandbox")
+        .header(header::CACHE_CONTROL, "private, max-age=3600")
+        .body(data))
+}
+
+/// List a user's access tokens, newest first.
+pub async fn tokens_response(user_id: &str) -> Result<Response, ApiError> {
+    let tokens = KolloquyDB::new().execute(&AccessTokenQuery::ListForUser(user_id.to_string())).await?;
+
+    Ok(json_response(StatusCode::OK, TokensResponse {
+        success: true,
+        tokens: tokens.iter().map(TokenView::from).collect(),
+    }))
+}
+
+/// Create an access token for a user, responding with its secret, which is never shown again.
+pub async fn create_token_response(user_id: &str, body: Body) -> Result<Response, ApiError> {
+    let body = json_body::<CreateTokenBody>(body).await?;
+
+    if body.name.is_empty() || body.name.len() > 40 {
+        return Err(ApiError::AccessTokenName);
+    }
+
+    if body.scopes.is_empty() {
+        return Err(ApiError::AccessTokenScopes);
+    }
+
+    let (token, secret) = AccessToken::generate(user_id, ammonia::clean_text(&body.name), body.scopes);
+
+    KolloquyDB::new().execute(&AccessTokenQuery::Create(token.clone())).await?;
+
+    Ok(json_response(StatusCode::CREATED, TokenResponse {
+        success: true,
+        token: TokenView {
+            token: Some(secret),
+            ..TokenView::from(&token)
+        },
+    }))
+}
+
+/// Revoke one of a user's access tokens.
+pub async fn revoke_token_response(user_id: &str, id: &str) -> Result<Response, ApiError> {
+    let revoked = KolloquyDB::new().execute(&AccessTokenQuery::Revoke(user_id.to_string(), id.to_string())).await?;
+
+    if revoked.is_empty() {
+        return Err(ApiError::AccessTokenNotFound);
+    }
+
+    Ok(json_response(StatusCode::OK, SuccessResponse {
+        success: true,
+    }))
+}
+
+/// The user's access tokens.
+#[utoipa::path(
+    get,
+    path = "/tokens",
+    tag = "tokens",
+    security(("token" = ["admin"])),
+    responses((status = 200, body = TokensResponse)),
+)]
+#[handler]
+async fn list_tokens(api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Admin)?;
+
+    tokens_response(&api.user.user_id).await
+}
+
+/// Create an access token, which is only shown in this response.
+#[utoipa::path(
+    post,
+    path = "/tokens",
+    tag = "tokens",
+    request_body = CreateTokenBody,
+    security(("token" = ["admin"])),
+    responses(
+        (status = 201, body = TokenResponse),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200), name (217) or scopes (218)"),
+    ),
+)]
+#[handler]
+async fn create_token(body: Body, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Admin)?;
+
+    create_token_response(&api.user.user_id, body).await
+}
+
+/// Revoke one of the user's access tokens.
+#[utoipa::path(
+    delete,
+    path = "/tokens/{id}",
+    tag = "tokens",
+    params(("id" = String, Path)),
+    security(("token" = ["admin"])),
+    responses(
+        (status = 200, body = SuccessResponse),
+        (status = 404, body = ErrorResponse, description = "No access token with this id (216)"),
+    ),
+)]
+#[handler]
+async fn revoke_token(Path(id): Path<String>, api: ApiUser) -> Result<Response, ApiError> {
+    api.require(Scope::Admin)?;
+
+    revoke_token_response(&api.user.user_id, &id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::ChatMemberQuery;
+    use crate::data::MemoryStore;
+    use crate::test_support::{memory_db, test_user};
+    use serde_json::json;
+
+    /// The status and error code a rejected request is answered with.
+    async fn rejection(error: ApiError) -> (StatusCode, serde_json::Value) {
+        let response = error.into_response();
+        let status = response.status();
+        let json = serde_json::from_str::<serde_json::Value>(&response.into_body().into_string().await.unwrap()).unwrap();
+
+        assert_eq!(json["success"], false);
+        assert!(json["error"]["message"].is_string());
+
+        (status, json["error"]["code"].clone())
+    }
+
+    /// A stored user with a stored token that has `scopes`, and the token's secret.
+    async fn token_for(db: &KolloquyDB<'_>, user_id: &str, scopes: Vec<Scope>) -> (AccessToken, String) {
+        db.execute(&UserQuery::PutToDB(test_user(user_id))).await.unwrap();
+
+        let (token, secret) = AccessToken::generate(user_id, "Bot".to_string(), scopes);
+
+        db.execute(&AccessTokenQuery::Create(token.clone())).await.unwrap();
+
+        (token, secret)
+    }
+
+    #[tokio::test]
+    async fn read_tokens_cannot_write() {
+        let db = memory_db();
+        let (token, secret) = token_for(&db, "xy12abc", vec![Scope::Read]).await;
+
+        let api = ApiUser::from_secret_in(&db, &secret).await.unwrap();
+
+        assert!(api.require(Scope::Read).is_ok());
+        assert_eq!(rejection(api.require(Scope::Write).unwrap_err()).await, (StatusCode::FORBIDDEN, json!(7)));
+
+        // Use is recorded, but only once a minute
+        let used = db.execute(&AccessTokenQuery::GetByHash(token.hash.clone())).await.unwrap()[0].last_used.unwrap();
+
+        ApiUser::from_secret_in(&db, &secret).await.unwrap();
+
+        assert_eq!(db.execute(&AccessTokenQuery::GetByHash(token.hash)).await.unwrap()[0].last_used, Some(used));
+    }
+
+    #[tokio::test]
+    async fn revoked_tokens_are_rejected() {
+        let db = memory_db();
+        let (token, secret) = token_for(&db, "xy12abc", vec![Scope::Admin]).await;
+
+        assert!(ApiUser::from_secret_in(&db, &secret).await.is_some());
+
+        db.execute(&AccessTokenQuery::Revoke("xy12abc".to_string(), token.id)).await.unwrap();
+
+        assert!(ApiUser::from_secret_in(&db, &secret).await.is_none());
+        assert_eq!(rejection(ApiError::NeedsAccessToken).await, (StatusCode::UNAUTHORIZED, json!(6)));
+    }
+
+    #[tokio::test]
+    async fn only_participants_read_chats() {
+        let db = memory_db();
+        let bucket = KolloquyR2::new(MemoryStore::default());
+        let (chat, _) = Chat::new("Test Chat".to_string()).await;
+
+        chat.put_to(&bucket).await.unwrap();
+        db.execute(&ChatMemberQuery::Add { chat: chat.id.clone(), user: "me12mber".to_string(), role: Role::ReadOnly }).await.unwrap();
+
+        let (_, member) = token_for(&db, "me12mber", vec![Scope::Read]).await;
+        let (_, outsider) = token_for(&db, "ou12tsider", vec![Scope::Admin]).await;
+
+        let member = ApiUser::from_secret_in(&db, &member).await.unwrap();
+        let outsider = ApiUser::from_secret_in(&db, &outsider).await.unwrap();
+
+        let (_, role) = member.require_role_in(&db, &bucket, &chat.id, Permission::Read).await.unwrap();
+
+        assert_eq!(role, Role::ReadOnly);
+
+        let Err(error) = outsider.require_role_in(&db, &bucket, &chat.id, Permission::Read).await else {
+            panic!("people who aren't a part of a chat shouldn't be able to read it");
+        };
+
+        assert_eq!(rejection(error).await, (StatusCode::FORBIDDEN, json!(1)));
+    }
+
+    #[tokio::test]
+    async fn requests_without_a_token_are_unauthorised() {
+        let (req, mut body) = Request::builder().header(header::AUTHORIZATION, "Basic Zm9vOmJhcg==").finish().split();
+
+        let response = match ApiUser::from_request(&req, &mut body).await {
+            Ok(_) => panic!("requests without a bearer token should be rejected"),
+            Err(e) => e.into_response(),
+        };
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers().get(header::WWW_AUTHENTICATE).unwrap(), "Bearer");
+    }
+}
@@ -0,0 +1,635 @@
+use crate::chat::is_bidi_control;
+use crate::data::{bool_column, column, datetime_column, DBQuery, FromRow, KolloquyDB, KolloquyR2, Query, QueryError, Row, KOLLOQUY_CHATS_BUCKET};
+use crate::error::ApiError;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeDelta, Utc};
+use image::{ImageError, ImageFormat, ImageReader, Limits};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::sync::LazyLock;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+
+/// The most bytes a file can have to be attached, from `KOLLOQUY_MAX_ATTACHMENT_BYTES` (default 10 MiB).
+pub static MAX_ATTACHMENT_BYTES: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_MAX_ATTACHMENT_BYTES").ok()
+        .and_then(|bytes| bytes.parse().ok())
+        .unwrap_or(10 * 1024 * 1024)
+});
+
+/// The types of file that can be attached, from the comma separated `KOLLOQUY_ATTACHMENT_TYPES`.
+///
+/// A file's type is worked out from what's in it (see [`sniff`]), never from its name or the type it was uploaded as.
+pub static ATTACHMENT_TYPES: LazyLock<Vec<String>> = LazyLock::new(|| {
+    match std::env::var("KOLLOQUY_ATTACHMENT_TYPES") {
+        Ok(types) => types.split(',').map(str::trim).filter(|mime| !mime.is_empty()).map(str::to_string).collect(),
+        Err(_) => DEFAULT_ATTACHMENT_TYPES.iter().map(|mime| mime.to_string()).collect(),
+    }
+});
+
+/// The most attachments someone can have uploaded without sending, across every chat, from
+/// `KOLLOQUY_MAX_PENDING_ATTACHMENTS` (default 20).
+pub static MAX_PENDING_ATTACHMENTS: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_MAX_PENDING_ATTACHMENTS").ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(20)
+});
+
+/// How long an attachment is kept without being sent, from `KOLLOQUY_PENDING_ATTACHMENT_TTL_MINUTES` (default a day).
+pub static PENDING_ATTACHMENT_TTL: LazyLock<TimeDelta> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_PENDING_ATTACHMENT_TTL_MINUTES").ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .map_or(TimeDelta::days(1), TimeDelta::minutes)
+});
+
+/// How often attachments that were never sent are looked for and deleted.
+const ATTACHMENT_SWEEP_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+const DEFAULT_ATTACHMENT_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp", "application/pdf", "application/zip", "text/plain"];
+
+/// The most attachments a message can have.
+pub const MAX_MESSAGE_ATTACHMENTS: usize = 10;
+
+/// The most characters kept of a file's name.
+const MAX_NAME_LENGTH: usize = 100;
+
+/// Thumbnails fit in a square this many pixels wide.
+const THUMBNAIL_SIZE: u32 = 320;
+
+/// The widest or tallest image a thumbnail is made from, so a small file can't decode into a huge one.
+const MAX_IMAGE_SIDE: u32 = 10_000;
+
+/// The query string for uploading an attachment.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UploadParams {
+    /// The file's name, which it's shown and downloaded with
+    pub name: Option<String>,
+}
+
+/// A file uploaded to a chat, which is kept in the chats bucket.
+///
+/// Anyone who can send messages can upload one, but only the uploader can see it until it's sent with a message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, ToSchema)]
+pub struct Attachment {
+    pub id: String,
+    #[serde(skip)]
+    pub chat_id: String,
+    /// The uploader's user id
+    pub uploader: String,
+    pub name: String,
+    /// The file's type, found from its contents
+    pub mime: String,
+    /// In bytes
+    pub size: u64,
+    /// Whether it's an image with a thumbnail
+    pub thumbnail: bool,
+    pub uploaded: DateTime<Utc>,
+    /// The id of the message it was sent with
+    #[serde(skip)]
+    pub message: Option<u64>,
+}
+
+impl Attachment {
+    /// Check an uploaded file and store it in `bucket`, with a thumbnail if it's an image.
+    pub async fn upload(db: &KolloquyDB<'_>, bucket: &KolloquyR2, chat: &str, uploader: &str, name: Option<&str>, data: Vec<u8>) -> Result<Self, ApiError> {
+        if data.len() > *MAX_ATTACHMENT_BYTES {
+            return Err(ApiError::AttachmentTooLarge);
+        }
+
+        let mime = sniff(&data)
+            .filter(|mime| ATTACHMENT_TYPES.iter().any(|allowed| allowed == mime))
+            .ok_or(ApiError::UnsupportedAttachment)?;
+
+        let mut id = [0; 9];
+
+        rand::rng().fill_bytes(&mut id);
+
+        let mut attachment = Self {
+            id: URL_SAFE_NO_PAD.encode(id),
+            chat_id: chat.to_string(),
+            uploader: uploader.to_string(),
+            name: clean_name(name),
+            mime: mime.to_string(),
+            size: data.len() as u64,
+            thumbnail: false,
+            uploaded: Utc::now(),
+            message: None,
+        };
+
+        let thumbnail = if attachment.is_image() {
+            // Decoding is slow, and an image that can't be decoded is refused rather than stored without a thumbnail
+            let thumbnail = {
+                let data = data.clone();
+
+                tokio::task::spawn_blocking(move || thumbnail(&data)).await.unwrap()
+            };
+
+            attachment.thumbnail = true;
+
+            Some(thumbnail.map_err(|_| ApiError::UnreadableImage)?)
+        } else {
+            None
+        };
+
+        // The row is only added if the uploader has room for another unsent attachment, and before the files are
+        // stored, so uploads at the same time can't go over the limit
+        if db.execute(&AttachmentQuery::Create(attachment.clone())).await?.is_empty() {
+            return Err(ApiError::TooManyPendingAttachments);
+        }
+
+        let stored = async {
+            if let Some(thumbnail) = &thumbnail {
+                bucket.put_object(&attachment.thumbnail_path(), thumbnail).await?;
+            }
+
+            bucket.put_object(&attachment.path(), &data).await
+        };
+
+        if let Err(e) = stored.await {
+            db.execute(&AttachmentQuery::Remove { chat: attachment.chat_id.clone(), id: attachment.id.clone() }).await.ok();
+
+            return Err(e.into());
+        }
+
+        Ok(attachment)
+    }
+
+    /// Delete every attachment that was uploaded before `before` and never sent, along with its files.
+    ///
+    /// Each one is only forgotten once its files are gone, so any left behind by a failure are found again next time.
+    pub async fn sweep(db: &KolloquyDB<'_>, bucket: &KolloquyR2, before: DateTime<Utc>) -> Result<usize, ApiError> {
+        let expired = db.execute(&AttachmentQuery::Expired { before }).await?;
+
+        for attachment in &expired {
+            attachment.delete_files(bucket).await?;
+
+            db.execute(&AttachmentQuery::Remove { chat: attachment.chat_id.clone(), id: attachment.id.clone() }).await?;
+        }
+
+        Ok(expired.len())
+    }
+
+    /// Sweep attachments left unsent for longer than [`PENDING_ATTACHMENT_TTL`] every [`ATTACHMENT_SWEEP_PERIOD`],
+    /// for as long as the server runs.
+    pub async fn sweep_periodically() {
+        let mut interval = tokio::time::interval(ATTACHMENT_SWEEP_PERIOD);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = Self::sweep(&KolloquyDB::new(), &KOLLOQUY_CHATS_BUCKET, Utc::now() - *PENDING_ATTACHMENT_TTL).await {
+                e.log();
+            }
+        }
+    }
+
+    /// Where the file is kept in the chats bucket
+    pub fn path(&self) -> String {
+        format!("/attachments/{}/{}", self.chat_id, self.id)
+    }
+
+    /// Where the file's thumbnail is kept in the chats bucket, if it has one
+    pub fn thumbnail_path(&self) -> String {
+        format!("/attachments/{}/{}.thumb.png", self.chat_id, self.id)
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.mime.starts_with("image/")
+    }
+
+    /// Whether `user_id` can download this, as long as they can read the chat it's in
+    pub fn visible_to(&self, user_id: &str) -> bool {
+        self.message.is_some() || self.uploader == user_id
+    }
+
+    /// Delete the file and its thumbnail from `bucket`
+    pub async fn delete_files(&self, bucket: &KolloquyR2) -> Result<(), ApiError> {
+        bucket.delete_object(&self.path()).await?;
+
+        if self.thumbnail {
+            bucket.delete_object(&self.thumbnail_path()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The `Content-Disposition` to send the file with. Images are shown, and everything else is downloaded.
+    pub fn content_disposition(&self) -> String {
+        let kind = if self.is_image() { "inline" } else { "attachment" };
+
+        // Browsers that don't understand `filename*` get the name with anything that isn't plain ASCII replaced
+        let fallback = self.name.chars()
+            .map(|c| if (c.is_ascii_graphic() && c != '"' && c != '\\') || c == ' ' { c } else { '_' })
+            .collect::<String>();
+
+        let encoded = self.name.bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => (b as char).to_string(),
+                _ => format!("%{b:02X}"),
+            })
+            .collect::<String>();
+
+        format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+    }
+}
+
+/// The type of a file from what's in it.
+///
+/// Anything that isn't recognised is plain text if it's UTF-8 without control characters other than whitespace.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+    if data.is_empty() {
+        return None;
+    }
+
+    if let Some(kind) = infer::get(data) {
+        return Some(kind.mime_type());
+    }
+
+    let text = std::str::from_utf8(data).ok()?;
+
+    text.chars()
+        .all(|c| !c.is_control() || c.is_ascii_whitespace())
+        .then_some("text/plain")
+}
+
+/// Get an uploaded file's name ready to be shown and sent back in `Content-Disposition`.
+///
+/// Only the last part of a path is kept, without control or reordering characters.
+pub fn clean_name(name: Option<&str>) -> String {
+    let name = name.unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !(c.is_control() || is_bidi_control(*c)))
+        .take(MAX_NAME_LENGTH)
+        .collect::<String>();
+
+    match name.trim() {
+        "" => "attachment".to_string(),
+        name => name.to_string(),
+    }
+}
+
+/// Shrink an image to fit in a [`THUMBNAIL_SIZE`] square, as a PNG.
+fn thumbnail(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    let mut limits = Limits::default();
+
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+    reader.limits(limits);
+
+    let mut png = Vec::new();
+
+    reader.decode()?
+        .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        .to_rgba8()
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+
+    Ok(png)
+}
+
+impl FromRow for Attachment {
+    fn from_row(row: &Row) -> Result<Self, QueryError<'static>> {
+        Ok(Self {
+            id: column(row, "id")?,
+            chat_id: column(row, "chat_id")?,
+            uploader: column(row, "uploader")?,
+            name: column(row, "name")?,
+            mime: column(row, "mime")?,
+            size: column(row, "size")?,
+            thumbnail: bool_column(row, "thumbnail")?,
+            uploaded: datetime_column(row, "uploaded")?,
+            message: column(row, "message_id")?,
+        })
+    }
+}
+
+/// Queries over the attachments uploaded to chats, which are returned oldest first.
+#[derive(Debug, Clone)]
+pub enum AttachmentQuery {
+    /// Add an attachment, only if its uploader has fewer than [`MAX_PENDING_ATTACHMENTS`] that haven't been sent
+    Create(Attachment),
+
+    /// Forget an attachment whose files couldn't be stored, or have been deleted
+    Remove { chat: String, id: String },
+
+    /// Every attachment uploaded before `before` that hasn't been sent, so it can be deleted
+    Expired { before: DateTime<Utc> },
+
+    /// A single attachment in a chat
+    Get { chat: String, id: String },
+
+    /// Mark an attachment as sent with a message, only if `uploader` uploaded it after `uploaded_after` and it hasn't been
+    /// sent yet, so attachments that are being swept can't be sent
+    Attach { chat: String, id: String, uploader: String, message: u64, uploaded_after: DateTime<Utc> },
+
+    /// The attachments sent with the messages from `first` to `last` in a chat
+    ForMessages { chat: String, first: u64, last: u64 },
+
+    /// Forget the attachments sent with a message, returning them so their files can be deleted
+    RemoveForMessage { chat: String, message: u64 },
+
+    /// Forget every attachment in a chat, returning them so their files can be deleted
+    Clear { chat: String },
+}
+
+impl Query for AttachmentQuery {
+    fn has_result(&self) -> bool {
+        false
+    }
+}
+
+impl DBQuery for AttachmentQuery {
+    type Output = Vec<Attachment>;
+
+    fn to_sql_query_string(&self) -> (String, Vec<String>) {
+        match self {
+            Self::Create(attachment) => (
+                "INSERT INTO attachments (id, chat_id, uploader, name, mime, size, thumbnail, uploaded, message_id)\nSELECT ?, ?, ?, ?, ?, ?, ?, ?, NULL\nWHERE (SELECT COUNT(*) FROM attachments WHERE uploader = ? AND message_id IS NULL) < CAST(? AS INTEGER)\nRETURNING *;".to_string(),
+                vec![
+                    attachment.id.clone(),
+                    attachment.chat_id.clone(),
+                    attachment.uploader.clone(),
+                    attachment.name.clone(),
+                    attachment.mime.clone(),
+                    attachment.size.to_string(),
+                    (attachment.thumbnail as u8).to_string(),
+                    attachment.uploaded.to_rfc3339(),
+                    attachment.uploader.clone(),
+                    MAX_PENDING_ATTACHMENTS.to_string(),
+                ]
+            ),
+
+            Self::Remove { chat, id } => (
+                "DELETE FROM attachments WHERE chat_id = ? AND id = ?\nRETURNING *;".to_string(),
+                vec![chat.clone(), id.clone()]
+            ),
+
+            Self::Expired { before } => (
+                "SELECT * FROM attachments WHERE message_id IS NULL AND uploaded < ? ORDER BY uploaded ASC;".to_string(),
+                vec![before.to_rfc3339()]
+            ),
+
+            Self::Get { chat, id } => (
+                "SELECT * FROM attachments WHERE chat_id = ? AND id = ?;".to_string(),
+                vec![chat.clone(), id.clone()]
+            ),
+
+            Self::Attach { chat, id, uploader, message, uploaded_after } => (
+                "UPDATE attachments SET message_id = ? WHERE chat_id = ? AND id = ? AND uploader = ? AND message_id IS NULL AND uploaded >= ?\nRETURNING *;".to_string(),
+                vec![message.to_string(), chat.clone(), id.clone(), uploader.clone(), uploaded_after.to_rfc3339()]
+            ),
+
+            Self::ForMessages { chat, first, last } => (
+                "SELECT * FROM attachments WHERE chat_id = ? AND message_id BETWEEN ? AND ? ORDER BY uploaded ASC;".to_string(),
+                vec![chat.clone(), first.to_string(), last.to_string()]
+            ),
+
+            Self::RemoveForMessage { chat, message } => (
+                "DELETE FROM attachments WHERE chat_id = ? AND message_id = ?\nRETURNING *;".to_string(),
+                vec![chat.clone(), message.to_string()]
+            ),
+
+            Self::Clear { chat } => (
+                "DELETE FROM attachments WHERE chat_id = ?\nRETURNING *;".to_string(),
+                vec![chat.clone()]
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{MemoryStore, ObjectHead, ObjectStore};
+    use crate::test_support::memory_db;
+    use s3::error::S3Error;
+    use image::{Rgb, RgbImage};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut png = Vec::new();
+
+        RgbImage::from_pixel(width, height, Rgb([200, 40, 90]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+
+        png
+    }
+
+    #[test]
+    fn files_are_sniffed_by_their_contents() {
+        assert_eq!(sniff(&png(4, 4)), Some("image/png"));
+        assert_eq!(sniff(b"%PDF-1.7\n"), Some("application/pdf"));
+        assert_eq!(sniff("hello\r\nthere\t!".as_bytes()), Some("text/plain"));
+
+        // Executables aren't text, whatever they're called
+        let mut elf = b"\x7FELF\x02\x01\x01".to_vec();
+
+        elf.resize(64, 0);
+
+        assert_eq!(sniff(&elf), Some("application/x-executable"));
+        assert_eq!(sniff(b"\x00\x01\x02binary"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn names_are_cleaned() {
+        assert_eq!(clean_name(Some("../../etc/passwd")), "passwd");
+        assert_eq!(clean_name(Some("C:\\Users\\me\\cat.png")), "cat.png");
+        assert_eq!(clean_name(Some("evil\u{202E}gnp.exe")), "evilgnp.exe");
+        assert_eq!(clean_name(Some("  ")), "attachment");
+        assert_eq!(clean_name(None), "attachment");
+        assert_eq!(clean_name(Some(&"a".repeat(500))).len(), MAX_NAME_LENGTH);
+    }
+
+    #[test]
+    fn names_are_encoded_for_downloads() {
+        let attachment = Attachment {
+            id: "abc".to_string(),
+            chat_id: "ab12cde".to_string(),
+            uploader: "xy12abc".to_string(),
+            name: "résumé \"final\".pdf".to_string(),
+            mime: "application/pdf".to_string(),
+            size: 10,
+            thumbnail: false,
+            uploaded: Utc::now(),
+            message: None,
+        };
+
+        assert_eq!(
+            attachment.content_disposition(),
+            "attachment; filename=\"r_sum_ _final_.pdf\"; filename*=UTF-8''r%C3%A9sum%C3%A9%20%22final%22.pdf"
+        );
+    }
+
+    #[tokio::test]
+    async fn images_are_stored_with_thumbnails() {
+        let db = memory_db();
+        let bucket = KolloquyR2::new(MemoryStore::default());
+
+        let image = Attachment::upload(&db, &bucket, "ab12cde", "xy12abc", Some("photo.png"), png(1000, 500)).await.unwrap();
+
+        assert_eq!(image.mime, "image/png");
+        assert!(image.thumbnail);
+
+        let thumbnail = image::load_from_memory(&bucket.get_object(&image.thumbnail_path()).await.unwrap()).unwrap();
+
+        assert_eq!((thumbnail.width(), thumbnail.height()), (THUMBNAIL_SIZE, THUMBNAIL_SIZE / 2));
+        assert_eq!(bucket.get_object(&image.path()).await.unwrap(), png(1000, 500));
+
+        // Only the uploader sees it until it's sent, and only with a message they send
+        assert!(!image.visible_to("zz99zzz"));
+
+        let query = |uploader: &str| AttachmentQuery::Attach { chat: "ab12cde".to_string(), id: image.id.clone(), uploader: uploader.to_string(), message: 3, uploaded_after: image.uploaded };
+
+        assert!(db.execute(&query("zz99zzz")).await.unwrap().is_empty());
+        assert_eq!(db.execute(&query("xy12abc")).await.unwrap().len(), 1);
+        assert!(db.execute(&query("xy12abc")).await.unwrap().is_empty());
+
+        let sent = db.execute(&AttachmentQuery::ForMessages { chat: "ab12cde".to_string(), first: 0, last: 5 }).await.unwrap();
+
+        assert_eq!(sent.len(), 1);
+        assert!(sent[0].visible_to("zz99zzz"));
+
+        let text = Attachment::upload(&db, &bucket, "ab12cde", "xy12abc", Some("notes.txt"), b"just some notes".to_vec()).await.unwrap();
+
+        assert_eq!(text.mime, "text/plain");
+        assert!(!text.thumbnail);
+
+        for removed in db.execute(&AttachmentQuery::Clear { chat: "ab12cde".to_string() }).await.unwrap() {
+            removed.delete_files(&bucket).await.unwrap();
+        }
+
+        assert!(bucket.list_objects("attachments/ab12cde").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn bad_uploads_are_refused() {
+        let db = memory_db();
+        let bucket = KolloquyR2::new(MemoryStore::default());
+
+        let upload = async |data: Vec<u8>| Attachment::upload(&db, &bucket, "ab12cde", "xy12abc", None, data).await;
+
+        // An executable can't be disguised by its name or the type it's uploaded as
+        assert!(matches!(upload(b"MZ\x90\x00\x03\x00\x00\x00".to_vec()).await, Err(ApiError::UnsupportedAttachment)));
+        assert!(matches!(upload(vec![b'a'; *MAX_ATTACHMENT_BYTES + 1]).await, Err(ApiError::AttachmentTooLarge)));
+
+        // It looks like a PNG, but isn't one
+        let mut broken = png(4, 4);
+
+        broken.truncate(20);
+
+        assert!(matches!(upload(broken).await, Err(ApiError::UnreadableImage)));
+
+        // Images too big to decode safely aren't
+        assert!(matches!(upload(png(MAX_IMAGE_SIDE + 1, 1)).await, Err(ApiError::UnreadableImage)));
+
+        assert!(db.execute(&AttachmentQuery::Clear { chat: "ab12cde".to_string() }).await.unwrap().is_empty());
+        assert!(bucket.list_objects("attachments").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unsent_attachments_are_limited_and_swept() {
+        let db = memory_db();
+        let bucket = KolloquyR2::new(MemoryStore::default());
+
+        let upload = async |uploader: &str| Attachment::upload(&db, &bucket, "ab12cde", uploader, None, b"notes".to_vec()).await;
+
+        let mut pending = Vec::new();
+
+        for _ in 0..*MAX_PENDING_ATTACHMENTS {
+            pending.push(upload("xy12abc").await.unwrap());
+        }
+
+        assert!(matches!(upload("xy12abc").await, Err(ApiError::TooManyPendingAttachments)));
+
+        // Other people have their own limit, and sending one makes room for another
+        let other = upload("zz99zzz").await.unwrap();
+        let attach = |uploaded_after| AttachmentQuery::Attach { chat: "ab12cde".to_string(), id: pending[0].id.clone(), uploader: "xy12abc".to_string(), message: 1, uploaded_after };
+
+        // Attachments old enough to be swept can't be sent
+        assert!(db.execute(&attach(pending[0].uploaded + TimeDelta::seconds(1))).await.unwrap().is_empty());
+        assert_eq!(db.execute(&attach(pending[0].uploaded)).await.unwrap().len(), 1);
+
+        let latest = upload("xy12abc").await.unwrap();
+
+        // Only unsent attachments are swept, along with their files
+        let swept = Attachment::sweep(&db, &bucket, latest.uploaded + TimeDelta::seconds(1)).await.unwrap();
+
+        assert_eq!(swept, *MAX_PENDING_ATTACHMENTS + 1);
+
+        for attachment in pending.iter().skip(1).chain([&other, &latest]) {
+            assert!(bucket.get_object(&attachment.path()).await.is_err());
+        }
+
+        assert_eq!(bucket.get_object(&pending[0].path()).await.unwrap(), b"notes");
+        assert_eq!(Attachment::sweep(&db, &bucket, Utc::now()).await.unwrap(), 0);
+    }
+
+    /// A store that fails to delete one object once.
+    struct FlakyStore {
+        inner: MemoryStore,
+        fail: std::sync::Mutex<Option<String>>,
+    }
+
+    #[async_trait::async_trait]
+    impl ObjectStore for FlakyStore {
+        async fn put_object(&self, path: &str, data: &[u8]) -> Result<(), S3Error> {
+            self.inner.put_object(path, data).await
+        }
+
+        async fn get_object(&self, path: &str) -> Result<Vec<u8>, S3Error> {
+            self.inner.get_object(path).await
+        }
+
+        async fn delete_object(&self, path: &str) -> Result<(), S3Error> {
+            if self.fail.lock().unwrap().take_if(|fail| fail == path).is_some() {
+                return Err(S3Error::HttpFailWithBody(500, "bucket on fire".to_string()));
+            }
+
+            self.inner.delete_object(path).await
+        }
+
+        async fn head_object(&self, path: &str) -> Result<Option<ObjectHead>, S3Error> {
+            self.inner.head_object(path).await
+        }
+
+        async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, S3Error> {
+            self.inner.list_objects(prefix).await
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_sweeps_leave_what_they_missed_to_be_found_again() {
+        let db = memory_db();
+        let first = Attachment::upload(&db, &KolloquyR2::new(MemoryStore::default()), "ab12cde", "xy12abc", None, b"notes".to_vec()).await.unwrap();
+        let second = Attachment::upload(&db, &KolloquyR2::new(MemoryStore::default()), "ab12cde", "xy12abc", None, b"notes".to_vec()).await.unwrap();
+
+        let store = FlakyStore { inner: MemoryStore::default(), fail: std::sync::Mutex::new(Some(second.path())) };
+
+        for attachment in [&first, &second] {
+            store.put_object(&attachment.path(), b"notes").await.unwrap();
+        }
+
+        let bucket = KolloquyR2::new(store);
+        let before = second.uploaded + TimeDelta::seconds(1);
+
+        assert!(Attachment::sweep(&db, &bucket, before).await.is_err());
+
+        // The first was deleted and forgotten, but the second is still there to try again
+        assert!(bucket.get_object(&first.path()).await.is_err());
+        assert_eq!(bucket.get_object(&second.path()).await.unwrap(), b"notes");
+
+        let left = db.execute(&AttachmentQuery::Expired { before }).await.unwrap();
+
+        assert_eq!(left.iter().map(|attachment| &attachment.id).collect::<Vec<_>>(), vec![&second.id]);
+
+        assert_eq!(Attachment::sweep(&db, &bucket, before).await.unwrap(), 1);
+        assert!(bucket.get_object(&second.path()).await.is_err());
+    }
+}
@@ -0,0 +1,160 @@
+use crate::data::{KolloquyDB, QueryError};
+use crate::error::ApiError;
+use crate::ratelimit::client_ip;
+use crate::session::{Session, SessionStore};
+use crate::user::{User, UserQuery};
+use crate::{random_session_id, ServerState};
+use chrono::{TimeDelta, Utc};
+use poem::http::header;
+use poem::web::cookie::{Cookie, SameSite};
+use poem::web::Redirect;
+use poem::{FromRequest, IntoResponse, Request, RequestBody};
+use std::sync::{Arc, LazyLock};
+use std::time::Duration;
+
+/// How long a session lasts without being used, from `KOLLOQUY_SESSION_TTL_MINUTES`.
+///
+/// Every authenticated request restarts the clock.
+pub static SESSION_TTL: LazyLock<TimeDelta> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_SESSION_TTL_MINUTES").ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .map_or(TimeDelta::minutes(30), TimeDelta::minutes)
+});
+
+/// The `SSID` cookie for a session, which expires along with it.
+pub fn session_cookie(sid: &str) -> Cookie {
+    let mut cookie = Cookie::new("SSID", sid);
+
+    cookie.set_same_site(SameSite::Strict);
+    cookie.set_http_only(true);
+    cookie.set_secure(true);
+    cookie.set_path("/");
+    cookie.set_max_age(Duration::from_secs(SESSION_TTL.num_seconds().max(0) as u64));
+
+    cookie
+}
+
+/// Start a session for a user who just signed in, returning its id.
+pub async fn start_session(state: &ServerState, user: &User, req: &Request) -> Result<String, QueryError<'static>> {
+    let sid = random_session_id().await;
+
+    state.sessions.create(Session {
+        id: sid.clone(),
+        user_id: user.user_id.clone(),
+        created: Utc::now(),
+        last_seen: Utc::now(),
+        user_agent: req.header(header::USER_AGENT).unwrap_or_default().to_string(),
+        ip: client_ip(req),
+    }).await?;
+
+    Ok(sid)
+}
+
+/// A session that's still going, restarting its clock, or `None` if it was revoked or has been idle too long.
+///
+/// Sessions that have expired are ended here.
+pub async fn resume_session(sessions: &dyn SessionStore, id: &str) -> Option<Session> {
+    let session = sessions.get(id).await.ok()??;
+
+    if Utc::now() - session.last_seen > *SESSION_TTL {
+        sessions.revoke(&session.user_id, &session.id).await.ok();
+
+        return None;
+    }
+
+    sessions.touch(&session.id, Utc::now()).await.ok();
+
+    Some(session)
+}
+
+/// The signed in user making a request, from the session in its `SSID` cookie.
+///
+/// Pages that browsers navigate to are redirected to `/login` without one, and everything else gets a 401.
+pub struct AuthenticatedUser {
+    pub session: Session,
+    /// Looked up for every request, so it's never out of date
+    pub user: User,
+}
+
+impl AuthenticatedUser {
+    async fn from_cookie(req: &Request) -> Option<Self> {
+        let state = req.data::<Arc<ServerState>>()?;
+        let sid = req.cookie().get("SSID")?.value::<String>().ok()?;
+        let session = resume_session(state.sessions.as_ref(), &sid).await?;
+
+        // Slide the cookie's expiry along with the session's
+        req.cookie().add(session_cookie(&session.id));
+
+        let db = KolloquyDB::new();
+        let query = UserQuery::GetByID(session.user_id.clone());
+
+        let user = db.execute(&query).await.ok()??;
+
+        Some(Self { session, user })
+    }
+}
+
+impl<'a> FromRequest<'a> for AuthenticatedUser {
+    async fn from_request(req: &'a Request, _body: &mut RequestBody) -> poem::Result<Self> {
+        if let Some(authenticated) = Self::from_cookie(req).await {
+            return Ok(authenticated);
+        }
+
+        let wants_page = req.header(header::ACCEPT).is_some_and(|accept| accept.contains("text/html"));
+
+        if wants_page {
+            return Err(poem::Error::from_response(Redirect::temporary("/login").into_response()));
+        }
+
+        Err(ApiError::NotSignedIn.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session::MemorySessionStore;
+    use poem::http::StatusCode;
+
+    async fn reject(accept: &str) -> StatusCode {
+        let (req, mut body) = Request::builder().header(header::ACCEPT, accept).finish().split();
+
+        match AuthenticatedUser::from_request(&req, &mut body).await {
+            Ok(_) => panic!("requests without a session should be rejected"),
+            Err(e) => e.into_response().status(),
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_expire() {
+        let sessions = MemorySessionStore::default();
+        let session = |id: &str, last_seen| Session {
+            id: id.to_string(),
+            user_id: "xy12abc".to_string(),
+            created: last_seen,
+            last_seen,
+            user_agent: "Mozilla/5.0".to_string(),
+            ip: "::1".to_string(),
+        };
+
+        let idle = Utc::now() - *SESSION_TTL - TimeDelta::minutes(1);
+        let recent = Utc::now() - *SESSION_TTL / 2;
+
+        sessions.create(session("idle", idle)).await.unwrap();
+        sessions.create(session("recent", recent)).await.unwrap();
+
+        assert!(resume_session(&sessions, "idle").await.is_none());
+        assert!(sessions.get("idle").await.unwrap().is_none());
+
+        assert!(resume_session(&sessions, "recent").await.is_some());
+        assert!(sessions.get("recent").await.unwrap().unwrap().last_seen > recent);
+
+        assert!(resume_session(&sessions, "missing").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn pages_redirect_and_everything_else_is_unauthorised() {
+        assert_eq!(reject("text/html,application/xhtml+xml").await, StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(reject("*/*").await, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -1,29 +1,100 @@
-use crate::data::{KolloquyDB, R2Query, KOLLOQUY_CHATS_BUCKET, USER_AVATAR_BUCKET};
+use crate::attachment::{Attachment, AttachmentQuery, MAX_MESSAGE_ATTACHMENTS, PENDING_ATTACHMENT_TTL};
+use crate::data::{column, datetime_column, decompress_string, DBQuery, FromRow, KolloquyDB, KolloquyR2, ObjectData, ObjectQuery, Query, QueryError, Row, RowsAffected, KOLLOQUY_CHATS_BUCKET, USER_AVATAR_BUCKET};
+use crate::error::ApiError;
+use crate::markup;
 use crate::random_user_id;
+use crate::role::{Permission, Role};
 use crate::user::{User, UserQuery};
-use brotli::{BrotliCompress, BrotliDecompress};
-use chrono::{DateTime, Utc};
+use brotli::BrotliCompress;
+use chrono::{DateTime, TimeDelta, Utc};
 use rand::Rng;
+use s3::error::S3Error;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::ops::Deref;
+use std::sync::LazyLock;
 use svg::Document;
+use utoipa::{IntoParams, ToSchema};
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct CreateChatBody {
+    /// Handles of everyone else to add to the chat
     pub participants: Vec<String>,
+    /// At most 20 characters
     pub name: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
-pub struct SocketChatBody {
-    pub content: Option<String>,
-    pub action: String,
-    pub author: SocketChatAuthor,
-    pub chat: Option<String>,
+/// The body of a request to add someone to a chat.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct AddParticipantBody {
+    /// The handle of the user to add, with or without the `@`
+    pub handle: String,
+}
+
+/// The body of a request to rename a chat.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct RenameChatBody {
+    /// At most 20 characters
+    pub name: String,
+}
+
+/// The body of a request to change someone's role in a chat.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct SetRoleBody {
+    pub role: Role,
+}
+
+/// How many messages are loaded at once by default.
+pub const MESSAGE_PAGE_SIZE: u32 = 50;
+
+/// The most messages that can be loaded at once.
+pub const MAX_MESSAGE_PAGE_SIZE: u32 = 100;
+
+/// The query string for loading a page of a chat's history.
+///
+/// At most one of `before` and `after` can be given; with neither, the most recent messages are loaded.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct MessagePageParams {
+    /// Load messages sent before the message with this id
+    pub before: Option<u64>,
+    /// Load messages sent after the message with this id
+    pub after: Option<u64>,
+    /// How many messages to load, 50 by default and at most 100
+    pub limit: Option<u32>,
+}
+
+/// How long after sending a message its author can edit it, from `KOLLOQUY_EDIT_WINDOW_MINUTES`.
+///
+/// Messages can be edited forever if this isn't set.
+pub static MESSAGE_EDIT_WINDOW: LazyLock<Option<TimeDelta>> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_EDIT_WINDOW_MINUTES").ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .map(TimeDelta::minutes)
+});
+
+/// The most characters a message can have, from `KOLLOQUY_MAX_MESSAGE_LENGTH` (default 4000).
+pub static MAX_MESSAGE_LENGTH: LazyLock<usize> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_MAX_MESSAGE_LENGTH").ok()
+        .and_then(|length| length.parse().ok())
+        .unwrap_or(4000)
+});
+
+/// The body of a request to edit a message.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct EditMessageBody {
+    pub content: String,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+/// The author of system messages, like those saying who joined or left a chat.
+///
+/// User ids always have digits in them, so this can't be taken by a user.
+pub const SYSTEM_AUTHOR: &str = "system";
+
+/// The author of a message sent over a socket, which is always the socket's session user.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq)]
 pub struct SocketChatAuthor {
     pub avatar: String,
     pub id: String,
@@ -31,22 +102,53 @@ pub struct SocketChatAuthor {
     pub handle: String,
 }
 
+impl SocketChatAuthor {
+    /// The author for messages from `user`, with their avatar
+    pub async fn for_user(user: &User) -> Self {
+        let query = UserQuery::GetAvatar(user.clone());
+
+        let avatar = match USER_AVATAR_BUCKET.execute(&query).await {
+            Ok(compressed) => decompress_string(&compressed.into_bytes()).unwrap_or_default(),
+            Err(_) => String::new(),
+        };
+
+        Self {
+            id: user.user_id.clone(),
+            is_self: false,
+            handle: user.handle.clone(),
+            avatar,
+        }
+    }
+
+    /// The author of system messages
+    pub fn system() -> Self {
+        Self {
+            id: SYSTEM_AUTHOR.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Changes to a chat. Run them with [`Chat::execute_as`] to check that whoever asked for them is allowed to.
 pub enum ChatQuery<'a> {
     /// Upload the chat to the R2 bucket
     PutChat,
     
-    /// Add a message to the local chat
-    AddMessage(Message),
-    
     /// Add a participant to the chat
-    AddParticipant(&'a mut User),
+    AddParticipant(&'a User),
     
     /// Remove a participant from the chat
-    RemoveParticipant(&'a mut User),
+    RemoveParticipant(&'a User),
 
     PutIcon(Document),
+
+    /// Rename the chat
+    Rename(String),
+
+    /// Change a participant's role. Making someone the owner makes the old owner an admin
+    SetRole { user: String, role: Role },
     
-    /// Delete the chat
+    /// Delete the chat with all of its messages, taking everyone out of it
     Delete,
 }
 
@@ -77,7 +179,7 @@ pub fn create_chat_icon() -> Document {
         .set("id", "noise")
         .add(fe_turbelence);
 
-    let svg = Document::new()
+    Document::new()
         .set("width", "100px")
         .set("height", "100px")
         .set("style", format!("background: {gradient}"))
@@ -96,18 +198,670 @@ pub fn create_chat_icon() -> Document {
                 .set("height", "50%")
                 .set("width", "50%")
                 .set("fill", "#e9eaff")
-        );
-
-    svg
+        )
 }
 
 #[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct Message {
-    /// This contains the current message in the chat in LIFO order (last sent message is first in the vec)
+    /// This contains the current message in the chat in LIFO order (last sent message is first in the vec), each
+    /// revision rendered and sanitised by [`sanitise`] before it was stored
     pub content: Vec<String>,
+    /// The Kolloquy Markup each revision in `content` was written in, in the same order, which is only kept so the
+    /// author can edit it again
+    #[serde(default)]
+    pub source: Vec<String>,
     pub author: String,
     pub sent: DateTime<Utc>,
     pub id: u64,
+    /// When each edit was made, in the same order as `content` (the original content was written at `sent`, so has no entry)
+    #[serde(default)]
+    pub edited: Vec<DateTime<Utc>>,
+    /// Set once the message is removed, at which point `content` and `edited` are emptied
+    #[serde(default)]
+    pub deleted: Option<Tombstone>,
+    /// The files sent with the message, which are forgotten once it's removed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+/// What's left of a removed message, alongside its `id`, `author` and `sent`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Tombstone {
+    pub at: DateTime<Utc>,
+    /// Who removed it, which is someone other than the author if it was redacted
+    pub by: String,
+}
+
+/// Why a message couldn't be changed.
+#[derive(Debug)]
+pub enum MessageError<'a> {
+    NotFound,
+    NotAuthor,
+    EditWindowClosed,
+    /// Nothing was left once the content was cleaned up
+    Empty,
+    TooLong,
+    /// An attachment isn't in the chat, wasn't uploaded by the author or was already sent
+    AttachmentNotFound,
+    TooManyAttachments,
+    Database(QueryError<'a>),
+}
+
+impl<'a> From<QueryError<'a>> for MessageError<'a> {
+    fn from(error: QueryError<'a>) -> Self {
+        Self::Database(error)
+    }
+}
+
+/// Get a message's content ready to be stored and sent.
+///
+/// Line endings become `\n`, control characters other than new lines and tabs are removed, and so are the
+/// characters that reorder text, which can make a message or link read differently from what it is. What's left
+/// is trimmed, and has to fit in [`MAX_MESSAGE_LENGTH`].
+///
+/// What's returned is still the Kolloquy Markup that was written, which [`sanitise`] turns into what's stored and sent.
+pub fn clean_content(content: &str) -> Result<String, MessageError<'static>> {
+    let cleaned = content.replace("\r\n", "\n")
+        .chars()
+        .map(|c| if c == '\r' { '\n' } else { c })
+        .filter(|c| matches!(c, '\n' | '\t') || !(c.is_control() || is_bidi_control(*c)))
+        .collect::<String>();
+
+    let cleaned = cleaned.trim();
+
+    if cleaned.is_empty() {
+        return Err(MessageError::Empty);
+    }
+
+    if cleaned.chars().count() > *MAX_MESSAGE_LENGTH {
+        return Err(MessageError::TooLong);
+    }
+
+    Ok(cleaned.to_string())
+}
+
+/// Render a message's markup and clean it with ammonia, which is how every revision is stored and sent, so no client
+/// ever gets the markup that was written unless it's the author's.
+pub fn sanitise(source: &str) -> String {
+    markup::render_html(source)
+}
+
+pub fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{200E}' | '\u{200F}' | '\u{061C}' | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}')
+}
+
+impl Message {
+    /// A new message written by `author` in Kolloquy Markup, which is given its id when it's appended.
+    pub fn new(author: &str, source: String) -> Self {
+        let mut message = Self {
+            content: Vec::new(),
+            source: vec![source],
+            author: author.to_string(),
+            sent: Utc::now(),
+            id: 0,
+            edited: Vec::new(),
+            deleted: None,
+            attachments: Vec::new(),
+        };
+
+        message.content = vec![message.sanitise(message.current_source())];
+        message
+    }
+
+    /// A new message from [`SYSTEM_AUTHOR`], which is given its id when it's appended.
+    pub fn system(content: String) -> Self {
+        Self::new(SYSTEM_AUTHOR, content)
+    }
+
+    /// Sanitise a revision of this message. System messages are shown exactly as they are, rather than as markup.
+    fn sanitise(&self, source: &str) -> String {
+        if self.is_system() {
+            return sanitise(&markup::escape(source));
+        }
+
+        sanitise(source)
+    }
+
+    /// Messages kept on chats before they moved into the database only have the markup they were written in, so keep
+    /// that as their source and sanitise it. Only [`migrate_legacy_chats`] needs this.
+    fn sanitise_legacy(&mut self) {
+        if self.source.len() != self.content.len() {
+            self.source = std::mem::take(&mut self.content);
+            self.content = self.source.iter().map(|source| self.sanitise(source)).collect();
+        }
+    }
+
+    /// Whether this was sent by the server rather than a user.
+    pub fn is_system(&self) -> bool {
+        self.author == SYSTEM_AUTHOR
+    }
+
+    /// The message as it currently reads, sanitised, which is empty once it's removed.
+    pub fn current(&self) -> &str {
+        self.content.first().map_or("", String::as_str)
+    }
+
+    /// The Kolloquy Markup the message currently reads as, which is only for its author to edit.
+    pub fn current_source(&self) -> &str {
+        self.source.first().map_or("", String::as_str)
+    }
+
+    /// The message as it currently reads, without its markup.
+    pub fn text(&self) -> String {
+        if self.is_system() {
+            return self.current_source().to_string();
+        }
+
+        markup::render_text(self.current_source())
+    }
+
+    /// Whether the message was removed by someone other than its author.
+    pub fn is_redacted(&self) -> bool {
+        self.deleted.as_ref().is_some_and(|tombstone| tombstone.by != self.author)
+    }
+
+    /// Every sanitised revision of the message with when it was written, oldest first.
+    pub fn revisions(&self) -> Vec<(&str, DateTime<Utc>)> {
+        let written = self.edited.iter().copied().chain([self.sent]);
+
+        let mut revisions = self.content.iter().map(String::as_str).zip(written).collect::<Vec<_>>();
+
+        revisions.reverse();
+        revisions
+    }
+
+    /// Clean up and sanitise a new message's content and append it to a chat with the attachments `author` uploaded to it,
+    /// returning it with its id.
+    pub async fn send<'a>(db: &KolloquyDB<'a>, chat: &str, author: &str, content: &str, attachments: &[String]) -> Result<Self, MessageError<'a>> {
+        let mut attachments = attachments.to_vec();
+
+        attachments.sort();
+        attachments.dedup();
+
+        if attachments.len() > MAX_MESSAGE_ATTACHMENTS {
+            return Err(MessageError::TooManyAttachments);
+        }
+
+        let content = match clean_content(content) {
+            // Attachments can be sent on their own
+            Err(MessageError::Empty) if !attachments.is_empty() => String::new(),
+            content => content?,
+        };
+
+        // Attachments about to be swept can't be sent
+        let uploaded_after = Utc::now() - *PENDING_ATTACHMENT_TTL;
+
+        // Check the attachments first, so the message isn't sent without them
+        for id in &attachments {
+            let attachment = db.execute(&AttachmentQuery::Get { chat: chat.to_string(), id: id.clone() }).await?.pop();
+
+            if !attachment.is_some_and(|attachment| attachment.uploader == author && attachment.message.is_none() && attachment.uploaded >= uploaded_after) {
+                return Err(MessageError::AttachmentNotFound);
+            }
+        }
+
+        let message = Self::new(author, content);
+
+        let mut message = db.execute(&MessageQuery::Append { chat: chat.to_string(), message }).await?
+            .pop()
+            .ok_or(MessageError::NotFound)?;
+
+        for id in attachments {
+            let query = AttachmentQuery::Attach { chat: chat.to_string(), id, uploader: author.to_string(), message: message.id, uploaded_after };
+
+            message.attachments.extend(db.execute(&query).await?);
+        }
+
+        Ok(message)
+    }
+
+    /// Fill in the attachments sent with `messages`, which are all from `chat` and in order of their ids.
+    pub async fn load_attachments<'a>(db: &KolloquyDB<'a>, chat: &str, messages: &mut [Self]) -> Result<(), QueryError<'a>> {
+        let (Some(first), Some(last)) = (messages.first(), messages.last()) else {
+            return Ok(());
+        };
+
+        let query = AttachmentQuery::ForMessages { chat: chat.to_string(), first: first.id, last: last.id };
+
+        for attachment in db.execute(&query).await? {
+            if let Some(message) = messages.iter_mut().find(|message| Some(message.id) == attachment.message) {
+                message.attachments.push(attachment);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Push a new revision of a message, if `editor` wrote it and it is still within `window` of being sent.
+    pub async fn edit<'a>(db: &KolloquyDB<'a>, chat: &str, id: u64, editor: &str, content: String, window: Option<TimeDelta>) -> Result<Self, MessageError<'a>> {
+        let content = clean_content(&content)?;
+
+        loop {
+            let Some(mut message) = db.execute(&MessageQuery::Get { chat: chat.to_string(), id }).await?.pop() else {
+                return Err(MessageError::NotFound);
+            };
+
+            if message.deleted.is_some() {
+                return Err(MessageError::NotFound);
+            }
+
+            if message.author != editor {
+                return Err(MessageError::NotAuthor);
+            }
+
+            if window.is_some_and(|window| Utc::now() - message.sent > window) {
+                return Err(MessageError::EditWindowClosed);
+            }
+
+            let previous = message.edited.clone();
+
+            message.content.insert(0, message.sanitise(&content));
+            message.source.insert(0, content.clone());
+            message.edited.insert(0, Utc::now());
+
+            // Someone else may have edited it since it was read, in which case start again from their revision
+            if let Some(edited) = db.execute(&MessageQuery::Revise { chat: chat.to_string(), message, previous }).await?.pop() {
+                return Ok(edited);
+            }
+        }
+    }
+
+    /// Replace a message with a tombstone, if `remover` wrote it or is allowed to redact others' messages.
+    pub async fn delete<'a>(db: &KolloquyDB<'a>, chat: &str, id: u64, remover: &str, can_redact: bool) -> Result<Self, MessageError<'a>> {
+        let Some(message) = db.execute(&MessageQuery::Get { chat: chat.to_string(), id }).await?.pop() else {
+            return Err(MessageError::NotFound);
+        };
+
+        if message.author != remover && !can_redact {
+            return Err(MessageError::NotAuthor);
+        }
+
+        let tombstone = Tombstone { at: Utc::now(), by: remover.to_string() };
+
+        // Nothing is returned if it was already removed
+        let removed = db.execute(&MessageQuery::Remove { chat: chat.to_string(), id, tombstone }).await?
+            .pop()
+            .ok_or(MessageError::NotFound)?;
+
+        for attachment in db.execute(&AttachmentQuery::RemoveForMessage { chat: chat.to_string(), message: id }).await? {
+            // The message is already gone, so a file that can't be deleted is only left behind
+            attachment.delete_files(&KOLLOQUY_CHATS_BUCKET).await.ok();
+        }
+
+        Ok(removed)
+    }
+}
+
+impl FromRow for Message {
+    fn from_row(row: &Row) -> Result<Self, QueryError<'static>> {
+        fn json_column<T: DeserializeOwned>(row: &Row, name: &str) -> Result<T, QueryError<'static>> {
+            let json = column::<String>(row, name)?;
+
+            serde_json::from_str(&json).map_err(|e| QueryError::MalformedRow(format!("column '{name}': {e}")))
+        }
+
+        Ok(Self {
+            content: json_column(row, "content")?,
+            source: json_column(row, "source")?,
+            author: column(row, "author")?,
+            sent: datetime_column(row, "sent")?,
+            id: column(row, "id")?,
+            edited: json_column(row, "edited")?,
+            deleted: column::<Option<String>>(row, "deleted")?
+                .map(|json| serde_json::from_str(&json).map_err(|e| QueryError::MalformedRow(format!("column 'deleted': {e}"))))
+                .transpose()?,
+            attachments: Vec::new(),
+        })
+    }
+}
+
+/// Queries over the messages in a chat, which are kept in the database rather than in the chat object.
+///
+/// Messages are returned oldest first.
+#[derive(Debug, Clone)]
+pub enum MessageQuery {
+    /// Append a message, giving it the next id in the chat (its own `id` is ignored)
+    Append { chat: String, message: Message },
+
+    /// Insert a message with the id it already has, if that id is free
+    Import { chat: String, message: Message },
+
+    /// The `limit` most recent messages sent before the message with id `before`, or the most recent messages if `None`
+    Before { chat: String, before: Option<u64>, limit: u32 },
+
+    /// The `limit` oldest messages sent after the message with id `after`, or the oldest messages if `None`
+    After { chat: String, after: Option<u64>, limit: u32 },
+
+    /// A single message
+    Get { chat: String, id: u64 },
+
+    /// Replace a message's revisions, only if it hasn't been removed and when it was edited is still `previous`
+    Revise { chat: String, message: Message, previous: Vec<DateTime<Utc>> },
+
+    /// Empty a message and mark it as removed, unless it already is
+    Remove { chat: String, id: u64, tombstone: Tombstone },
+
+    /// Delete every message in a chat, along with their tombstones
+    Clear { chat: String },
+}
+
+impl Query for MessageQuery {
+    fn has_result(&self) -> bool {
+        matches!(self, Self::Append { .. })
+    }
+}
+
+impl DBQuery for MessageQuery {
+    type Output = Vec<Message>;
+
+    fn to_sql_query_string(&self) -> (String, Vec<String>) {
+        match self {
+            // Picking the id inside the insert keeps it atomic, so concurrent messages can't take the same id
+            Self::Append { chat, message } => (
+                "INSERT INTO messages (chat_id, id, author, sent, content, source)\nSELECT ?, COALESCE(MAX(id) + 1, 0), ?, ?, ?, ? FROM messages WHERE chat_id = ?\nRETURNING *;".to_string(),
+                vec![
+                    chat.clone(),
+                    message.author.clone(),
+                    message.sent.to_rfc3339(),
+                    serde_json::to_string(&message.content).unwrap(),
+                    serde_json::to_string(&message.source).unwrap(),
+                    chat.clone(),
+                ]
+            ),
+
+            Self::Import { chat, message } => (
+                "INSERT OR IGNORE INTO messages (chat_id, id, author, sent, content, source, edited) VALUES (?, ?, ?, ?, ?, ?, ?);".to_string(),
+                vec![
+                    chat.clone(),
+                    message.id.to_string(),
+                    message.author.clone(),
+                    message.sent.to_rfc3339(),
+                    serde_json::to_string(&message.content).unwrap(),
+                    serde_json::to_string(&message.source).unwrap(),
+                    serde_json::to_string(&message.edited).unwrap(),
+                ]
+            ),
+
+            Self::Before { chat, before, limit } => (
+                "SELECT * FROM (SELECT * FROM messages WHERE chat_id = ? AND id < ? ORDER BY id DESC LIMIT ?)\nORDER BY id ASC;".to_string(),
+                vec![chat.clone(), before.unwrap_or(i64::MAX as u64).to_string(), limit.to_string()]
+            ),
+
+            Self::After { chat, after, limit } => (
+                "SELECT * FROM messages WHERE chat_id = ? AND id > ? ORDER BY id ASC LIMIT ?;".to_string(),
+                vec![chat.clone(), after.map_or(-1, |id| id as i64).to_string(), limit.to_string()]
+            ),
+
+            Self::Get { chat, id } => (
+                "SELECT * FROM messages WHERE chat_id = ? AND id = ?;".to_string(),
+                vec![chat.clone(), id.to_string()]
+            ),
+
+            Self::Revise { chat, message, previous } => (
+                "UPDATE messages SET content = ?, source = ?, edited = ? WHERE chat_id = ? AND id = ? AND edited = ? AND deleted IS NULL\nRETURNING *;".to_string(),
+                vec![
+                    serde_json::to_string(&message.content).unwrap(),
+                    serde_json::to_string(&message.source).unwrap(),
+                    serde_json::to_string(&message.edited).unwrap(),
+                    chat.clone(),
+                    message.id.to_string(),
+                    serde_json::to_string(previous).unwrap(),
+                ]
+            ),
+
+            Self::Remove { chat, id, tombstone } => (
+                "UPDATE messages SET content = '[]', source = '[]', edited = '[]', deleted = ? WHERE chat_id = ? AND id = ? AND deleted IS NULL\nRETURNING *;".to_string(),
+                vec![serde_json::to_string(tombstone).unwrap(), chat.clone(), id.to_string()]
+            ),
+
+            Self::Clear { chat } => (
+                "DELETE FROM messages WHERE chat_id = ?;".to_string(),
+                vec![chat.clone()]
+            ),
+        }
+    }
+}
+
+/// Someone's place in a chat.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatMember {
+    pub chat_id: String,
+    pub user_id: String,
+    pub role: Role,
+}
+
+impl FromRow for ChatMember {
+    fn from_row(row: &Row) -> Result<Self, QueryError<'static>> {
+        Ok(Self {
+            chat_id: column(row, "chat_id")?,
+            user_id: column(row, "user_id")?,
+            role: column(row, "role")?,
+        })
+    }
+}
+
+/// Queries over who is a part of each chat.
+///
+/// Each changes a single row, or all of a chat's rows in a single statement, so concurrent changes can't undo each other.
+#[derive(Debug, Clone)]
+pub enum ChatMemberQuery {
+    /// Add someone to a chat, returning nothing if they're already a part of it
+    Add { chat: String, user: String, role: Role },
+
+    /// Take someone out of a chat, returning nothing if they weren't a part of it
+    Remove { chat: String, user: String },
+
+    /// Change someone's role, returning nothing if they aren't a part of the chat or are its owner
+    SetRole { chat: String, user: String, role: Role },
+
+    /// Make someone the owner of a chat, and its old owner an admin
+    TransferOwnership { chat: String, user: String },
+
+    /// Everyone who is a part of a chat
+    ForChat(String),
+
+    /// Every chat someone is a part of
+    ChatsOf(String),
+
+    /// Take everyone out of a chat
+    Clear(String),
+}
+
+impl Query for ChatMemberQuery {
+    fn has_result(&self) -> bool {
+        false
+    }
+}
+
+impl DBQuery for ChatMemberQuery {
+    type Output = Vec<ChatMember>;
+
+    fn to_sql_query_string(&self) -> (String, Vec<String>) {
+        match self {
+            Self::Add { chat, user, role } => (
+                "INSERT INTO chat_members (chat_id, user_id, role) VALUES (?, ?, ?)\nON CONFLICT DO NOTHING\nRETURNING *;".to_string(),
+                vec![chat.clone(), user.clone(), role_column(*role)]
+            ),
+
+            Self::Remove { chat, user } => (
+                "DELETE FROM chat_members WHERE chat_id = ? AND user_id = ?\nRETURNING *;".to_string(),
+                vec![chat.clone(), user.clone()]
+            ),
+
+            // The owner only changes when the chat is given away
+            Self::SetRole { chat, user, role } => (
+                "UPDATE chat_members SET role = ? WHERE chat_id = ? AND user_id = ? AND role != 'owner'\nRETURNING *;".to_string(),
+                vec![role_column(*role), chat.clone(), user.clone()]
+            ),
+
+            // Both rows change in one statement, and only if the new owner is a part of the chat, so there's never a moment with two owners or none
+            Self::TransferOwnership { chat, user } => (
+                "UPDATE chat_members SET role = CASE WHEN user_id = ? THEN 'owner' ELSE 'admin' END\nWHERE chat_id = ? AND (user_id = ? OR role = 'owner')\nAND EXISTS (SELECT 1 FROM chat_members WHERE chat_id = ? AND user_id = ?)\nRETURNING *;".to_string(),
+                vec![user.clone(), chat.clone(), user.clone(), chat.clone(), user.clone()]
+            ),
+
+            Self::ForChat(chat) => (
+                "SELECT * FROM chat_members WHERE chat_id = ? ORDER BY user_id ASC;".to_string(),
+                vec![chat.clone()]
+            ),
+
+            Self::ChatsOf(user) => (
+                "SELECT * FROM chat_members WHERE user_id = ? ORDER BY chat_id ASC;".to_string(),
+                vec![user.clone()]
+            ),
+
+            Self::Clear(chat) => (
+                "DELETE FROM chat_members WHERE chat_id = ?\nRETURNING *;".to_string(),
+                vec![chat.clone()]
+            ),
+        }
+    }
+}
+
+/// How a role is stored in the `role` column
+fn role_column(role: Role) -> String {
+    serde_json::to_value(role).unwrap().as_str().unwrap().to_string()
+}
+
+/// The ids of every chat a user is a part of.
+pub async fn enrolled_chats(user_id: &str) -> Result<Vec<String>, QueryError<'static>> {
+    let members = KolloquyDB::new().execute(&ChatMemberQuery::ChatsOf(user_id.to_string())).await?;
+
+    Ok(members.into_iter().map(|member| member.chat_id).collect())
+}
+
+/// A user's role in a chat, if it allows `permission`, found without loading the chat from the bucket.
+///
+/// The chat is only looked for when the user isn't a part of it, to tell a chat that doesn't exist from one they're not in.
+pub async fn require_role_in(db: &KolloquyDB<'_>, bucket: &KolloquyR2, chat: &str, user_id: &str, permission: Permission) -> Result<Role, ApiError> {
+    let members = db.execute(&ChatMemberQuery::ForChat(chat.to_string())).await?;
+
+    let Some(member) = members.into_iter().find(|member| member.user_id == user_id) else {
+        return match bucket.execute(&ObjectQuery::Head(format!("/{chat}.json.br"))).await? {
+            ObjectData::Head(Some(_)) => Err(ApiError::NotParticipant),
+            _ => Err(ApiError::ChatNotFound),
+        };
+    };
+
+    member.role.require(permission)?;
+
+    Ok(member.role)
+}
+
+/// The chats each user was enrolled in, from the `enrolled_chats` column of the users table.
+///
+/// Membership used to be kept there and on the chat object, and is only read to move it into `chat_members`.
+#[derive(Debug, Clone)]
+struct LegacyEnrolmentQuery;
+
+/// Empty the `enrolled_chats` column once what was in it has been moved, returning how many users had any.
+#[derive(Debug, Clone)]
+struct ClearLegacyEnrolmentQuery;
+
+/// A user and the chats they were enrolled in.
+struct LegacyEnrolment {
+    user_id: String,
+    chats: Vec<String>,
+}
+
+impl FromRow for LegacyEnrolment {
+    fn from_row(row: &Row) -> Result<Self, QueryError<'static>> {
+        Ok(Self {
+            user_id: column(row, "userid")?,
+            chats: column::<String>(row, "enrolled_chats")?
+                .split(',')
+                .filter(|id| !id.is_empty())
+                .map(|id| id.to_string())
+                .collect(),
+        })
+    }
+}
+
+impl Query for LegacyEnrolmentQuery {
+    fn has_result(&self) -> bool {
+        false
+    }
+}
+
+impl DBQuery for LegacyEnrolmentQuery {
+    type Output = Vec<LegacyEnrolment>;
+
+    fn to_sql_query_string(&self) -> (String, Vec<String>) {
+        ("SELECT userid, enrolled_chats FROM users WHERE enrolled_chats != '';".to_string(), vec![])
+    }
+}
+
+impl Query for ClearLegacyEnrolmentQuery {
+    fn has_result(&self) -> bool {
+        false
+    }
+}
+
+impl DBQuery for ClearLegacyEnrolmentQuery {
+    type Output = RowsAffected;
+
+    fn to_sql_query_string(&self) -> (String, Vec<String>) {
+        ("UPDATE users SET enrolled_chats = '' WHERE enrolled_chats != '';".to_string(), vec![])
+    }
+}
+
+/// Move chats stored before their participants were kept in `chat_members` into it, along with any messages still kept on them.
+///
+/// This is run once by `server migrate`, and running it again does nothing. Returns how many chats were migrated,
+/// and how many users they were migrated for.
+pub async fn migrate_legacy_chats(db: &KolloquyDB<'_>, bucket: &KolloquyR2) -> Result<(usize, u64), ApiError> {
+    let mut enrolled: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for enrolment in db.execute(&LegacyEnrolmentQuery).await? {
+        for chat in enrolment.chats {
+            enrolled.entry(chat).or_default().push(enrolment.user_id.clone());
+        }
+    }
+
+    let mut migrated = 0;
+
+    for (id, users) in enrolled {
+        let compressed = match bucket.get_object(&format!("/{id}.json.br")).await {
+            Ok(compressed) => compressed,
+            // Chats that have been deleted are only left in their users' enrolled chats
+            Err(S3Error::HttpFailWithBody(404, _)) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        let json = decompress_string(&compressed)?;
+        let malformed = |e: serde_json::Error| ApiError::Storage(format!("Chat '{id}' is malformed: {e}"));
+
+        let chat: Chat = serde_json::from_str(&json).map_err(malformed)?;
+        let legacy: LegacyChat = serde_json::from_str(&json).map_err(malformed)?;
+
+        // Chats didn't record who made them, so whoever spoke first is taken to be the owner, or anyone if nobody has
+        let owner = legacy.messages.iter()
+            .map(|message| &message.author)
+            .find(|author| users.contains(author))
+            .or(users.first())
+            .cloned();
+
+        for mut message in legacy.messages {
+            message.sanitise_legacy();
+
+            db.execute(&MessageQuery::Import { chat: id.clone(), message }).await?;
+        }
+
+        for user in users {
+            let role = if owner.as_ref() == Some(&user) { Role::Owner } else { Role::Member };
+
+            db.execute(&ChatMemberQuery::Add { chat: id.clone(), user, role }).await?;
+        }
+
+        // Rewrite the chat without what's been moved out of it
+        chat.put_to(bucket).await?;
+
+        migrated += 1;
+    }
+
+    let RowsAffected(users) = db.execute(&ClearLegacyEnrolmentQuery).await?;
+
+    Ok((migrated, users))
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -115,8 +869,19 @@ pub struct Chat {
     pub name: String, 
     pub id: String,
     pub icon_url: String,
-    pub messages: Vec<Message>,
     remote_url: String,
+    /// Everyone who is a part of the chat and their role, by user id, as of when the chat was loaded.
+    ///
+    /// This is kept in `chat_members` rather than in the chats bucket.
+    #[serde(skip)]
+    pub members: BTreeMap<String, Role>,
+}
+
+/// What chats used to be stored with, which [`migrate_legacy_chats`] moves into the database.
+#[derive(Deserialize)]
+struct LegacyChat {
+    #[serde(default)]
+    messages: Vec<Message>,
 }
 
 impl<'a> Chat {
@@ -131,98 +896,741 @@ impl<'a> Chat {
             id,
             icon_url,
             remote_url,
-            messages: Vec::new(),
+            members: BTreeMap::new(),
         }, create_chat_icon())
     }
     
-    pub async fn execute(&mut self, query: &mut ChatQuery<'a>) {
+    /// Create a chat with its icon, making `creator` its owner and adding everyone to it
+    pub async fn create(name: String, creator: &User, participants: Vec<User>) -> Result<(Self, Document), ApiError> {
+        let (mut chat, icon) = Self::new(name).await;
+
+        chat.execute(&mut ChatQuery::PutIcon(icon.clone())).await?;
+        chat.execute(&mut ChatQuery::PutChat).await?;
+        chat.join(&creator.user_id, Role::Owner).await?;
+
+        // Anyone listed twice, or the creator listing themselves, is only added once
+        for user in &participants {
+            if !chat.is_participant(&user.user_id) {
+                chat.execute(&mut ChatQuery::AddParticipant(user)).await?;
+            }
+        }
+
+        Ok((chat, icon))
+    }
+
+    /// Check that `actor` is a part of this chat, and that their role lets them run `query`.
+    pub fn authorize(&self, actor: &str, query: &ChatQuery) -> Result<(), ApiError> {
+        let role = self.require(actor, Permission::Read)?;
+
         match query {
-            ChatQuery::PutChat => {
-                let mut serialised = Cursor::new(serde_json::to_string(self).unwrap());
-                let mut compressed = Vec::new();
-                
-                BrotliCompress(&mut serialised, &mut compressed, &Default::default()).unwrap();
-                
-                KOLLOQUY_CHATS_BUCKET.deref()
-                    .put_object(self.remote_url.as_str(), &*compressed).await.unwrap();
+            ChatQuery::PutChat | ChatQuery::PutIcon(_) | ChatQuery::Rename(_) => role.require(Permission::Edit),
+
+            ChatQuery::AddParticipant(user) => {
+                role.require(Permission::Invite)?;
+
+                if self.is_participant(&user.user_id) {
+                    return Err(ApiError::AlreadyParticipant);
+                }
+
+                Ok(())
             }
-            
-            ChatQuery::AddMessage(message) => {
-                self.messages.push(message.clone());
+
+            // Anyone can leave, except the owner, who has to give the chat to someone else first
+            ChatQuery::RemoveParticipant(user) if user.user_id == actor => match role {
+                Role::Owner => Err(ApiError::OwnerCannotLeave),
+                _ => Ok(()),
+            },
+
+            ChatQuery::RemoveParticipant(user) => {
+                role.require(Permission::Manage)?;
+
+                let target = self.role(&user.user_id).ok_or(ApiError::ParticipantNotFound)?;
+
+                if target >= role {
+                    return Err(ApiError::NotAllowed(Permission::Manage));
+                }
+
+                Ok(())
             }
-            
-            ChatQuery::AddParticipant(user) => {
-                user.enrolled_chats.push(self.id.clone());
-                
-                let db = KolloquyDB::new();
-                let query = UserQuery::UpdateRemote(user.clone().clone());
-                
-                db.execute(&query).await.unwrap();
+
+            ChatQuery::SetRole { user, role: new } => {
+                let target = self.role(user).ok_or(ApiError::ParticipantNotFound)?;
+
+                if *new == Role::Owner {
+                    return role.require(Permission::Delete);
+                }
+
+                role.require(Permission::Manage)?;
+
+                // Only roles below the actor's can be changed, and only to roles below it
+                if user == actor || target >= role || *new >= role {
+                    return Err(ApiError::NotAllowed(Permission::Manage));
+                }
+
+                Ok(())
             }
+
+            ChatQuery::Delete => role.require(Permission::Delete),
+        }
+    }
+
+    /// Run a query on behalf of `actor`, if they're allowed to (see [`Chat::authorize`])
+    pub async fn execute_as(&mut self, actor: &str, query: &mut ChatQuery<'a>) -> Result<(), ApiError> {
+        self.authorize(actor, query)?;
+        self.execute(query).await
+    }
+
+    /// Run a query without checking who asked for it
+    pub async fn execute(&mut self, query: &mut ChatQuery<'a>) -> Result<(), ApiError> {
+        match query {
+            ChatQuery::PutChat => self.put().await?,
+            
+            ChatQuery::AddParticipant(user) => self.join(&user.user_id, Role::Member).await?,
             
             ChatQuery::RemoveParticipant(user) => {
-                user.enrolled_chats.retain(|chat| chat != &self.id);
+                let query = ChatMemberQuery::Remove { chat: self.id.clone(), user: user.user_id.clone() };
 
-                let db = KolloquyDB::new();
-                let query = UserQuery::UpdateRemote(user.clone().clone());
+                if KolloquyDB::new().execute(&query).await?.is_empty() {
+                    return Err(ApiError::ParticipantNotFound);
+                }
 
-                db.execute(&query).await.unwrap();
+                self.members.remove(&user.user_id);
             }
 
             ChatQuery::PutIcon(icon) => {
                 let mut serialised = Cursor::new(icon.to_string());
                 let mut compressed = Vec::new();
 
-                BrotliCompress(&mut serialised, &mut compressed, &Default::default()).unwrap();
+                BrotliCompress(&mut serialised, &mut compressed, &Default::default())?;
 
                 USER_AVATAR_BUCKET.deref()
-                    .put_object(self.icon_url.as_str(), &*compressed).await.unwrap();
+                    .put_object(self.icon_url.as_str(), &compressed).await?;
+            }
+            
+            ChatQuery::Rename(name) => {
+                self.name = name.clone();
+
+                self.put().await?;
+            }
+
+            ChatQuery::SetRole { user, role } => {
+                // There's only ever one owner
+                let query = match role {
+                    Role::Owner => ChatMemberQuery::TransferOwnership { chat: self.id.clone(), user: user.clone() },
+                    _ => ChatMemberQuery::SetRole { chat: self.id.clone(), user: user.clone(), role: *role },
+                };
+
+                let changed = KolloquyDB::new().execute(&query).await?;
+
+                if !changed.iter().any(|member| &member.user_id == user) {
+                    return Err(ApiError::ParticipantNotFound);
+                }
+
+                for member in changed {
+                    self.members.insert(member.user_id, member.role);
+                }
             }
             
             ChatQuery::Delete => {
+                let db = KolloquyDB::new();
+
+                db.execute(&ChatMemberQuery::Clear(self.id.clone())).await?;
+                db.execute(&MessageQuery::Clear { chat: self.id.clone() }).await?;
+
+                db.execute(&AttachmentQuery::Clear { chat: self.id.clone() }).await?;
+
+                // Every file under the chat goes, including any left behind by uploads that didn't finish
+                let files = KOLLOQUY_CHATS_BUCKET.execute(&ObjectQuery::List(format!("/attachments/{}/", self.id))).await?;
+
+                for path in files.into_paths() {
+                    KOLLOQUY_CHATS_BUCKET.execute(&ObjectQuery::Delete(path)).await?;
+                }
+
                 KOLLOQUY_CHATS_BUCKET.deref()
-                    .delete_object(self.remote_url.as_str()).await.unwrap();
+                    .delete_object(self.remote_url.as_str()).await?;
+
+                USER_AVATAR_BUCKET.deref()
+                    .delete_object(self.icon_url.as_str()).await?;
             }
         }
+
+        Ok(())
     }
     
-    pub async fn from_remote(id: String) -> Option<Self> {
-        eprintln!("REM URL /{id}.json.br");
+    /// Add someone to the chat with `role`, unless they're already a part of it
+    async fn join(&mut self, user_id: &str, role: Role) -> Result<(), ApiError> {
+        let query = ChatMemberQuery::Add { chat: self.id.clone(), user: user_id.to_string(), role };
+
+        if KolloquyDB::new().execute(&query).await?.is_empty() {
+            return Err(ApiError::AlreadyParticipant);
+        }
+
+        self.members.insert(user_id.to_string(), role);
+
+        Ok(())
+    }
+
+    /// Upload the chat to the R2 bucket, replacing what's there
+    async fn put(&self) -> Result<(), ApiError> {
+        self.put_to(&KOLLOQUY_CHATS_BUCKET).await
+    }
+
+    pub(crate) async fn put_to(&self, bucket: &KolloquyR2) -> Result<(), ApiError> {
+        let mut serialised = Cursor::new(serde_json::to_string(self).unwrap());
+        let mut compressed = Vec::new();
+
+        BrotliCompress(&mut serialised, &mut compressed, &Default::default())?;
+
+        bucket.put_object(self.remote_url.as_str(), &compressed).await?;
+
+        Ok(())
+    }
+
+    /// Load a chat from the object store and its members from the database, failing with [`ApiError::ChatNotFound`] if it doesn't exist
+    pub async fn from_remote(id: String) -> Result<Self, ApiError> {
+        Self::from_remote_in(&KolloquyDB::new(), &KOLLOQUY_CHATS_BUCKET, id).await
+    }
 
+    pub(crate) async fn from_remote_in(db: &KolloquyDB<'_>, bucket: &KolloquyR2, id: String) -> Result<Self, ApiError> {
         let remote_url = format!("/{id}.json.br");
 
-        let mut compressed = Cursor::new(KOLLOQUY_CHATS_BUCKET.deref()
-            .get_object(remote_url.as_str()).await.ok()?
-            .to_vec());
+        let compressed = match bucket.get_object(remote_url.as_str()).await {
+            Ok(compressed) => compressed,
+            Err(S3Error::HttpFailWithBody(404, _)) => return Err(ApiError::ChatNotFound),
+            Err(e) => return Err(e.into()),
+        };
+
+        let json = decompress_string(&compressed)?;
+
+        let mut chat: Self = serde_json::from_str(&json)
+            .map_err(|e| ApiError::Storage(format!("Chat '{id}' is malformed: {e}")))?;
+
+        chat.members = db.execute(&ChatMemberQuery::ForChat(id)).await?
+            .into_iter()
+            .map(|member| (member.user_id, member.role))
+            .collect();
+
+        Ok(chat)
+    }
+
+    /// Whether a user is a part of this chat
+    pub fn is_participant(&self, user_id: &str) -> bool {
+        self.members.contains_key(user_id)
+    }
+
+    /// A user's role in this chat, if they're a part of it
+    pub fn role(&self, user_id: &str) -> Option<Role> {
+        self.members.get(user_id).copied()
+    }
+
+    /// A user's role in this chat, if they're a part of it and it allows `permission`
+    pub fn require(&self, user_id: &str, permission: Permission) -> Result<Role, ApiError> {
+        let role = self.role(user_id).ok_or(ApiError::NotParticipant)?;
+
+        role.require(permission)?;
 
-        let mut serialised = Cursor::new(Vec::new());
+        Ok(role)
+    }
+
+    /// Everyone who is a part of this chat, by handle
+    pub async fn participants(&self) -> Result<Vec<User>, QueryError<'static>> {
+        let db = KolloquyDB::new();
+        let mut users = Vec::new();
 
-        BrotliDecompress(&mut compressed, &mut serialised).unwrap();
+        for id in self.members.keys() {
+            match db.execute(&UserQuery::GetByID(id.clone())).await {
+                Ok(Some(user)) => users.push(user),
+                // Skip users whose accounts are gone
+                Ok(None) | Err(QueryError::NotFound) => (),
+                Err(e) => return Err(e),
+            }
+        }
+
+        users.sort_by(|a, b| a.handle.cmp(&b.handle));
+
+        Ok(users)
+    }
+
+    /// Post a system message to this chat, like who joined or left it.
+    pub async fn announce(&self, content: String) -> Result<Message, ApiError> {
+        let query = MessageQuery::Append { chat: self.id.clone(), message: Message::system(content) };
+
+        KolloquyDB::new().execute(&query).await?
+            .pop()
+            .ok_or(ApiError::from(QueryError::NotFound))
+    }
+
+    /// Fetch a page of this chat's messages, oldest first.
+    pub async fn messages(&self, before: Option<u64>, limit: u32) -> Result<Vec<Message>, QueryError<'static>> {
+        KolloquyDB::new().execute(&MessageQuery::Before { chat: self.id.clone(), before, limit }).await
+    }
+
+    /// Fetch the page of messages described by `params`, oldest first,
+    /// and whether there are more messages beyond it in the same direction.
+    pub async fn message_page(&self, params: &MessagePageParams) -> Result<(Vec<Message>, bool), QueryError<'static>> {
+        let limit = params.limit.unwrap_or(MESSAGE_PAGE_SIZE).clamp(1, MAX_MESSAGE_PAGE_SIZE);
+        let db = KolloquyDB::new();
+
+        // Ask for one extra message to find out if there are more
+        let (mut messages, has_more) = if let Some(after) = params.after {
+            let mut messages = db.execute(&MessageQuery::After { chat: self.id.clone(), after: Some(after), limit: limit + 1 }).await?;
+            let has_more = messages.len() > limit as usize;
+
+            messages.truncate(limit as usize);
+
+            (messages, has_more)
+        } else {
+            let mut messages = db.execute(&MessageQuery::Before { chat: self.id.clone(), before: params.before, limit: limit + 1 }).await?;
+            let has_more = messages.len() > limit as usize;
+
+            if has_more {
+                messages.remove(0);
+            }
 
-        println!("{}", String::from_utf8_lossy(&serialised.clone().into_inner()));
+            (messages, has_more)
+        };
 
-        let chat = serde_json::from_str(&String::from_utf8_lossy(&serialised.clone().into_inner())).unwrap();
+        Message::load_attachments(&db, &self.id, &mut messages).await?;
 
-        chat
+        Ok((messages, has_more))
     }
 }
 
+#[cfg(test)]
 mod tests {
     use super::*;
-    use dotenv::dotenv;
+    use crate::data::{DBBackend, MemoryStore};
+    use crate::test_support::{memory_backend, memory_db};
+    use futures::future::join_all;
+
+    fn message(author: &str, content: &str) -> Message {
+        Message::new(author, content.to_string())
+    }
 
     #[tokio::test]
     async fn test_upload_retrieve() {
-        dotenv().ok();
+        let db = memory_db();
+        let bucket = KolloquyR2::new(MemoryStore::default());
+
+        let (chat, _) = Chat::new("Test Chat".to_string()).await;
+
+        chat.put_to(&bucket).await.unwrap();
+        db.execute(&ChatMemberQuery::Add { chat: chat.id.clone(), user: "xy12abc".to_string(), role: Role::Owner }).await.unwrap();
+
+        let loaded = Chat::from_remote_in(&db, &bucket, chat.id.clone()).await.unwrap();
+
+        assert_eq!((loaded.name.as_str(), loaded.id.as_str(), loaded.icon_url.as_str()), ("Test Chat", chat.id.as_str(), chat.icon_url.as_str()));
+        assert_eq!(loaded.role("xy12abc"), Some(Role::Owner));
+        assert!(matches!(Chat::from_remote_in(&db, &bucket, "zz99zzz".to_string()).await, Err(ApiError::ChatNotFound)));
+    }
+
+    #[tokio::test]
+    async fn messages_are_appended_and_paged() {
+        let db = memory_db();
+
+        let queries = (0..10).map(|i| MessageQuery::Append { chat: "ab12cde".to_string(), message: message("xy12abc", &i.to_string()) }).collect::<Vec<_>>();
+        let appended = join_all(queries.iter().map(|query| db.execute(query))).await;
+
+        let mut ids = appended.into_iter().map(|m| m.unwrap()[0].id).collect::<Vec<_>>();
+
+        ids.sort();
+
+        assert_eq!(ids, (0..10).collect::<Vec<_>>());
+
+        // Other chats have their own ids
+        let other = db.execute(&MessageQuery::Append { chat: "zz99zzz".to_string(), message: message("xy12abc", "hi") }).await.unwrap();
+
+        assert_eq!(other[0].id, 0);
 
-        let (mut chat, _) = Chat::new("Test Chat".to_string()).await;
+        let latest = db.execute(&MessageQuery::Before { chat: "ab12cde".to_string(), before: None, limit: 3 }).await.unwrap();
 
-        chat.execute(&mut ChatQuery::PutChat).await;
+        assert_eq!(latest.iter().map(|m| m.id).collect::<Vec<_>>(), vec![7, 8, 9]);
 
-        println!("{chat:?}");
+        let older = db.execute(&MessageQuery::Before { chat: "ab12cde".to_string(), before: Some(7), limit: 3 }).await.unwrap();
 
-        let chat = Chat::from_remote(chat.id.clone()).await;
+        assert_eq!(older.iter().map(|m| m.id).collect::<Vec<_>>(), vec![4, 5, 6]);
 
-        println!("{chat:?}");
+        let oldest = db.execute(&MessageQuery::After { chat: "ab12cde".to_string(), after: None, limit: 2 }).await.unwrap();
+
+        assert_eq!(oldest.iter().map(|m| m.id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(oldest[0].author, "xy12abc");
+
+        let newer = db.execute(&MessageQuery::After { chat: "ab12cde".to_string(), after: Some(8), limit: 5 }).await.unwrap();
+
+        assert_eq!(newer.iter().map(|m| m.id).collect::<Vec<_>>(), vec![9]);
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn imported_messages_keep_their_ids() {
+        let db = memory_db();
+        let mut legacy = message("xy12abc", "from the old blob");
+
+        legacy.id = 4;
+
+        db.execute(&MessageQuery::Import { chat: "ab12cde".to_string(), message: legacy.clone() }).await.unwrap();
+        db.execute(&MessageQuery::Import { chat: "ab12cde".to_string(), message: legacy }).await.unwrap();
+
+        let appended = db.execute(&MessageQuery::Append { chat: "ab12cde".to_string(), message: message("xy12abc", "new") }).await.unwrap();
+
+        assert_eq!(appended[0].id, 5);
+
+        let all = db.execute(&MessageQuery::After { chat: "ab12cde".to_string(), after: None, limit: u32::MAX }).await.unwrap();
+
+        assert_eq!(all.len(), 2);
+        assert_eq!(all[0].content, vec!["from the old blob".to_string()]);
+    }
+
+    #[test]
+    fn content_is_cleaned() {
+        assert_eq!(clean_content("  hi\r\nthere\r\u{0}\u{7}\u{202E}txt.exe\t ").unwrap(), "hi\nthere\ntxt.exe");
+        assert_eq!(clean_content("<script>*kept*</script>").unwrap(), "<script>*kept*</script>");
+        assert!(matches!(clean_content(" \u{0}\n\u{200F} "), Err(MessageError::Empty)));
+        assert!(matches!(clean_content(&"a".repeat(*MAX_MESSAGE_LENGTH + 1)), Err(MessageError::TooLong)));
+        assert!(clean_content(&"é".repeat(*MAX_MESSAGE_LENGTH)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn sent_messages_are_cleaned() {
+        let db = memory_db();
+
+        let sent = Message::send(&db, "ab12cde", "xy12abc", "hello\u{0}\r\n", &[]).await.unwrap();
+        assert_eq!(sent.current(), "hello");
+
+        assert!(matches!(Message::send(&db, "ab12cde", "xy12abc", "\u{1b}", &[]).await, Err(MessageError::Empty)));
+        assert!(matches!(Message::edit(&db, "ab12cde", 0, "xy12abc", "\t".to_string(), None).await, Err(MessageError::Empty)));
+
+        let stored = db.execute(&MessageQuery::Get { chat: "ab12cde".to_string(), id: 0 }).await.unwrap();
+        assert_eq!(stored[0].content, vec!["hello".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn messages_are_sanitised_before_they_are_stored() {
+        let db = memory_db();
+        let hostile = "%MD\n<script>alert(1)</script><img src=x onerror=alert(1)>\n\n*hi*";
+
+        let sent = Message::send(&db, "ab12cde", "xy12abc", hostile, &[]).await.unwrap();
+        let edited = Message::edit(&db, "ab12cde", 0, "xy12abc", format!("{hostile}!"), None).await.unwrap();
+
+        for message in [&sent, &edited] {
+            assert!(!message.current().contains("<script") && !message.current().contains("<img"), "{}", message.current());
+            assert!(message.current().contains("<em>hi</em>"));
+        }
+
+        // Only the source is kept as it was written, for the author to edit
+        let stored = db.execute(&MessageQuery::Get { chat: "ab12cde".to_string(), id: 0 }).await.unwrap();
+
+        assert_eq!(stored[0].content, edited.content);
+        assert_eq!(stored[0].source, vec![format!("{hostile}!"), hostile.to_string()]);
+    }
+
+    #[tokio::test]
+    async fn messages_are_sent_with_attachments() {
+        let db = memory_db();
+
+        let attachment = Attachment {
+            id: "f1le".to_string(),
+            chat_id: "ab12cde".to_string(),
+            uploader: "xy12abc".to_string(),
+            name: "notes.txt".to_string(),
+            mime: "text/plain".to_string(),
+            size: 5,
+            thumbnail: false,
+            uploaded: Utc::now(),
+            message: None,
+        };
+
+        db.execute(&AttachmentQuery::Create(attachment)).await.unwrap();
+
+        let ids = ["f1le".to_string()];
+
+        // Only the uploader can send it, and only once
+        assert!(matches!(Message::send(&db, "ab12cde", "zz99zzz", "mine now", &ids).await, Err(MessageError::AttachmentNotFound)));
+        assert!(matches!(Message::send(&db, "zz99zzz", "xy12abc", "wrong chat", &ids).await, Err(MessageError::AttachmentNotFound)));
+
+        let sent = Message::send(&db, "ab12cde", "xy12abc", " ", &ids).await.unwrap();
+
+        assert_eq!(sent.current(), "");
+        assert_eq!(sent.attachments[0].message, Some(sent.id));
+        assert!(matches!(Message::send(&db, "ab12cde", "xy12abc", "again", &ids).await, Err(MessageError::AttachmentNotFound)));
+
+        let mut page = db.execute(&MessageQuery::After { chat: "ab12cde".to_string(), after: None, limit: 10 }).await.unwrap();
+
+        Message::load_attachments(&db, "ab12cde", &mut page).await.unwrap();
+
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].attachments, sent.attachments);
+
+        let too_many = (0..=MAX_MESSAGE_ATTACHMENTS).map(|i| i.to_string()).collect::<Vec<_>>();
+
+        assert!(matches!(Message::send(&db, "ab12cde", "xy12abc", "hi", &too_many).await, Err(MessageError::TooManyAttachments)));
+    }
+
+    #[tokio::test]
+    async fn edits_push_revisions() {
+        let db = memory_db();
+
+        db.execute(&MessageQuery::Append { chat: "ab12cde".to_string(), message: message("xy12abc", "helo") }).await.unwrap();
+
+        let edited = Message::edit(&db, "ab12cde", 0, "xy12abc", "hello".to_string(), None).await.unwrap();
+
+        assert_eq!(edited.current(), "hello");
+        assert_eq!(edited.content, vec!["hello".to_string(), "helo".to_string()]);
+
+        let stored = db.execute(&MessageQuery::Get { chat: "ab12cde".to_string(), id: 0 }).await.unwrap();
+        let revisions = stored[0].revisions();
+
+        assert_eq!(revisions.iter().map(|(content, _)| *content).collect::<Vec<_>>(), vec!["helo", "hello"]);
+        assert_eq!(revisions[0].1, stored[0].sent);
+        assert_eq!(revisions[1].1, stored[0].edited[0]);
+    }
+
+    #[tokio::test]
+    async fn only_authors_can_edit_in_time() {
+        let db = memory_db();
+        let mut old = message("xy12abc", "old news");
+
+        old.sent -= TimeDelta::hours(1);
+
+        db.execute(&MessageQuery::Append { chat: "ab12cde".to_string(), message: old }).await.unwrap();
+
+        let result = Message::edit(&db, "ab12cde", 0, "zz99zzz", "mine now".to_string(), None).await;
+        assert!(matches!(result, Err(MessageError::NotAuthor)));
+
+        let result = Message::edit(&db, "ab12cde", 0, "xy12abc", "new news".to_string(), Some(TimeDelta::minutes(15))).await;
+        assert!(matches!(result, Err(MessageError::EditWindowClosed)));
+
+        let result = Message::edit(&db, "ab12cde", 1, "xy12abc", "nothing".to_string(), None).await;
+        assert!(matches!(result, Err(MessageError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn removed_messages_leave_tombstones() {
+        let db = memory_db();
+
+        for content in ["first", "second"] {
+            db.execute(&MessageQuery::Append { chat: "ab12cde".to_string(), message: message("xy12abc", content) }).await.unwrap();
+        }
+
+        let result = Message::delete(&db, "ab12cde", 0, "zz99zzz", false).await;
+        assert!(matches!(result, Err(MessageError::NotAuthor)));
+
+        let deleted = Message::delete(&db, "ab12cde", 0, "xy12abc", false).await.unwrap();
+
+        assert_eq!(deleted.author, "xy12abc");
+        assert_eq!(deleted.current(), "");
+        assert!(deleted.revisions().is_empty());
+        assert!(!deleted.is_redacted());
+
+        let redacted = Message::delete(&db, "ab12cde", 1, "zz99zzz", true).await.unwrap();
+
+        assert!(redacted.is_redacted());
+
+        // Tombstones keep their place in the chat, but can't be edited or removed again
+        let all = db.execute(&MessageQuery::After { chat: "ab12cde".to_string(), after: None, limit: 10 }).await.unwrap();
+
+        assert_eq!(all.iter().map(|m| m.id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(all[1].deleted.as_ref().map(|t| t.by.as_str()), Some("zz99zzz"));
+
+        let result = Message::edit(&db, "ab12cde", 0, "xy12abc", "back again".to_string(), None).await;
+        assert!(matches!(result, Err(MessageError::NotFound)));
+
+        let result = Message::delete(&db, "ab12cde", 0, "xy12abc", false).await;
+        assert!(matches!(result, Err(MessageError::NotFound)));
+    }
+
+    #[tokio::test]
+    async fn read_only_authors_remove_their_own_messages() {
+        let db = memory_db();
+        let bucket = KolloquyR2::new(MemoryStore::default());
+        let (chat, _) = Chat::new("Test Chat".to_string()).await;
+
+        chat.put_to(&bucket).await.unwrap();
+        db.execute(&ChatMemberQuery::Add { chat: chat.id.clone(), user: "re12ader".to_string(), role: Role::ReadOnly }).await.unwrap();
+
+        // Sent before they were made read only
+        for author in ["re12ader", "xy12abc"] {
+            db.execute(&MessageQuery::Append { chat: chat.id.clone(), message: message(author, "hello") }).await.unwrap();
+        }
+
+        let role = require_role_in(&db, &bucket, &chat.id, "re12ader", Permission::Read).await.unwrap();
+
+        let result = Message::delete(&db, &chat.id, 1, "re12ader", role.allows(Permission::Redact)).await;
+        assert!(matches!(result, Err(MessageError::NotAuthor)));
+
+        let deleted = Message::delete(&db, &chat.id, 0, "re12ader", role.allows(Permission::Redact)).await.unwrap();
+
+        assert!(deleted.deleted.is_some());
+        assert!(!deleted.is_redacted());
+    }
+
+    #[test]
+    fn roles_limit_chat_changes() {
+        let mut chat: Chat = serde_json::from_value(serde_json::json!({
+            "name": "Test Chat",
+            "id": "ab12cde",
+            "icon_url": "",
+            "remote_url": "",
+        })).unwrap();
+
+        chat.members = BTreeMap::from([
+            ("ow12ner".to_string(), Role::Owner),
+            ("ad12min".to_string(), Role::Admin),
+            ("me12mber".to_string(), Role::Member),
+            ("re12ader".to_string(), Role::ReadOnly),
+        ]);
+
+        let set_role = |user: &str, role: Role| ChatQuery::SetRole { user: user.to_string(), role };
+
+        assert_eq!(chat.role("me12mber"), Some(Role::Member));
+        assert_eq!(chat.role("ou12tsider"), None);
+        assert!(matches!(chat.authorize("ou12tsider", &ChatQuery::PutChat), Err(ApiError::NotParticipant)));
+
+        assert!(chat.authorize("ad12min", &ChatQuery::Rename("New Name".to_string())).is_ok());
+        assert!(matches!(chat.authorize("me12mber", &ChatQuery::Rename("New Name".to_string())), Err(ApiError::NotAllowed(Permission::Edit))));
+        assert!(matches!(chat.authorize("ad12min", &ChatQuery::Delete), Err(ApiError::NotAllowed(Permission::Delete))));
+        assert!(chat.authorize("ow12ner", &ChatQuery::Delete).is_ok());
+
+        // Roles can only be changed below the actor's own
+        assert!(chat.authorize("ad12min", &set_role("re12ader", Role::Member)).is_ok());
+        assert!(chat.authorize("ad12min", &set_role("me12mber", Role::Admin)).is_err());
+        assert!(chat.authorize("ad12min", &set_role("ow12ner", Role::Member)).is_err());
+        assert!(chat.authorize("ow12ner", &set_role("me12mber", Role::Admin)).is_ok());
+
+        // Only the owner can give the chat away
+        assert!(chat.authorize("ad12min", &set_role("ad12min", Role::Owner)).is_err());
+        assert!(chat.authorize("ow12ner", &set_role("ad12min", Role::Owner)).is_ok());
+        assert!(matches!(chat.authorize("ow12ner", &set_role("ou12tsider", Role::Admin)), Err(ApiError::ParticipantNotFound)));
+    }
+
+    #[tokio::test]
+    async fn roles_are_checked_without_loading_the_chat() {
+        let db = memory_db();
+        let bucket = KolloquyR2::new(MemoryStore::default());
+        let (chat, _) = Chat::new("Test Chat".to_string()).await;
+
+        chat.put_to(&bucket).await.unwrap();
+        db.execute(&ChatMemberQuery::Add { chat: chat.id.clone(), user: "re12ader".to_string(), role: Role::ReadOnly }).await.unwrap();
+
+        let require = async |chat: &str, user: &str, permission| require_role_in(&db, &bucket, chat, user, permission).await;
+
+        assert_eq!(require(&chat.id, "re12ader", Permission::Read).await.unwrap(), Role::ReadOnly);
+        assert!(matches!(require(&chat.id, "re12ader", Permission::Send).await, Err(ApiError::NotAllowed(Permission::Send))));
+        assert!(matches!(require(&chat.id, "zz99zzz", Permission::Read).await, Err(ApiError::NotParticipant)));
+        assert!(matches!(require("zz99zzz", "re12ader", Permission::Read).await, Err(ApiError::ChatNotFound)));
+    }
+
+    #[tokio::test]
+    async fn members_change_one_row_at_a_time() {
+        let db = memory_db();
+
+        let add = |user: &str, role: Role| ChatMemberQuery::Add { chat: "ab12cde".to_string(), user: user.to_string(), role };
+        let roles = async || db.execute(&ChatMemberQuery::ForChat("ab12cde".to_string())).await.unwrap()
+            .into_iter()
+            .map(|member| (member.user_id, member.role))
+            .collect::<Vec<_>>();
+
+        // Adding the same people at once only adds each of them once
+        let added = join_all([add("ow12ner", Role::Owner), add("me12mber", Role::Member), add("me12mber", Role::Member)].iter().map(|query| db.execute(query))).await;
+
+        assert_eq!(added.into_iter().map(|rows| rows.unwrap().len()).sum::<usize>(), 2);
+        assert_eq!(db.execute(&add("ot12her", Role::Member)).await.unwrap().len(), 1);
+
+        // The owner can't be demoted, only replaced
+        let demote = ChatMemberQuery::SetRole { chat: "ab12cde".to_string(), user: "ow12ner".to_string(), role: Role::Member };
+
+        assert!(db.execute(&demote).await.unwrap().is_empty());
+
+        let transfer = ChatMemberQuery::TransferOwnership { chat: "ab12cde".to_string(), user: "me12mber".to_string() };
+
+        assert_eq!(db.execute(&transfer).await.unwrap().len(), 2);
+        assert_eq!(roles().await, vec![
+            ("me12mber".to_string(), Role::Owner),
+            ("ot12her".to_string(), Role::Member),
+            ("ow12ner".to_string(), Role::Admin),
+        ]);
+
+        // Removing someone leaves everyone else as they were
+        let remove = ChatMemberQuery::Remove { chat: "ab12cde".to_string(), user: "ot12her".to_string() };
+
+        assert_eq!(db.execute(&remove).await.unwrap().len(), 1);
+        assert!(db.execute(&remove).await.unwrap().is_empty());
+        assert_eq!(roles().await.len(), 2);
+
+        let chats = db.execute(&ChatMemberQuery::ChatsOf("ow12ner".to_string())).await.unwrap();
+
+        assert_eq!(chats.iter().map(|member| member.chat_id.as_str()).collect::<Vec<_>>(), vec!["ab12cde"]);
+
+        assert_eq!(db.execute(&ChatMemberQuery::Clear("ab12cde".to_string())).await.unwrap().len(), 2);
+        assert!(roles().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn ownership_is_only_given_to_participants() {
+        let db = memory_db();
+
+        db.execute(&ChatMemberQuery::Add { chat: "ab12cde".to_string(), user: "ow12ner".to_string(), role: Role::Owner }).await.unwrap();
+
+        // Someone who has left since the chat was loaded
+        let transfer = ChatMemberQuery::TransferOwnership { chat: "ab12cde".to_string(), user: "le12fter".to_string() };
+
+        assert!(db.execute(&transfer).await.unwrap().is_empty());
+
+        let members = db.execute(&ChatMemberQuery::ForChat("ab12cde".to_string())).await.unwrap();
+
+        assert_eq!(members.iter().map(|member| (member.user_id.as_str(), member.role)).collect::<Vec<_>>(), vec![("ow12ner", Role::Owner)]);
+    }
+
+    #[tokio::test]
+    async fn legacy_chats_are_migrated_once() {
+        let backend = memory_backend();
+        let db = KolloquyDB::with_backend(backend);
+        let bucket = KolloquyR2::new(MemoryStore::default());
+
+        for (user, enrolled) in [("ow12ner", "ab12cde,gone123"), ("me12mber", "ab12cde")] {
+            backend.query(
+                "INSERT INTO users (email, handle, password, age, country, preferences, suspended, age_verified, userid, phone_number, joined, description, last_agent, last_approx_country, avatar_url, email_verified, last_login, failed_login_attempts, locked_until, timezone, enrolled_chats)\nVALUES (?, ?, '', 19, '', '{}', 0, 0, ?, '', '', '', '', '', '', 0, '', 0, '', '', ?);".to_string(),
+                vec![format!("{user}@kolloquy.com"), user.to_string(), user.to_string(), enrolled.to_string()],
+            ).await.unwrap();
+        }
+
+        // Chats used to be stored with their messages
+        let legacy = serde_json::json!({
+            "name": "Old Chat",
+            "id": "ab12cde",
+            "icon_url": "/chats/ab12cde.svg.br",
+            "messages": [
+                { "content": ["hi *all*"], "author": "ow12ner", "sent": "2025-01-01T00:00:00Z", "id": 0 },
+                { "content": ["hello"], "author": "me12mber", "sent": "2025-01-01T00:01:00Z", "id": 1 },
+            ],
+            "remote_url": "/ab12cde.json.br",
+        });
+
+        let mut compressed = Vec::new();
+
+        BrotliCompress(&mut Cursor::new(legacy.to_string()), &mut compressed, &Default::default()).unwrap();
+        bucket.put_object("/ab12cde.json.br", &compressed).await.unwrap();
+
+        assert_eq!(migrate_legacy_chats(&db, &bucket).await.unwrap(), (1, 2));
+
+        let members = db.execute(&ChatMemberQuery::ForChat("ab12cde".to_string())).await.unwrap();
+
+        assert_eq!(members.into_iter().map(|member| (member.user_id, member.role)).collect::<Vec<_>>(), vec![
+            ("me12mber".to_string(), Role::Member),
+            ("ow12ner".to_string(), Role::Owner),
+        ]);
+
+        let messages = db.execute(&MessageQuery::After { chat: "ab12cde".to_string(), after: None, limit: 10 }).await.unwrap();
+
+        // Old messages only kept their markup, so they're sanitised as they're moved
+        assert_eq!(messages.iter().map(|message| message.current()).collect::<Vec<_>>(), vec!["hi <strong>all</strong>", "hello"]);
+        assert_eq!(messages[0].current_source(), "hi *all*");
+
+        // The chat is rewritten without what was moved out of it
+        let json = decompress_string(&bucket.get_object("/ab12cde.json.br").await.unwrap()).unwrap();
+
+        assert!(!json.contains("messages"));
+
+        // Everything has moved, so there's nothing left to migrate
+        assert_eq!(migrate_legacy_chats(&db, &bucket).await.unwrap(), (0, 0));
+    }
+}
@@ -1,46 +1,156 @@
-use crate::user::User;
-use chrono::DateTime;
+use brotli::BrotliDecompress;
+use chrono::{DateTime, Utc};
 use s3::error::S3Error;
-use s3::request::ResponseData;
 use s3::{Bucket, Region};
+use serde::de::DeserializeOwned;
 use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
 use std::env;
 use std::error::Error;
 use std::fmt::{Display, Formatter};
-use std::hash::Hash;
-use std::marker::PhantomData;
+use std::io::{self, Cursor};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::fs;
+use tokio::sync::RwLock;
+use async_trait::async_trait;
 use awscreds::Credentials;
+use rusqlite::types::ValueRef;
+use rusqlite::Connection;
 
-pub static USER_AVATAR_BUCKET: LazyLock<Box<Bucket>, fn() -> Box<Bucket>> = LazyLock::new(|| Bucket::new(
-    "kolloquy-user-avatars",
-    Region::R2 { account_id: env::var("CLOUDFLARE_ACC_ID").unwrap() },
-    Credentials::new(
-        Some(&*env::var("R2_ACCESS_KEY").unwrap()),
-        Some(&*env::var("R2_SECRET_KEY").unwrap()),
-        None,
-        None,
-        None,
-    ).unwrap(),
-).unwrap().with_path_style());
-
-pub static KOLLOQUY_CHATS_BUCKET: LazyLock<Box<Bucket>, fn() -> Box<Bucket>> = LazyLock::new(|| Bucket::new(
-    "kolloquy-chats",
-    Region::R2 { account_id: env::var("CLOUDFLARE_ACC_ID").unwrap() },
-    Credentials::new(
-        Some(&*env::var("R2_ACCESS_KEY").unwrap()),
-        Some(&*env::var("R2_SECRET_KEY").unwrap()),
-        None,
-        None,
-        None,
-    ).unwrap(),
-).unwrap().with_path_style());
+pub static USER_AVATAR_BUCKET: LazyLock<KolloquyR2> = LazyLock::new(|| KolloquyR2::from_env("kolloquy-user-avatars"));
+
+pub static KOLLOQUY_CHATS_BUCKET: LazyLock<KolloquyR2> = LazyLock::new(|| KolloquyR2::from_env("kolloquy-chats"));
+
+/// Decompress a Brotli compressed UTF-8 object, such as an avatar or a chat.
+pub fn decompress_string(compressed: &[u8]) -> io::Result<String> {
+    let mut decompressed = Vec::new();
+
+    BrotliDecompress(&mut Cursor::new(compressed), &mut decompressed)?;
+
+    String::from_utf8(decompressed).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The schema for Kolloquy's database.
+pub const SCHEMA: &str = include_str!("../schema.sql");
+
+/// The database backend, chosen by the `KOLLOQUY_DB_BACKEND` environment variable:
+/// * `d1` (default) uses Cloudflare D1 with the `CLOUDFLARE_*` and `KOLLOQUY_DB_ID` variables
+/// * `sqlite` uses a local SQLite database at `KOLLOQUY_SQLITE_PATH` (default `kolloquy.db`)
+pub static KOLLOQUY_DB_BACKEND: LazyLock<Box<dyn DBBackend>> = LazyLock::new(|| {
+    match env::var("KOLLOQUY_DB_BACKEND").as_deref() {
+        Ok("sqlite") => {
+            let path = env::var("KOLLOQUY_SQLITE_PATH").unwrap_or("kolloquy.db".to_string());
+
+            Box::new(SQLiteBackend::open(path).unwrap())
+        }
+        Ok("d1") | Err(_) => Box::new(D1Backend::from_env()),
+        Ok(other) => panic!("Unknown database backend '{other}', expected 'd1' or 'sqlite'."),
+    }
+});
 
 pub enum R2QueryKind {
     PutObject(Vec<u8>),
     GetObject,
     DeleteObject,
+    HeadObject,
+    /// List every object whose path starts with the query's path
+    ListObjects,
+}
+
+/// Information about a stored object, without its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectHead {
+    pub size: u64,
+}
+
+/// The result of executing an [`R2Query`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ObjectData {
+    Bytes(Vec<u8>),
+    Head(Option<ObjectHead>),
+    Paths(Vec<String>),
+    Empty,
+}
+
+impl ObjectData {
+    /// The contents of the object, or nothing if this wasn't a [`R2QueryKind::GetObject`].
+    pub fn into_bytes(self) -> Vec<u8> {
+        match self {
+            Self::Bytes(bytes) => bytes,
+            _ => Vec::new(),
+        }
+    }
+
+    /// The paths that were found, or nothing if this wasn't a [`R2QueryKind::ListObjects`].
+    pub fn into_paths(self) -> Vec<String> {
+        match self {
+            Self::Paths(paths) => paths,
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// A query on objects that don't belong to a query type of their own, like everything under a prefix.
+#[derive(Debug, Clone)]
+pub enum ObjectQuery {
+    Delete(String),
+    Head(String),
+    /// Every object whose path starts with this
+    List(String),
+}
+
+impl Query for ObjectQuery {
+    fn has_result(&self) -> bool {
+        !matches!(self, Self::Delete(_))
+    }
+}
+
+impl R2Query for ObjectQuery {
+    fn path(&self) -> String {
+        match self {
+            Self::Delete(path) | Self::Head(path) | Self::List(path) => path.clone(),
+        }
+    }
+
+    fn kind(&self) -> R2QueryKind {
+        match self {
+            Self::Delete(_) => R2QueryKind::DeleteObject,
+            Self::Head(_) => R2QueryKind::HeadObject,
+            Self::List(_) => R2QueryKind::ListObjects,
+        }
+    }
+}
+
+/// A bucket that objects can be stored in.
+///
+/// Paths are relative to the bucket, and a leading `/` is ignored.
+/// Getting a missing object fails with `S3Error::HttpFailWithBody(404, _)`, as it does on R2.
+#[async_trait]
+pub trait ObjectStore: Send + Sync {
+    async fn put_object(&self, path: &str, data: &[u8]) -> Result<(), S3Error>;
+    async fn get_object(&self, path: &str) -> Result<Vec<u8>, S3Error>;
+    async fn delete_object(&self, path: &str) -> Result<(), S3Error>;
+    async fn head_object(&self, path: &str) -> Result<Option<ObjectHead>, S3Error>;
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, S3Error>;
+}
+
+/// A Cloudflare R2 bucket.
+pub struct R2Store {
+    bucket: Box<Bucket>,
+}
+
+/// A directory on the local filesystem, with one file per object.
+pub struct DirectoryStore {
+    root: PathBuf,
+}
+
+/// Objects kept in memory, which are lost when the server stops.
+#[derive(Default)]
+pub struct MemoryStore {
+    objects: RwLock<BTreeMap<String, Vec<u8>>>,
 }
 
 pub trait Query: Send + Sync {
@@ -53,233 +163,711 @@ pub trait R2Query: Query {
 }
 
 pub trait DBQuery: Query {
+    /// What executing this query produces, e.g. `()`, `Option<T>`, `Vec<T>` or [`RowsAffected`].
+    type Output: FromQueryResult;
+
     fn to_sql_query_string(&self) -> (String, Vec<String>);
 }
 
-pub struct KolloquyDB<'a>(PhantomData<&'a ()>);
+/// A single row returned from the database, keyed by column name.
+pub type Row = Map<String, Value>;
 
-pub struct KolloquyR2 {
-    bucket: Bucket
+/// The raw result of running a single SQL statement.
+#[derive(Debug, Default, Clone)]
+pub struct QueryResult {
+    pub rows: Vec<Row>,
+    /// How many rows were inserted, updated or deleted by the statement
+    pub changes: u64,
 }
 
-#[derive(Debug)]
-pub enum QueryError<'a> {
-    InvalidQuery,
-    NotFound,
-    ServerError,
-    Other(Box<dyn Error + 'a>),
+/// A type that can be decoded from a database row.
+pub trait FromRow: Sized {
+    fn from_row(row: &Row) -> Result<Self, QueryError<'static>>;
 }
 
-// Just trust me bro
-unsafe impl<'a> Send for QueryError<'a> {}
-unsafe impl<'a> Sync for QueryError<'a> {}
+/// A type that can be produced from the result of a [`DBQuery`].
+pub trait FromQueryResult: Sized {
+    /// Whether this is a single row, which is [`QueryError::NotFound`] rather than empty when a query has no result
+    const SINGLE_ROW: bool = false;
 
-impl<'a> Clone for QueryError<'a> {
-    fn clone(&self) -> Self {
-        match self {
-            Self::InvalidQuery => Self::InvalidQuery,
-            Self::NotFound => Self::NotFound,
-            Self::ServerError => Self::ServerError,
-            Self::Other(e) => unsafe {
-                Self::Other((e as *const Box<dyn Error + 'a>).read())
-            },
-        }
+    fn from_query_result(result: QueryResult) -> Result<Self, QueryError<'static>>;
+}
+
+/// The number of rows changed by a query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RowsAffected(pub u64);
+
+impl FromQueryResult for () {
+    fn from_query_result(_: QueryResult) -> Result<Self, QueryError<'static>> {
+        Ok(())
     }
 }
 
-impl<'a, E: Error> From<&'a E> for QueryError<'a> {
-    fn from(err: &'a E) -> Self {
-        Self::Other(Box::new(err))
+impl FromQueryResult for RowsAffected {
+    fn from_query_result(result: QueryResult) -> Result<Self, QueryError<'static>> {
+        Ok(Self(result.changes))
     }
 }
 
-impl<'a> From<&'a dyn Error> for QueryError<'a> {
-    fn from(err: &'a dyn Error) -> Self {
-        Self::Other(Box::new(err))
+/// The first row, if there is one.
+impl<T: FromRow> FromQueryResult for Option<T> {
+    const SINGLE_ROW: bool = true;
+
+    fn from_query_result(result: QueryResult) -> Result<Self, QueryError<'static>> {
+        result.rows.first().map(T::from_row).transpose()
+    }
+}
+
+impl<T: FromRow> FromQueryResult for Vec<T> {
+    fn from_query_result(result: QueryResult) -> Result<Self, QueryError<'static>> {
+        result.rows.iter().map(T::from_row).collect()
+    }
+}
+
+/// Decode a single column of a row through serde.
+pub fn column<T: DeserializeOwned>(row: &Row, name: &str) -> Result<T, QueryError<'static>> {
+    let value = row.get(name).ok_or_else(|| QueryError::MalformedRow(format!("missing column '{name}'")))?;
+
+    serde_json::from_value(value.clone())
+        .map_err(|e| QueryError::MalformedRow(format!("column '{name}': {e}")))
+}
+
+/// Decode an RFC 3339 timestamp column.
+pub fn datetime_column(row: &Row, name: &str) -> Result<DateTime<Utc>, QueryError<'static>> {
+    DateTime::from_str(&column::<String>(row, name)?)
+        .map_err(|e| QueryError::MalformedRow(format!("column '{name}': {e}")))
+}
+
+/// Decode a `0`/`1` integer column as a boolean.
+pub fn bool_column(row: &Row, name: &str) -> Result<bool, QueryError<'static>> {
+    Ok(column::<i64>(row, name)? == 1)
+}
+
+/// A database that SQL queries can be run against.
+///
+/// Every backend must accept the same SQLite-dialect SQL with `?` parameters,
+/// and return each row as a JSON object keyed by column name (see [`Row`]).
+#[async_trait]
+pub trait DBBackend: Send + Sync {
+    async fn query(&self, sql: String, params: Vec<String>) -> Result<QueryResult, QueryError<'static>>;
+}
+
+/// Cloudflare D1, accessed through its HTTP API.
+pub struct D1Backend {
+    account_id: String,
+    database_id: String,
+    email: String,
+    api_key: String,
+    client: reqwest::Client,
+}
+
+/// An embedded SQLite database, stored in a local file or in memory.
+pub struct SQLiteBackend {
+    connection: Arc<Mutex<Connection>>,
+}
+
+pub struct KolloquyDB<'a> {
+    backend: &'a dyn DBBackend,
+}
+
+#[derive(Clone)]
+pub struct KolloquyR2 {
+    store: Arc<dyn ObjectStore>,
+}
+
+#[derive(Debug, Clone)]
+pub enum QueryError<'a> {
+    NotFound,
+    ServerError,
+    /// A row could not be decoded into the query's output type
+    MalformedRow(String),
+    Other(Arc<dyn Error + Send + Sync + 'a>),
+}
+
+impl<'a, E: Error + Sync> From<&'a E> for QueryError<'a> {
+    fn from(err: &'a E) -> Self {
+        Self::Other(Arc::new(err))
     }
 }
 
-impl<'a, E: Error + 'a> From<Box<E>> for QueryError<'a> {
+impl<'a, E: Error + Send + Sync + 'a> From<Box<E>> for QueryError<'a> {
     fn from(err: Box<E>) -> Self {
-        Self::Other(err.into())
+        Self::Other(Arc::<E>::from(err))
     }
 }
 
-impl<'a> From<Box<dyn Error + 'a>> for QueryError<'a> {
-    fn from(err: Box<dyn Error + 'a>) -> Self {
-        Self::Other(err)
+impl<'a> From<Box<dyn Error + Send + Sync + 'a>> for QueryError<'a> {
+    fn from(err: Box<dyn Error + Send + Sync + 'a>) -> Self {
+        Self::Other(Arc::from(err))
     }
 }
 
 impl Display for QueryError<'_> {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{self:?}")
+        match self {
+            Self::NotFound => write!(f, "no matching row"),
+            Self::ServerError => write!(f, "the database returned an error"),
+            Self::MalformedRow(reason) => write!(f, "malformed row: {reason}"),
+            Self::Other(err) => write!(f, "{err}"),
+        }
     }
 }
 
 impl Error for QueryError<'_> {}
 
 impl<'a> KolloquyDB<'a> {
+    /// Create a handle to the database backend chosen at startup (see [`KOLLOQUY_DB_BACKEND`]).
     pub fn new() -> Self {
-        Self(PhantomData)
+        Self {
+            backend: &**KOLLOQUY_DB_BACKEND,
+        }
+    }
+
+    /// Create a handle to a specific database backend.
+    pub fn with_backend(backend: &'a dyn DBBackend) -> Self {
+        Self {
+            backend
+        }
+    }
+
+    /// Run a query, failing with [`QueryError::NotFound`] if it should find a single row and doesn't.
+    pub async fn execute<Q: DBQuery>(&self, original_query: &Q) -> Result<Q::Output, QueryError<'a>> {
+        let (query, params) = original_query.to_sql_query_string();
+
+        let result = self.backend.query(query, params).await?;
+
+        if Q::Output::SINGLE_ROW && original_query.has_result() && result.rows.is_empty() {
+            return Err(QueryError::NotFound);
+        }
+
+        Q::Output::from_query_result(result)
     }
+}
 
-    pub async fn execute<Q: DBQuery>(&self, original_query: &Q) -> Result<Option<User>, QueryError<'a>> {
+impl D1Backend {
+    /// Read the Cloudflare account, database and API credentials from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            account_id: env::var("CLOUDFLARE_ACC_ID").unwrap(),
+            database_id: env::var("KOLLOQUY_DB_ID").unwrap(),
+            email: env::var("CLOUDFLARE_EMAIL").unwrap(),
+            api_key: env::var("CLOUDFLARE_API_KEY").unwrap(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl DBBackend for D1Backend {
+    async fn query(&self, sql: String, params: Vec<String>) -> Result<QueryResult, QueryError<'static>> {
         let url = format!(
             "https://api.cloudflare.com/client/v4/accounts/{}/d1/database/{}/query",
-            env::var("CLOUDFLARE_ACC_ID").unwrap(),
-            env::var("KOLLOQUY_DB_ID").unwrap()
+            self.account_id,
+            self.database_id,
         );
-        
-        let client = reqwest::Client::new();
-
-        let (query, params) = original_query.to_sql_query_string();
 
-        let json: Map<String, Value> = serde_json::from_str(&*client.post(url)
+        let json: Map<String, Value> = serde_json::from_str(&self.client.post(url)
             .header("Content-Type", "application/json")
-            .header("X-Auth-Email", env::var("CLOUDFLARE_EMAIL").unwrap())
-            .header("Authorization", format!("Bearer {}", env::var("CLOUDFLARE_API_KEY").unwrap()))
+            .header("X-Auth-Email", &self.email)
+            .header("Authorization", format!("Bearer {}", self.api_key))
             .body(serde_json::to_string(&json!({
-                "sql": query,
+                "sql": sql,
                 "params": params,
             })).unwrap())
             .send()
             .await
-            .map_err(|e| QueryError::Other(Box::new(e)))?
+            .map_err(|e| QueryError::Other(Arc::new(e)))?
             .text()
             .await
-            .map_err(|e| QueryError::Other(Box::new(e)))?).unwrap();
+            .map_err(|e| QueryError::Other(Arc::new(e)))?).map_err(|e| QueryError::Other(Arc::new(e)))?;
 
-        if !json.get("success").unwrap().as_bool().unwrap() {
+        if !json.get("success").and_then(Value::as_bool).unwrap_or(false) {
             eprintln!("{json:#?}");
 
             return Err(QueryError::ServerError);
         }
-        
-        if !original_query.has_result() {
-            return Ok(None)
-        }
-        
-        if json.get("result").unwrap().as_array().unwrap().len() == 0 {
-            return Err(QueryError::NotFound);
-        }
 
-        let result = json.get("result").unwrap().as_array().unwrap()[0].as_object().unwrap().get("results").unwrap().as_array().unwrap();
+        // D1 returns one result set per statement; Kolloquy only ever sends one statement
+        let Some(result) = json.get("result").and_then(Value::as_array).and_then(|result| result.first()) else {
+            return Ok(QueryResult::default());
+        };
+
+        let rows = result.get("results")
+            .and_then(Value::as_array)
+            .map(|rows| rows.iter().filter_map(|row| row.as_object().cloned()).collect())
+            .unwrap_or_default();
 
-        if result.len() == 0 {
-            return Err(QueryError::NotFound);
-        }
+        let changes = result.pointer("/meta/changes").and_then(Value::as_u64).unwrap_or(0);
 
-        let results = result[0].as_object().unwrap();
-
-        println!("{results:?}");
-        
-        let user = User {
-            email: results["email"].as_str().unwrap().to_string(),
-            handle: results["handle"].as_str().unwrap().to_string(),
-            password: results["password"].as_str().unwrap().to_string(),
-            age: results["age"].as_number().unwrap().as_u64().unwrap() as i32,
-            country: results["country"].as_str().unwrap().to_string(),
-            preferences: results["preferences"].as_str().unwrap().to_string(),
-            suspended: results["suspended"].as_number().unwrap().as_u64().unwrap() as i32 == 1,
-            age_verified: results["age_verified"].as_number().unwrap().as_u64().unwrap() as i32 == 1,
-            user_id: results["userid"].as_str().unwrap().to_string(),
-            phone_number: results["phone_number"].as_str().unwrap().to_string(),
-            joined: DateTime::from_str(results["joined"].as_str().unwrap()).unwrap(),
-            description: results["description"].as_str().unwrap().to_string(),
-            last_agent: results["last_agent"].as_str().unwrap().to_string(),
-            last_approx_country: results["last_approx_country"].as_str().unwrap().to_string(),
-            avatar_url: results["avatar_url"].as_str().unwrap().to_string(),
-            email_verified: results["email_verified"].as_number().unwrap().as_u64().unwrap() as i32 == 1,
-            last_login: DateTime::from_str(results["last_login"].as_str().unwrap()).unwrap(),
-            failed_login_attempts: results["failed_login_attempts"].as_number().unwrap().as_u64().unwrap() as i32,
-            locked_until: DateTime::from_str(results["locked_until"].as_str().unwrap()).unwrap(),
-            timezone: results["timezone"].as_str().unwrap().to_string(),
-            enrolled_chats: results["enrolled_chats"].as_str().unwrap().split(",").map(|s| s.to_string()).collect(),
-        };
+        Ok(QueryResult {
+            rows,
+            changes,
+        })
+    }
+}
+
+impl SQLiteBackend {
+    /// Open (or create) the SQLite database at `path` and apply [`SCHEMA`] to it.
+    ///
+    /// `:memory:` opens a private in-memory database, which is useful for tests.
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let connection = Connection::open(path)?;
 
-        Ok(Some(user))
+        connection.execute_batch(SCHEMA)?;
+
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+}
+
+#[async_trait]
+impl DBBackend for SQLiteBackend {
+    async fn query(&self, sql: String, params: Vec<String>) -> Result<QueryResult, QueryError<'static>> {
+        let connection = self.connection.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let connection = connection.lock().unwrap();
+            let mut statement = connection.prepare(&sql)?;
+
+            let read_only = statement.readonly();
+            let columns = statement.column_names().into_iter().map(String::from).collect::<Vec<_>>();
+            let mut rows = statement.query(rusqlite::params_from_iter(params.iter()))?;
+            let mut results = Vec::new();
+
+            while let Some(row) = rows.next()? {
+                let mut map = Map::new();
+
+                for (i, column) in columns.iter().enumerate() {
+                    let value = match row.get_ref(i)? {
+                        ValueRef::Null => Value::Null,
+                        ValueRef::Integer(int) => Value::from(int),
+                        ValueRef::Real(real) => Value::from(real),
+                        ValueRef::Text(text) | ValueRef::Blob(text) => Value::from(String::from_utf8_lossy(text)),
+                    };
+
+                    map.insert(column.clone(), value);
+                }
+
+                results.push(map);
+            }
+
+            drop(rows);
+
+            // `changes` keeps the count from the last write, so it's meaningless after a read
+            let changes = if read_only { 0 } else { connection.changes() };
+
+            Ok(QueryResult {
+                rows: results,
+                changes,
+            })
+        }).await.map_err(|e| QueryError::Other(Arc::new(e)))?
+            .map_err(|e: rusqlite::Error| QueryError::Other(Arc::new(e)))
     }
 }
 
 impl KolloquyR2 {
-    pub fn new(bucket: Bucket) -> Self {
+    pub fn new(store: impl ObjectStore + 'static) -> Self {
         Self {
-            bucket
+            store: Arc::new(store),
+        }
+    }
+
+    /// Open the bucket called `name` in the object store chosen by the `KOLLOQUY_OBJECT_STORE` environment variable:
+    /// * `r2` (default) uses Cloudflare R2 with the `CLOUDFLARE_ACC_ID`, `R2_ACCESS_KEY` and `R2_SECRET_KEY` variables
+    /// * `fs` uses a subdirectory of `KOLLOQUY_OBJECT_STORE_DIR` (default `objects`)
+    /// * `memory` keeps objects in memory
+    pub fn from_env(name: &str) -> Self {
+        match env::var("KOLLOQUY_OBJECT_STORE").as_deref() {
+            Ok("fs") => {
+                let root = env::var("KOLLOQUY_OBJECT_STORE_DIR").unwrap_or("objects".to_string());
+
+                Self::new(DirectoryStore::new(PathBuf::from(root).join(name)))
+            }
+            Ok("memory") => Self::new(MemoryStore::default()),
+            Ok("r2") | Err(_) => {
+                let bucket = Bucket::new(
+                    name,
+                    Region::R2 { account_id: env::var("CLOUDFLARE_ACC_ID").unwrap() },
+                    Credentials::new(
+                        Some(&*env::var("R2_ACCESS_KEY").unwrap()),
+                        Some(&*env::var("R2_SECRET_KEY").unwrap()),
+                        None,
+                        None,
+                        None,
+                    ).unwrap(),
+                ).unwrap().with_path_style();
+
+                Self::new(R2Store::new(bucket))
+            }
+            Ok(other) => panic!("Unknown object store '{other}', expected 'r2', 'fs' or 'memory'."),
         }
     }
 
-    pub async fn execute<Q: R2Query>(&self, query: &Q) -> Result<ResponseData, S3Error> {
+    pub async fn execute<Q: R2Query>(&self, query: &Q) -> Result<ObjectData, S3Error> {
         match query.kind() {
             R2QueryKind::PutObject(data) => {
-                self.bucket.put_object(query.path(), &*data).await
+                self.store.put_object(&query.path(), &data).await.map(|_| ObjectData::Empty)
             }
 
             R2QueryKind::GetObject => {
-                self.bucket.get_object(query.path()).await
+                self.store.get_object(&query.path()).await.map(ObjectData::Bytes)
             }
 
             R2QueryKind::DeleteObject => {
-                self.bucket.delete_object(query.path()).await
+                self.store.delete_object(&query.path()).await.map(|_| ObjectData::Empty)
             }
+
+            R2QueryKind::HeadObject => {
+                self.store.head_object(&query.path()).await.map(ObjectData::Head)
+            }
+
+            R2QueryKind::ListObjects => {
+                self.store.list_objects(&query.path()).await.map(ObjectData::Paths)
+            }
+        }
+    }
+}
+
+impl Deref for KolloquyR2 {
+    type Target = dyn ObjectStore;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.store
+    }
+}
+
+/// Strip the leading `/` from an object path, and make sure it can't escape its bucket.
+fn normalise_path(path: &str) -> Result<String, S3Error> {
+    let path = path.trim_start_matches('/');
+
+    if path.is_empty() || path.split('/').any(|part| part == ".." || part == ".") {
+        return Err(S3Error::HttpFailWithBody(400, format!("Invalid object path '{path}'.")));
+    }
+
+    Ok(path.to_string())
+}
+
+fn not_found(path: &str) -> S3Error {
+    S3Error::HttpFailWithBody(404, format!("Object '{path}' does not exist."))
+}
+
+impl R2Store {
+    pub fn new(bucket: Box<Bucket>) -> Self {
+        Self {
+            bucket
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for R2Store {
+    async fn put_object(&self, path: &str, data: &[u8]) -> Result<(), S3Error> {
+        self.bucket.put_object(path, data).await.map(|_| ())
+    }
+
+    async fn get_object(&self, path: &str) -> Result<Vec<u8>, S3Error> {
+        self.bucket.get_object(path).await.map(|response| response.to_vec())
+    }
+
+    async fn delete_object(&self, path: &str) -> Result<(), S3Error> {
+        self.bucket.delete_object(path).await.map(|_| ())
+    }
+
+    async fn head_object(&self, path: &str) -> Result<Option<ObjectHead>, S3Error> {
+        match self.bucket.head_object(path).await {
+            Ok((head, 200)) => Ok(Some(ObjectHead {
+                size: head.content_length.unwrap_or(0) as u64,
+            })),
+            Ok(_) | Err(S3Error::HttpFailWithBody(404, _)) => Ok(None),
+            Err(e) => Err(e),
         }
     }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, S3Error> {
+        let pages = self.bucket.list(prefix.trim_start_matches('/').to_string(), None).await?;
+
+        Ok(pages.into_iter().flat_map(|page| page.contents).map(|object| object.key).collect())
+    }
+}
+
+impl DirectoryStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root
+        }
+    }
+}
+
+#[async_trait]
+impl ObjectStore for DirectoryStore {
+    async fn put_object(&self, path: &str, data: &[u8]) -> Result<(), S3Error> {
+        let file = self.root.join(normalise_path(path)?);
+
+        if let Some(parent) = file.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+
+        fs::write(file, data).await?;
+
+        Ok(())
+    }
+
+    async fn get_object(&self, path: &str) -> Result<Vec<u8>, S3Error> {
+        match fs::read(self.root.join(normalise_path(path)?)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(not_found(path)),
+            result => Ok(result?),
+        }
+    }
+
+    async fn delete_object(&self, path: &str) -> Result<(), S3Error> {
+        // Deleting a missing object succeeds on R2, so it does here too
+        match fs::remove_file(self.root.join(normalise_path(path)?)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => Ok(result?),
+        }
+    }
+
+    async fn head_object(&self, path: &str) -> Result<Option<ObjectHead>, S3Error> {
+        match fs::metadata(self.root.join(normalise_path(path)?)).await {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+            Ok(metadata) => Ok(Some(ObjectHead {
+                size: metadata.len(),
+            })),
+        }
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, S3Error> {
+        let prefix = prefix.trim_start_matches('/');
+        let mut paths = Vec::new();
+        let mut directories = vec![self.root.clone()];
+
+        while let Some(directory) = directories.pop() {
+            let mut entries = match fs::read_dir(&directory).await {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                entries => entries?,
+            };
+
+            while let Some(entry) = entries.next_entry().await? {
+                if entry.file_type().await?.is_dir() {
+                    directories.push(entry.path());
+                    continue;
+                }
+
+                let Ok(relative) = entry.path().strip_prefix(&self.root).map(|path| path.to_string_lossy().replace('\\', "/")) else {
+                    continue;
+                };
+
+                if relative.starts_with(prefix) {
+                    paths.push(relative);
+                }
+            }
+        }
+
+        paths.sort();
+
+        Ok(paths)
+    }
+}
+
+#[async_trait]
+impl ObjectStore for MemoryStore {
+    async fn put_object(&self, path: &str, data: &[u8]) -> Result<(), S3Error> {
+        self.objects.write().await.insert(normalise_path(path)?, data.to_vec());
+
+        Ok(())
+    }
+
+    async fn get_object(&self, path: &str) -> Result<Vec<u8>, S3Error> {
+        self.objects.read().await.get(&normalise_path(path)?).cloned().ok_or_else(|| not_found(path))
+    }
+
+    async fn delete_object(&self, path: &str) -> Result<(), S3Error> {
+        self.objects.write().await.remove(&normalise_path(path)?);
+
+        Ok(())
+    }
+
+    async fn head_object(&self, path: &str) -> Result<Option<ObjectHead>, S3Error> {
+        Ok(self.objects.read().await.get(&normalise_path(path)?).map(|data| ObjectHead {
+            size: data.len() as u64,
+        }))
+    }
+
+    async fn list_objects(&self, prefix: &str) -> Result<Vec<String>, S3Error> {
+        let prefix = prefix.trim_start_matches('/');
+
+        Ok(self.objects.read().await.keys().filter(|path| path.starts_with(prefix)).cloned().collect())
+    }
 }
 
+#[cfg(test)]
 mod tests {
     use crate::create_avatar;
-    use crate::data::KolloquyR2;
-    use crate::user::{User, UserQuery};
-    use awscreds::Credentials;
-    use s3::{Bucket, Region};
+    use crate::data::{column, DBQuery, DirectoryStore, FromRow, KolloquyR2, MemoryStore, ObjectData, ObjectHead, ObjectQuery, ObjectStore, Query, QueryError, Row, RowsAffected};
+    use crate::test_support::{memory_db, test_user};
+    use crate::user::UserQuery;
+    use s3::error::S3Error;
 
     #[tokio::test]
-    async fn try_upload_file() -> Result<(), Box<dyn std::error::Error>> {
-        dotenv::dotenv().ok();
-        
-        let bucket = Bucket::new(
-            "kolloquy-user-avatars",
-            Region::R2 { account_id: std::env::var("CLOUDFLARE_ACC_ID").unwrap() },
-            // Credentials are collected from environment, config, profile or instance metadata
-            Credentials::new(
-                Some(&*std::env::var("R2_ACCESS_KEY").unwrap()),
-                Some(&*std::env::var("R2_SECRET_KEY").unwrap()),
-                None,
-                None,
-                None,
-            )?,
-        )?.with_path_style();
-
-        let user = User {
-            email: "".to_string(),
-            handle: "".to_string(),
-            password: "".to_string(),
-            age: 0,
-            country: "".to_string(),
-            preferences: "".to_string(),
-            suspended: false,
-            age_verified: false,
-            user_id: "TEST".to_string(),
-            phone_number: "".to_string(),
-            joined: Default::default(),
-            description: "".to_string(),
-            last_agent: "".to_string(),
-            last_approx_country: "".to_string(),
-            avatar_url: "".to_string(),
-            email_verified: false,
-            last_login: Default::default(),
-            failed_login_attempts: 0,
-            locked_until: Default::default(),
-            timezone: "".to_string(),
-            enrolled_chats: vec![]
+    async fn sqlite_runs_user_queries() {
+        let db = memory_db();
+        let user = test_user("xy12abc");
+
+        assert!(matches!(db.execute(&UserQuery::GetByEmail(user.email.clone())).await, Err(QueryError::NotFound)));
+
+        db.execute(&UserQuery::PutToDB(user.clone())).await.unwrap();
+
+        let fetched = db.execute(&UserQuery::GetByHandle(user.handle.clone())).await.unwrap().unwrap();
+
+        assert_eq!(fetched.user_id, user.user_id);
+        assert_eq!(fetched.age, 19);
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Handle {
+        handle: String,
+        age: i32,
+    }
+
+    impl FromRow for Handle {
+        fn from_row(row: &Row) -> Result<Self, QueryError<'static>> {
+            Ok(Self {
+                handle: column(row, "handle")?,
+                age: column(row, "age")?,
+            })
+        }
+    }
+
+    /// Selects every handle, optionally with an age that can't be decoded
+    struct AllHandles {
+        malformed: bool,
+    }
+
+    impl Query for AllHandles {
+        fn has_result(&self) -> bool {
+            true
+        }
+    }
+
+    impl DBQuery for AllHandles {
+        type Output = Vec<Handle>;
+
+        fn to_sql_query_string(&self) -> (String, Vec<String>) {
+            if self.malformed {
+                ("SELECT handle, 'old' AS age FROM users ORDER BY handle".to_string(), vec![])
+            } else {
+                ("SELECT handle, age FROM users ORDER BY handle".to_string(), vec![])
+            }
+        }
+    }
+
+    struct SuspendAll;
+
+    impl Query for SuspendAll {
+        fn has_result(&self) -> bool {
+            false
+        }
+    }
+
+    impl DBQuery for SuspendAll {
+        type Output = RowsAffected;
+
+        fn to_sql_query_string(&self) -> (String, Vec<String>) {
+            ("UPDATE users SET suspended = 1".to_string(), vec![])
+        }
+    }
+
+    #[tokio::test]
+    async fn sqlite_decodes_typed_rows() {
+        let db = memory_db();
+
+        // Lists that find nothing are empty rather than missing
+        assert_eq!(db.execute(&AllHandles { malformed: false }).await.unwrap(), vec![]);
+
+        db.execute(&UserQuery::PutToDB(test_user("ab12cde"))).await.unwrap();
+        db.execute(&UserQuery::PutToDB(test_user("xy12abc"))).await.unwrap();
+
+        assert_eq!(db.execute(&AllHandles { malformed: false }).await.unwrap(), vec![
+            Handle { handle: "ab12cde".to_string(), age: 19 },
+            Handle { handle: "xy12abc".to_string(), age: 19 },
+        ]);
+
+        assert!(matches!(db.execute(&AllHandles { malformed: true }).await, Err(QueryError::MalformedRow(_))));
+
+        // Writes report how many rows they changed
+        assert_eq!(db.execute(&SuspendAll).await.unwrap(), RowsAffected(2));
+    }
+
+    async fn exercise_store(store: &dyn ObjectStore) {
+        assert!(matches!(store.get_object("/chats/ab12cde.svg.br").await, Err(S3Error::HttpFailWithBody(404, _))));
+        assert_eq!(store.head_object("/chats/ab12cde.svg.br").await.unwrap(), None);
+
+        store.put_object("/chats/ab12cde.svg.br", b"icon").await.unwrap();
+        store.put_object("/xy12abc.svg.br", b"avatar").await.unwrap();
+
+        assert_eq!(store.get_object("/chats/ab12cde.svg.br").await.unwrap(), b"icon");
+        assert_eq!(store.get_object("xy12abc.svg.br").await.unwrap(), b"avatar");
+        assert_eq!(store.head_object("chats/ab12cde.svg.br").await.unwrap(), Some(ObjectHead { size: 4 }));
+        assert_eq!(store.list_objects("/chats/").await.unwrap(), vec!["chats/ab12cde.svg.br".to_string()]);
+        assert_eq!(store.list_objects("").await.unwrap().len(), 2);
+        assert!(store.get_object("/../escape").await.is_err());
+
+        store.delete_object("/chats/ab12cde.svg.br").await.unwrap();
+        store.delete_object("/chats/ab12cde.svg.br").await.unwrap();
+
+        assert!(matches!(store.get_object("/chats/ab12cde.svg.br").await, Err(S3Error::HttpFailWithBody(404, _))));
+        assert_eq!(store.head_object("/chats/ab12cde.svg.br").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn memory_store_round_trip() {
+        exercise_store(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn directory_store_round_trip() {
+        let root = std::env::temp_dir().join(format!("kolloquy-store-{}", std::process::id()));
+
+        exercise_store(&DirectoryStore::new(root.clone())).await;
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn avatar_queries_use_store() {
+        let r2 = KolloquyR2::new(MemoryStore::default());
+        let user = test_user("xy12abc");
+
+        r2.execute(&UserQuery::UploadAvatar(user.clone(), create_avatar())).await.unwrap();
+
+        let ObjectData::Bytes(avatar) = r2.execute(&UserQuery::GetAvatar(user)).await.unwrap() else {
+            panic!("GetAvatar should return the avatar's bytes");
         };
 
-        let query = UserQuery::UploadAvatar(user, create_avatar());
+        assert!(!avatar.is_empty());
+    }
+
+    #[tokio::test]
+    async fn object_queries_use_store() {
+        let r2 = KolloquyR2::new(MemoryStore::default());
 
-        let r2 = KolloquyR2::new(*bucket);
+        r2.put_object("/attachments/ab12cde/one", b"first").await.unwrap();
+        r2.put_object("/attachments/ab12cde/two", b"second").await.unwrap();
+        r2.put_object("/attachments/zz99zzz/one", b"other").await.unwrap();
 
-        r2.execute(&query).await?;
+        let paths = r2.execute(&ObjectQuery::List("/attachments/ab12cde/".to_string())).await.unwrap().into_paths();
 
-        Ok(())
+        assert_eq!(paths, vec!["attachments/ab12cde/one".to_string(), "attachments/ab12cde/two".to_string()]);
+        assert_eq!(r2.execute(&ObjectQuery::Head(paths[1].clone())).await.unwrap(), ObjectData::Head(Some(ObjectHead { size: 6 })));
+
+        for path in paths {
+            assert_eq!(r2.execute(&ObjectQuery::Delete(path)).await.unwrap(), ObjectData::Empty);
+        }
+
+        assert!(r2.execute(&ObjectQuery::List("attachments/ab12cde".to_string())).await.unwrap().into_paths().is_empty());
+        assert_eq!(r2.execute(&ObjectQuery::Head("/attachments/ab12cde/one".to_string())).await.unwrap(), ObjectData::Head(None));
+        assert_eq!(r2.execute(&ObjectQuery::List("attachments".to_string())).await.unwrap().into_paths().len(), 1);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,436 @@
+//! Every error the server sends, over HTTP or the chat socket, with its status, code and message.
+//!
+//! Codes are grouped by what went wrong:
+//! * `0`–`99` the user isn't allowed to do this
+//! * `100`–`199` something conflicts with or is missing from an account
+//! * `200`–`299` the request is invalid, or refers to something that doesn't exist
+//! * `300`–`399` the server couldn't do its part
+//! * `400`–`499` too many requests
+
+use crate::access_token::Scope;
+use crate::attachment::{ATTACHMENT_TYPES, MAX_ATTACHMENT_BYTES, MAX_MESSAGE_ATTACHMENTS, MAX_PENDING_ATTACHMENTS};
+use crate::chat::{MessageError, MAX_MESSAGE_LENGTH};
+use crate::data::QueryError;
+use crate::mail::MailError;
+use crate::role::{Permission, Role};
+use chrono::TimeDelta;
+use poem::error::{ReadBodyError, ResponseError};
+use poem::http::{header, StatusCode};
+use poem::{IntoResponse, Response};
+use s3::error::S3Error;
+use serde_json::json;
+use std::fmt::{Display, Formatter};
+
+#[derive(Debug)]
+pub enum ApiError {
+    IncorrectPassword,
+    NotParticipant,
+    NotAuthor,
+    AccountLocked { retry_after: TimeDelta },
+    NotSignedIn,
+    EmailNotVerified,
+    NeedsAccessToken,
+    MissingScope(Scope),
+    /// The user's role in the chat doesn't have this permission
+    NotAllowed(Permission),
+    OwnerCannotLeave,
+
+    EmailTaken,
+    HandleTaken,
+    UserNotFound,
+
+    /// The body didn't match its schema, described in the details
+    InvalidBody(String),
+    InvalidEmail,
+    InvalidHandle,
+    InvalidPasswordHash,
+    InvalidRedirect,
+    ChatNotFound,
+    ChatNameTooLong,
+    ConflictingCursors,
+    UnsupportedProtocol(String),
+    MessageNotFound,
+    EditWindowClosed,
+    SessionNotFound,
+    InvalidVerificationLink,
+    ExpiredVerificationLink,
+    InvalidResetLink,
+    ExpiredResetLink,
+    AccessTokenNotFound,
+    AccessTokenName,
+    AccessTokenScopes,
+    AlreadyParticipant,
+    ParticipantNotFound,
+    EmptyMessage,
+    MessageTooLong,
+    AttachmentNotFound,
+    AttachmentTooLarge,
+    UnsupportedAttachment,
+    UnreadableImage,
+    TooManyAttachments,
+    TooManyPendingAttachments,
+
+    Database(String),
+    Mail(String),
+    Storage(String),
+    Render(String),
+
+    TooManyRequests { retry_after: TimeDelta },
+    TooManyAttempts { retry_after: TimeDelta },
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::NotSignedIn | Self::NeedsAccessToken => StatusCode::UNAUTHORIZED,
+
+            Self::IncorrectPassword
+            | Self::NotParticipant
+            | Self::NotAuthor
+            | Self::AccountLocked { .. }
+            | Self::EmailNotVerified
+            | Self::MissingScope(_)
+            | Self::NotAllowed(_)
+            | Self::OwnerCannotLeave
+            | Self::EditWindowClosed => StatusCode::FORBIDDEN,
+
+            Self::UserNotFound
+            | Self::ChatNotFound
+            | Self::MessageNotFound
+            | Self::SessionNotFound
+            | Self::AccessTokenNotFound
+            | Self::ParticipantNotFound
+            | Self::AttachmentNotFound => StatusCode::NOT_FOUND,
+
+            Self::EmailTaken | Self::HandleTaken | Self::AlreadyParticipant => StatusCode::CONFLICT,
+
+            Self::InvalidBody(_)
+            | Self::InvalidEmail
+            | Self::InvalidHandle
+            | Self::InvalidPasswordHash
+            | Self::InvalidRedirect
+            | Self::ChatNameTooLong
+            | Self::ConflictingCursors
+            | Self::UnsupportedProtocol(_)
+            | Self::InvalidVerificationLink
+            | Self::ExpiredVerificationLink
+            | Self::InvalidResetLink
+            | Self::ExpiredResetLink
+            | Self::AccessTokenName
+            | Self::AccessTokenScopes
+            | Self::EmptyMessage
+            | Self::MessageTooLong
+            | Self::UnreadableImage
+            | Self::TooManyAttachments
+            | Self::TooManyPendingAttachments => StatusCode::BAD_REQUEST,
+
+            Self::AttachmentTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedAttachment => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+
+            Self::Database(_) | Self::Mail(_) | Self::Storage(_) | Self::Render(_) => StatusCode::INTERNAL_SERVER_ERROR,
+
+            Self::TooManyRequests { .. } | Self::TooManyAttempts { .. } => StatusCode::TOO_MANY_REQUESTS,
+        }
+    }
+
+    /// The code clients can tell errors apart by, which never changes once it's given out.
+    pub fn code(&self) -> u32 {
+        match self {
+            Self::IncorrectPassword => 0,
+            Self::NotParticipant => 1,
+            Self::NotAuthor => 2,
+            Self::AccountLocked { .. } => 3,
+            Self::NotSignedIn => 4,
+            Self::EmailNotVerified => 5,
+            Self::NeedsAccessToken => 6,
+            Self::MissingScope(_) => 7,
+            Self::NotAllowed(_) => 8,
+            Self::OwnerCannotLeave => 9,
+
+            Self::EmailTaken => 100,
+            Self::HandleTaken => 101,
+            Self::UserNotFound => 102,
+
+            Self::InvalidBody(_) => 200,
+            Self::InvalidEmail => 201,
+            Self::InvalidHandle => 202,
+            Self::InvalidPasswordHash => 203,
+            Self::InvalidRedirect => 204,
+            Self::ChatNotFound => 205,
+            Self::ChatNameTooLong => 206,
+            Self::ConflictingCursors => 207,
+            Self::UnsupportedProtocol(_) => 208,
+            Self::MessageNotFound => 209,
+            Self::EditWindowClosed => 210,
+            Self::SessionNotFound => 211,
+            Self::InvalidVerificationLink => 212,
+            Self::ExpiredVerificationLink => 213,
+            Self::InvalidResetLink => 214,
+            Self::ExpiredResetLink => 215,
+            Self::AccessTokenNotFound => 216,
+            Self::AccessTokenName => 217,
+            Self::AccessTokenScopes => 218,
+            Self::AlreadyParticipant => 219,
+            Self::ParticipantNotFound => 220,
+            Self::EmptyMessage => 221,
+            Self::MessageTooLong => 222,
+            Self::AttachmentNotFound => 223,
+            Self::AttachmentTooLarge => 224,
+            Self::UnsupportedAttachment => 225,
+            Self::UnreadableImage => 226,
+            Self::TooManyAttachments => 227,
+            Self::TooManyPendingAttachments => 228,
+
+            Self::Database(_) => 300,
+            Self::Mail(_) => 301,
+            Self::Storage(_) => 302,
+            Self::Render(_) => 303,
+
+            Self::TooManyRequests { .. } => 400,
+            Self::TooManyAttempts { .. } => 401,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::IncorrectPassword => "Incorrect password.",
+            Self::NotParticipant => "This user is not a part of this chat.",
+            Self::NotAuthor => "This user is not the author of this message.",
+            Self::AccountLocked { .. } => "This account is locked after too many failed logins.",
+            Self::NotSignedIn => "This request needs a signed in user.",
+            Self::EmailNotVerified => "Verify your email address before creating chats.",
+            Self::NeedsAccessToken => "This request needs a valid access token.",
+            Self::MissingScope(Scope::Read) => "This access token needs the 'read' scope.",
+            Self::MissingScope(Scope::Write) => "This access token needs the 'write' scope.",
+            Self::MissingScope(Scope::Admin) => "This access token needs the 'admin' scope.",
+            Self::NotAllowed(_) => "This user's role in this chat doesn't allow this.",
+            Self::OwnerCannotLeave => "The owner can't leave a chat without giving it to someone else first.",
+
+            Self::EmailTaken => "A user with this email already exists.",
+            Self::HandleTaken => "A user with this handle already exists.",
+            Self::UserNotFound => "This user does not exist.",
+
+            Self::InvalidBody(_) => "Invalid schema for JSON body",
+            Self::InvalidEmail => "Invalid email address.",
+            Self::InvalidHandle => "Invalid handle.",
+            Self::InvalidPasswordHash => "Invalid password hash.",
+            Self::InvalidRedirect => "Invalid redirect URL.",
+            Self::ChatNotFound => "A chat with this ID does not exist.",
+            Self::ChatNameTooLong => "Chat name is too long.",
+            Self::ConflictingCursors => "Only one of before and after can be given.",
+            Self::UnsupportedProtocol(_) => "Unsupported protocol version.",
+            Self::MessageNotFound => "A message with this ID does not exist.",
+            Self::EditWindowClosed => "This message can no longer be edited.",
+            Self::SessionNotFound => "A session with this ID does not exist.",
+            Self::InvalidVerificationLink => "Invalid verification link.",
+            Self::ExpiredVerificationLink => "This verification link has expired.",
+            Self::InvalidResetLink => "Invalid password reset link.",
+            Self::ExpiredResetLink => "This password reset link has expired.",
+            Self::AccessTokenNotFound => "An access token with this ID does not exist.",
+            Self::AccessTokenName => "Access token names must be between 1 and 40 characters.",
+            Self::AccessTokenScopes => "Access tokens need at least one scope.",
+            Self::AlreadyParticipant => "This user is already a part of this chat.",
+            Self::ParticipantNotFound => "This user is not a participant in this chat.",
+            Self::EmptyMessage => "Messages can't be empty.",
+            Self::MessageTooLong => "This message is too long.",
+            Self::AttachmentNotFound => "An attachment with this ID does not exist.",
+            Self::AttachmentTooLarge => "This file is too large to attach.",
+            Self::UnsupportedAttachment => "This type of file can't be attached.",
+            Self::UnreadableImage => "This image couldn't be read.",
+            Self::TooManyAttachments => "This message has too many attachments.",
+            Self::TooManyPendingAttachments => "Too many attachments have been uploaded without being sent.",
+
+            Self::Database(_) => "Could not access database.",
+            Self::Mail(_) => "Could not send email.",
+            Self::Storage(_) => "Could not access object storage.",
+            Self::Render(_) => "Could not render page.",
+
+            Self::TooManyRequests { .. } => "Too many requests from this address.",
+            Self::TooManyAttempts { .. } => "Too many attempts for this email address.",
+        }
+    }
+
+    pub fn details(&self) -> Option<String> {
+        match self {
+            Self::InvalidEmail => Some("Email address did not match the (partial) RFC 5233 regex.".to_string()),
+            Self::InvalidHandle => Some(r"Handle did not match the handle regex (/^@?[\w!$-.\\\/]{3,15}$/)".to_string()),
+            Self::InvalidPasswordHash => Some("Hash did not match required length and encoding.".to_string()),
+            Self::MessageTooLong => Some(format!("Messages can be at most {} characters.", *MAX_MESSAGE_LENGTH)),
+            Self::AttachmentTooLarge => Some(format!("Attachments can be at most {} bytes.", *MAX_ATTACHMENT_BYTES)),
+            Self::UnsupportedAttachment => Some(format!("Attachments can be {}.", ATTACHMENT_TYPES.join(", "))),
+            Self::TooManyAttachments => Some(format!("Messages can have at most {MAX_MESSAGE_ATTACHMENTS} attachments.")),
+            Self::TooManyPendingAttachments => Some(format!("At most {} attachments can be waiting to be sent.", *MAX_PENDING_ATTACHMENTS)),
+            Self::NotAllowed(permission) => Some(format!("This needs the '{}' role or above.", Role::needed_for(*permission).as_str())),
+
+            Self::InvalidBody(details) | Self::UnsupportedProtocol(details) => Some(details.clone()),
+
+            _ => None,
+        }
+    }
+
+    /// What went wrong inside the server, which is logged rather than sent to the client.
+    fn internal_details(&self) -> Option<&str> {
+        match self {
+            Self::Database(details) | Self::Mail(details) | Self::Storage(details) | Self::Render(details) => Some(details),
+            _ => None,
+        }
+    }
+
+    /// Log internal errors before they're sent on without their details.
+    pub fn log(&self) {
+        if self.internal_details().is_some() {
+            eprintln!("{self}");
+        }
+    }
+
+    /// How long until this can be tried again, sent as `Retry-After`.
+    fn retry_after(&self) -> Option<TimeDelta> {
+        match self {
+            Self::AccountLocked { retry_after } | Self::TooManyRequests { retry_after } | Self::TooManyAttempts { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ApiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.details().as_deref().or(self.internal_details()) {
+            Some(details) => write!(f, "{} ({}): {details}", self.message(), self.code()),
+            None => write!(f, "{} ({})", self.message(), self.code()),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status(&self) -> StatusCode {
+        ApiError::status(self)
+    }
+
+    fn as_response(&self) -> Response {
+        self.log();
+
+        let mut error = json!({
+            "code": self.code(),
+            "message": self.message(),
+        });
+
+        if let Some(details) = self.details() {
+            error["details"] = json!(details);
+        }
+
+        let mut response = Response::builder()
+            .status(self.status())
+            .content_type("application/json")
+            .body(json!({ "success": false, "error": error }).to_string());
+
+        if let Some(retry_after) = self.retry_after() {
+            // Round up, so clients never retry too early
+            let seconds = (retry_after.num_milliseconds() + 999) / 1000;
+
+            response.headers_mut().insert("Retry-After", seconds.max(1).into());
+        }
+
+        if matches!(self, Self::NeedsAccessToken) {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+        }
+
+        response
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        self.as_response()
+    }
+}
+
+impl From<QueryError<'_>> for ApiError {
+    fn from(error: QueryError<'_>) -> Self {
+        Self::Database(error.to_string())
+    }
+}
+
+impl From<S3Error> for ApiError {
+    fn from(error: S3Error) -> Self {
+        Self::Storage(error.to_string())
+    }
+}
+
+/// Stored objects that can't be decompressed or decoded
+impl From<std::io::Error> for ApiError {
+    fn from(error: std::io::Error) -> Self {
+        Self::Storage(error.to_string())
+    }
+}
+
+impl From<MailError> for ApiError {
+    fn from(error: MailError) -> Self {
+        Self::Mail(error.0)
+    }
+}
+
+impl From<handlebars::RenderError> for ApiError {
+    fn from(error: handlebars::RenderError) -> Self {
+        Self::Render(error.to_string())
+    }
+}
+
+impl From<ReadBodyError> for ApiError {
+    fn from(error: ReadBodyError) -> Self {
+        match error {
+            // Only uploads are read with a limit
+            ReadBodyError::PayloadTooLarge => Self::AttachmentTooLarge,
+            error => Self::InvalidBody(error.to_string()),
+        }
+    }
+}
+
+impl From<MessageError<'_>> for ApiError {
+    fn from(error: MessageError<'_>) -> Self {
+        match error {
+            MessageError::NotFound => Self::MessageNotFound,
+            MessageError::NotAuthor => Self::NotAuthor,
+            MessageError::EditWindowClosed => Self::EditWindowClosed,
+            MessageError::Empty => Self::EmptyMessage,
+            MessageError::TooLong => Self::MessageTooLong,
+            MessageError::AttachmentNotFound => Self::AttachmentNotFound,
+            MessageError::TooManyAttachments => Self::TooManyAttachments,
+            MessageError::Database(e) => e.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_are_sent_in_the_envelope() {
+        let response = ApiError::Database("disk full".to_string()).into_response();
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(response.content_type(), Some("application/json"));
+
+        let response = ApiError::TooManyRequests { retry_after: TimeDelta::milliseconds(1500) }.into_response();
+
+        assert_eq!(response.headers().get("Retry-After").unwrap(), "2");
+    }
+
+    #[tokio::test]
+    async fn internal_errors_are_not_sent_to_the_client() {
+        let error = ApiError::from(S3Error::HttpFailWithBody(500, "bucket on fire".to_string()));
+
+        assert!(error.to_string().contains("bucket on fire"));
+
+        let body = error.into_response().into_body().into_string().await.unwrap();
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+
+        assert_eq!(json["success"], false);
+        assert_eq!(json["error"]["code"], 302);
+        assert_eq!(json["error"]["message"], "Could not access object storage.");
+        assert!(json["error"].get("details").is_none());
+        assert!(!body.contains("bucket on fire"));
+    }
+}
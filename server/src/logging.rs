@@ -1,11 +1,13 @@
 use chrono::Utc;
 use poem::Middleware;
+use poem::http::header::{AUTHORIZATION, COOKIE};
 use poem::{Endpoint, Request};
 use std::collections::HashMap;
 use std::path::PathBuf;
 use tokio::fs;
 
 #[derive(Debug, Clone)]
+#[allow(dead_code)]
 pub enum LoggingPersistence {
     MemoryOnly,
     LogFileOnly(PathBuf),
@@ -13,6 +15,7 @@ pub enum LoggingPersistence {
 }
 
 #[derive(Eq, PartialEq, Ord, PartialOrd, Hash, Clone, Debug, Copy)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 pub enum LoggingFormat {
     // JSON,
     // YAML,
@@ -34,9 +37,29 @@ pub struct LoggingMiddleware {
 pub struct LoggedEndpoint<E: Endpoint> {
     inner: E,
     persistence: LoggingPersistence,
+    #[allow(dead_code)]
     format: LoggingFormat,
 }
 
+/// Headers that carry credentials, which are logged without their values.
+const REDACTED_HEADERS: [poem::http::HeaderName; 2] = [AUTHORIZATION, COOKIE];
+
+/// Describe a request on one line, leaving out any credentials it was sent with.
+fn log_line(req: &Request) -> String {
+    format!(
+        "[{}] {} {} PATH {} PARAMS {} FROM {} HEADERS {}", Utc::now().to_rfc3339(),
+        req.scheme().as_str().to_ascii_uppercase(),
+        req.method().as_str().to_ascii_uppercase(),
+        req.uri().path(),
+        req.params::<HashMap<String, String>>().unwrap().iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join(","),
+        req.remote_addr(),
+        req.headers().iter().map(|(k, v)| match REDACTED_HEADERS.contains(k) {
+            true => format!("{k}=<redacted>"),
+            false => format!("{k}={v:?}"),
+        }).collect::<Vec<_>>().join(","),
+    )
+}
+
 impl<E: Endpoint> Endpoint for LoggedEndpoint<E> {
     type Output = E::Output;
 
@@ -44,21 +67,13 @@ impl<E: Endpoint> Endpoint for LoggedEndpoint<E> {
         match self.persistence.clone() {
             LoggingPersistence::MemoryOnly => todo!("In-memory logging"),
             LoggingPersistence::LogFileOnly(file) => {
-                let log = format!(
-                    "[{}] {} {} PATH {} PARAMS {} FROM {} HEADERS {}", Utc::now().to_rfc3339(),
-                    req.scheme().as_str().to_ascii_uppercase(),
-                    req.method().as_str().to_ascii_uppercase(),
-                    req.uri().path(),
-                    req.params::<HashMap<String, String>>().unwrap().iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<_>>().join(","),
-                    req.remote_addr(),
-                    req.headers().iter().map(|(k, v)| format!("{k}={v:?}")).collect::<Vec<_>>().join(","),
-                );
+                let log = log_line(&req);
                 
                 tokio::task::spawn(async move {
-                    if &*String::from_utf8_lossy(fs::read(file.clone()).await.unwrap().as_slice()) == "" {
+                    if String::from_utf8_lossy(fs::read(file.clone()).await.unwrap().as_slice()).is_empty() {
                         fs::write(file, &[log.as_bytes().to_owned()].concat()).await.unwrap();
                     } else {
-                        fs::write(file.clone(), &[fs::read(file).await.unwrap(), vec!['\n' as u8], log.as_bytes().to_owned()].concat()).await.unwrap();
+                        fs::write(file.clone(), &[fs::read(file).await.unwrap(), vec![b'\n'], log.as_bytes().to_owned()].concat()).await.unwrap();
                     }
                 });
             }
@@ -81,3 +96,23 @@ impl<E: Endpoint> Middleware<E> for LoggingMiddleware {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_are_not_logged() {
+        let req = Request::builder()
+            .uri_str("/api/v1/chats")
+            .header("Authorization", "Bearer kq_secret")
+            .header("Cookie", "SSID=session")
+            .header("User-Agent", "tests")
+            .finish();
+
+        let line = log_line(&req);
+
+        assert!(!line.contains("kq_secret") && !line.contains("session"), "{line}");
+        assert!(line.contains("authorization=<redacted>"));
+        assert!(line.contains("user-agent=\"tests\""));
+    }
+}
@@ -1,46 +1,84 @@
+mod access_token;
+mod attachment;
+mod api;
+mod auth;
 pub(crate) mod user;
 pub(crate) mod data;
+mod error;
 mod logging;
 mod chat;
-
-use crate::chat::{Chat, ChatQuery, CreateChatBody, SocketChatAuthor, SocketChatBody};
-use crate::data::{KolloquyDB, KolloquyR2, QueryError, USER_AVATAR_BUCKET};
+mod mail;
+mod markup;
+mod openapi;
+mod password;
+mod protocol;
+mod ratelimit;
+mod role;
+mod room;
+mod session;
+mod token;
+#[cfg(test)]
+mod test_support;
+
+use crate::access_token::AccessTokenQuery;
+use crate::api::{AttachmentResponse, ChatResponse, CreateTokenBody, ParticipantsResponse, TokenResponse, TokensResponse};
+use crate::attachment::{Attachment, UploadParams};
+use crate::auth::{resume_session, session_cookie, start_session, AuthenticatedUser};
+use crate::chat::{enrolled_chats, migrate_legacy_chats, require_role_in, AddParticipantBody, Chat, ChatMemberQuery, CreateChatBody, EditMessageBody, MessageQuery, MessagePageParams, RenameChatBody, SetRoleBody, SocketChatAuthor, MESSAGE_EDIT_WINDOW};
+use crate::error::ApiError;
+use crate::data::{decompress_string, KolloquyDB, QueryError, KOLLOQUY_CHATS_BUCKET, KOLLOQUY_DB_BACKEND, USER_AVATAR_BUCKET};
+use crate::mail::{Mail, KOLLOQUY_MAILER, KOLLOQUY_PUBLIC_URL};
+use crate::openapi::{ErrorResponse, SuccessResponse};
 use crate::logging::{LoggingFormat, LoggingMiddleware, LoggingPersistence};
-use crate::user::{AuthenticateBody, RegisterBody, User, UserQuery};
+use crate::password::{hash_password, verify_password, PasswordCheck};
+use crate::role::{Permission, Role};
+use crate::protocol::{parse_client_frame, ClientFrame, ServerFrame};
+use crate::ratelimit::{RateLimitMiddleware, LOGIN_EMAIL_LIMITER, LOGIN_IP_LIMITER};
+use crate::room::{ChatRooms, SocketCommand, SocketRoles, UserSockets};
+use crate::session::{session_store_from_env, Session, SessionStore};
+use crate::token::{check_token, issue_token, token_subject, TokenError, TokenPurpose};
+use crate::user::{AuthenticateBody, ForgotPasswordBody, RegisterBody, ResetPasswordBody, User, UserQuery, VerifyParams, EMAIL_VERIFICATION_TTL, LOCKOUT_POLICY, PASSWORD_RESET_TTL};
 use base64::alphabet::Alphabet;
 use base64::engine::GeneralPurpose;
 use base64::Engine;
-use brotli::BrotliDecompress;
-use chrono::{DateTime, TimeDelta, Utc};
+use chrono::{DateTime, Utc};
 use dotenv::dotenv;
 use futures::future::join_all;
-use futures::{SinkExt, StreamExt, TryFutureExt};
+use futures::StreamExt;
 use handlebars::{Context, Handlebars};
 use poem::http::StatusCode;
 use poem::middleware::{AddData, CookieJarManager, Cors, CorsEndpoint};
-use poem::web::cookie::{CookieJar, SameSite};
+use poem::web::cookie::CookieJar;
 use poem::web::websocket::{Message, WebSocket};
-use poem::web::{cookie, Data, Redirect};
-use poem::{get, handler, listener::TcpListener, web::Path, Body, EndpointExt, FromRequest, IntoResponse, Route, Server};
-use poem::Response;
+use poem::web::{Data, Query, Redirect};
+use poem::{delete, get, handler, patch, post, put, listener::TcpListener, web::Path, Body, EndpointExt, IntoResponse, Route, Server};
+use poem::{Request, Response};
 use rand::{Rng, RngCore, SeedableRng};
 use regex::Regex;
-use serde::Serialize;
 use serde_json::json;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::env;
-use std::io::Cursor;
-use std::ops::{Deref, DerefMut};
+use std::ops::Deref;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, LazyLock};
-use std::time::Duration;
-use tokio::sync::{broadcast, RwLock};
-use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc;
 
-#[derive(Default, Clone)]
+#[derive(Clone)]
 pub struct ServerState {
-    open_sessions: Arc<RwLock<HashMap<String, (User, DateTime<Utc>)>>>,
+    sessions: Arc<dyn SessionStore>,
+    rooms: ChatRooms,
+    sockets: UserSockets,
+}
+
+impl Default for ServerState {
+    fn default() -> Self {
+        Self {
+            sessions: session_store_from_env(),
+            rooms: ChatRooms::default(),
+            sockets: UserSockets::default(),
+        }
+    }
 }
 
 macro_rules! define_static_files {
@@ -87,7 +125,7 @@ pub fn create_avatar() -> svg::Document {
 
     let circ_radius = random.random_range(25..40);
 
-    let svg = svg::Document::new()
+    svg::Document::new()
         .set("width", "100px")
         .set("height", "100px")
         .set("style", format!("background: {gradient}"))
@@ -105,14 +143,13 @@ pub fn create_avatar() -> svg::Document {
                 .set("cy", "50%")
                 .set("r", format!("{}%", circ_radius))
                 .set("fill", "#e9eaff")
-        );
-    
-    svg
+        )
 }
 
 define_static_files! {
     signup_page ("text/html") => "../../client/signup.html",
     login_page ("text/html") => "../../client/login.html",
+    reset_page ("text/html") => "../../client/reset.html",
     index_css ("text/css") => "../../client/index.css",
     account_css ("text/css") => "../../client/account.css",
     login_css ("text/css") => "../../client/login.css",
@@ -133,12 +170,10 @@ async fn rng() -> String {
     reqwest::get(url).await.unwrap().text().await.unwrap()[9..=52].to_owned()
 }
 
-const USE_NET_RAND: bool = false;
 const ACCOUNT_TEMPLATE: &str = include_str!("../../client/account.handlebars");
 const CHATS_TEMPLATE: &str = include_str!("../../client/chats.handlebars");
 const CHAT_TEMPLATE: &str = include_str!("../../client/chat.handlebars");
 
-static UID_REGEX: LazyLock<Regex, fn() -> Regex> = LazyLock::new(|| Regex::from_str(r"[a-z]{2}+\d{2}+[a-z]{3}+").unwrap());
 static UID_FILTERING_REGEX: LazyLock<Regex, fn() -> Regex> = LazyLock::new(|| Regex::from_str(r"([a-z])[^a-z]*([a-z])[^a-z]*(\d)[^\d]*(\d)[^\d]*([a-z])[^a-z]*([a-z])[^a-z]*([a-z])[^a-z]*").unwrap());
 
 /// A monolithic regex for matching RFC 5233 email addresses.
@@ -176,9 +211,7 @@ pub async fn random_user_id() -> String {
 
     iter.next();
 
-    let needle = iter.map(|m| m.unwrap().as_str()).collect::<String>();
-
-    needle
+    iter.map(|m| m.unwrap().as_str()).collect::<String>()
 }
 
 async fn random_session_id() -> String {
@@ -201,7 +234,7 @@ async fn random_session_id() -> String {
 }
 
 #[handler]
-async fn user_page(Path(mut handle): Path<String>) -> Response {
+async fn user_page(Path(mut handle): Path<String>) -> Result<Response, ApiError> {
     if handle.starts_with("@") {
         handle.remove(0);
     }
@@ -210,386 +243,502 @@ async fn user_page(Path(mut handle): Path<String>) -> Response {
     let query = UserQuery::GetByHandle(handle.clone());
 
     let user = match db.execute(&query).await {
-        Ok(user) => user.unwrap(),
-        Err(QueryError::NotFound) => {
-            let error_json = json!({
-                "success": false,
-                "error": {
-                    "code": 100,
-                    "message": "A user with this handle does not exist."
-                }
-            });
-
-            return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
-        },
-        Err(e) => {
-            let error_json = json!({
-                "success": false,
-                "error": {
-                    "code": 300,
-                    "message": "Could not access database.",
-                    "details": format!("{:?}", e)
-                }
-            });
-
-            return (StatusCode::INTERNAL_SERVER_ERROR, serde_json::to_string(&error_json).unwrap()).into_response();
-        }
+        Ok(Some(user)) => user,
+        Ok(None) | Err(QueryError::NotFound) => return Err(ApiError::UserNotFound),
+        Err(e) => return Err(e.into()),
     };
 
-    let r2 = KolloquyR2::new(*USER_AVATAR_BUCKET.clone());
-    let query = UserQuery::GetAvatar(user.clone());
-
-    let mut compressed_avatar = Cursor::new(r2.execute(&query).await.unwrap().to_vec());
-    let mut avatar = Cursor::new(Vec::new());
-
-    BrotliDecompress(&mut compressed_avatar, &mut avatar).unwrap();
-
-    let avatar_string = String::from_utf8(avatar.into_inner()).unwrap();
-
-    let engine = Handlebars::new();
-    let context = Context::from(json!({
+    render_page(ACCOUNT_TEMPLATE, json!({
         "user": {
-            "avatar": avatar_string,
+            "avatar": user_avatar(&user).await?,
             "handle": user.handle,
             "joined": user.joined.to_rfc3339(),
         },
         "not_self": true
-    }));
-
-    let rendered = engine.render_template_with_context(ACCOUNT_TEMPLATE, &context).unwrap();
-
-    Response::builder()
-        .body(rendered)
-        .set_content_type("text/html")
-        .with_status(StatusCode::OK)
-        .into_response()
+    }))
 }
 
 #[handler]
-async fn user_chats(jar: &CookieJar, state: Data<&Arc<ServerState>>) -> Response {
-    let Some(mut sid) = jar.get("SSID").map(|cookie| (&cookie.to_string()[5..]).to_string()) else {
-        return Redirect::temporary("/login").into_response();
-    };
-    
-    sid = sid.replace("%22", "");
-    sid = sid.replace("%2F", "/");
-
-    let state_read = state.open_sessions.read().await;
-
-    let Some((user, session_started)) = state_read.get(&sid) else {
-        return Redirect::temporary("/login").into_response();
-    };
-
-    if Utc::now().naive_local() - session_started.naive_local() > TimeDelta::minutes(30) {
-        state.open_sessions.write().await.remove(&sid);
-
-        return Redirect::temporary("/login").into_response();
+async fn user_chats(AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    let mut chats = Vec::new();
+
+    for chat in join_all(enrolled_chats(&user.user_id).await?.into_iter().map(Chat::from_remote)).await {
+        match chat {
+            Ok(chat) => chats.push(chat),
+            // Skip chats that have been deleted since the user joined them
+            Err(ApiError::ChatNotFound) => (),
+            Err(e) => return Err(e),
+        }
     }
 
-    let Some(chats) = join_all(user.enrolled_chats.iter().map(async |id| Chat::from_remote(id.clone()).await)).await.into_iter().collect::<Option<Vec<_>>>() else {
-        let context = Context::from(json!({
-            "chats": [],
-            "debug_info": {
-                "chat_count": 0
-            }
-        }));
-
-        let engine = Handlebars::new();
-
-        let rendered = engine.render_template_with_context(CHATS_TEMPLATE, &context).unwrap();
-
-        return Response::builder()
-            .body(rendered)
-            .set_content_type("text/html")
-            .with_status(StatusCode::OK)
-            .into_response();
-    };
-
-    let Some(chat_icons) = join_all(chats.iter().map(async |chat| {
-        eprintln!("REMOTE URL {}", chat.icon_url);
-
-        let mut compressed = Cursor::new(USER_AVATAR_BUCKET.deref().get_object(&chat.icon_url).await.ok()?.into_bytes());
-        let mut avatar = Cursor::new(Vec::new());
-
-        BrotliDecompress(&mut compressed, &mut avatar).unwrap();
-
-        Some(String::from_utf8(avatar.into_inner()).unwrap())
-    })).await.into_iter().collect::<Option<Vec<_>>>() else {
-        let context = Context::from(json!({
-            "chats": [],
-        }));
+    let chat_icons = join_all(chats.iter().map(async |chat| {
+        let compressed = USER_AVATAR_BUCKET.get_object(&chat.icon_url).await?;
 
-        let engine = Handlebars::new();
+        Ok::<_, ApiError>(decompress_string(&compressed)?)
+    })).await.into_iter().collect::<Result<Vec<_>, _>>()?;
 
-        let rendered = engine.render_template_with_context(CHATS_TEMPLATE, &context).unwrap();
+    let latest_messages = join_all(chats.iter().map(|chat| chat.messages(None, 1))).await;
 
-        return Response::builder()
-            .body(rendered)
-            .set_content_type("text/html")
-            .with_status(StatusCode::OK)
-            .into_response();
-    };
-
-    let json_chats = chats.iter().enumerate().map(|(i, chat)| json!({
-        "name": chat.name,
-        "messages": chat.messages.iter().map(|m| m.content.get(0).unwrap().clone()).collect::<Vec<String>>(),
-        "icon": chat_icons.get(i).unwrap().clone(),
-        "id": chat.id,
-    })).collect::<Vec<_>>();
+    let json_chats = chats.iter().zip(chat_icons).zip(latest_messages)
+        .map(|((chat, icon), latest)| chat_summary(&chat.name, &chat.id, icon, &latest.unwrap_or_default()))
+        .collect::<Vec<_>>();
 
-    let context = Context::from(json!({
+    render_page(CHATS_TEMPLATE, json!({
         "chats": json_chats,
-    }));
-
-    let engine = Handlebars::new();
-
-    let rendered = engine.render_template_with_context(CHATS_TEMPLATE, &context).unwrap();
-
-    Response::builder()
-        .body(rendered)
-        .set_content_type("text/html")
-        .with_status(StatusCode::OK)
-        .into_response()
+    }))
 }
 
 #[handler]
 async fn chat_socket(
     ws: WebSocket,
-    sender: Data<&Sender<SocketChatBody>>,
+    AuthenticatedUser { user, session }: AuthenticatedUser,
+    state: Data<&Arc<ServerState>>,
 ) -> Response {
-    let sender = sender.clone();
-    let mut receiver = sender.subscribe();
+    // Everything sent through this socket is from the session's user, whatever the client claims
+    let author = SocketChatAuthor::for_user(&user).await;
+
+    let enrolled = match KolloquyDB::new().execute(&ChatMemberQuery::ChatsOf(user.user_id.clone())).await {
+        Ok(enrolled) => enrolled,
+        Err(e) => return ApiError::from(e).into_response(),
+    };
+
+    // Kept up to date through `sockets` as the user's chats and roles change, so frames are checked without loading the chat
+    let roles = SocketRoles::new(enrolled.iter().map(|member| (member.chat_id.clone(), member.role)));
+
+    let rooms = state.rooms.clone();
+    let sockets = state.sockets.clone();
+    let sessions = state.sessions.clone();
 
     ws.on_upgrade(move |socket| async move {
-        let (mut sink, mut stream) = socket.split();
+        let (sink, mut stream) = socket.split();
+        let (commands, command_receiver) = mpsc::unbounded_channel::<SocketCommand>();
+
+        // Chats the user joins or leaves while the socket is open are sent through here too, and so is signing out
+        sockets.register(&user.user_id, &session.id, &commands, roles.clone()).await;
+
+        // Only listen to the chats this user is a part of
+        for chat in enrolled.iter().map(|member| &member.chat_id) {
+            if commands.send(SocketCommand::Join(chat.clone(), rooms.join(chat).await)).is_err() {
+                rooms.prune(chat).await;
+            }
+        }
+
+        let reader_rooms = rooms.clone();
 
         tokio::spawn(async move {
+            let rooms = reader_rooms;
+
             while let Some(Ok(msg)) = stream.next().await {
-                if let Message::Text(ref json) = msg {
-                    let body: SocketChatBody = serde_json::from_str(json).unwrap();
-
-                    let filled_author = SocketChatAuthor {
-                        id: body.author.id.clone(),
-                        is_self: false,
-                        handle: body.author.handle,
-                        avatar: {
-                            let db = KolloquyDB::new();
-                            let query = UserQuery::GetByID(body.author.id.clone());
-                            let author = db.execute(&query).await.unwrap().unwrap();
-
-                            let r2 = KolloquyR2::new(*(*USER_AVATAR_BUCKET).clone());
-                            let query = UserQuery::GetAvatar(author);
-
-                            let mut compressed_avatar = Cursor::new(r2.execute(&query).await.unwrap().to_vec());
-                            let mut avatar = Cursor::new(Vec::new());
-
-                            BrotliDecompress(&mut compressed_avatar, &mut avatar).unwrap();
-
-                            String::from_utf8(avatar.into_inner()).unwrap()
-                        },
-                    };
-
-                    let response = match &*body.action {
-                        "PUT" => SocketChatBody {
-                            content: Some(body.content.clone().unwrap()),
-                            action: "PUT".into(),
-                            author: filled_author,
-                            chat: Some(body.chat.clone().unwrap()),
-                        },
-                        "RENEW" => SocketChatBody {
-                            content: None,
-                            action: "RENEW".into(),
-                            author: filled_author,
-                            chat: None,
-                        },
-                        _ => unreachable!(),
-                    };
-
-                    if sender.send(response).is_err() {
-                        break;
-                    }
+                // The writer stops when the socket is closed, and nothing sent after that is acted on
+                if commands.is_closed() {
+                    break;
+                }
 
-                    if &*body.action == "PUT" {
-                        tokio::spawn(async move {
-                            let mut chat = Chat::from_remote(body.chat.unwrap()).await.unwrap();
+                // The session was only checked when the socket opened, so make sure it hasn't expired since
+                if resume_session(sessions.as_ref(), &session.id).await.is_none() {
+                    commands.send(SocketCommand::Close).ok();
 
-                            chat.messages.push(chat::Message {
-                                content: vec![body.content.unwrap()],
-                                author: body.author.id,
-                                sent: Utc::now(),
-                                id: chat.messages.len() as u64,
-                            });
+                    break;
+                }
 
-                            let mut query = ChatQuery::PutChat;
+                let Message::Text(ref json) = msg else {
+                    continue;
+                };
+
+                let frame = parse_client_frame(json);
+
+                // Membership and roles can change while the socket is open, so check them for every message
+                let role = frame.as_ref().ok()
+                    .and_then(|frame| Some((frame.chat()?, frame.permission())))
+                    .map(|(chat, permission)| roles.require(chat, permission));
+
+                let reply = match (frame, role) {
+                    (Err(error), _) => *error,
+
+                    (Ok(frame), Some(Err(error))) => ServerFrame::error(frame.client_id(), error),
+
+                    (Ok(ClientFrame::Renew), _) => ServerFrame::Renewed,
+
+                    (Ok(ClientFrame::Put { chat, content, attachments, client_id }), _) => {
+                        let db = KolloquyDB::new();
+
+                        // Store the message first, so everyone sees the id it was given and the content as it was sanitised
+                        match chat::Message::send(&db, &chat, &author.id, &content, &attachments).await {
+                            Ok(message) => {
+                                rooms.send(&chat, ServerFrame::Message {
+                                    chat: chat.clone(),
+                                    id: message.id,
+                                    sent: message.sent,
+                                    author: author.clone(),
+                                    content: message.current().to_string(),
+                                    attachments: message.attachments.clone(),
+                                }).await;
+
+                                ServerFrame::Ack {
+                                    client_id,
+                                    chat,
+                                    id: message.id,
+                                    sent: message.sent,
+                                    source: Some(message.current_source().to_string()),
+                                }
+                            }
+                            Err(e) => ServerFrame::error(client_id, e.into()),
+                        }
+                    }
 
-                            chat.execute(&mut query).await;
-                        });
+                    (Ok(ClientFrame::Edit { chat, id, content, client_id }), _) => {
+                        let db = KolloquyDB::new();
+
+                        match chat::Message::edit(&db, &chat, id, &author.id, content, *MESSAGE_EDIT_WINDOW).await {
+                            Ok(message) => {
+                                rooms.send(&chat, ServerFrame::Edited {
+                                    chat: chat.clone(),
+                                    id,
+                                    content: message.current().to_string(),
+                                    edited: message.edited[0],
+                                }).await;
+
+                                ServerFrame::Ack {
+                                    client_id,
+                                    chat,
+                                    id,
+                                    sent: message.sent,
+                                    source: Some(message.current_source().to_string()),
+                                }
+                            }
+                            Err(e) => ServerFrame::error(client_id, e.into()),
+                        }
                     }
-                }
-            }
-        });
 
-        tokio::spawn(async move {
-            while let Ok(msg) = receiver.recv().await {
-                println!("{}", serde_json::to_string(&msg).unwrap());
+                    (Ok(ClientFrame::Delete { chat, id, client_id }), role) => {
+                        let db = KolloquyDB::new();
+                        let role = role.and_then(Result::ok).unwrap_or(Role::ReadOnly);
+
+                        // Removing someone else's message is redacting it, which being a part of the chat doesn't allow
+                        let allowed = match db.execute(&MessageQuery::Get { chat: chat.clone(), id }).await {
+                            Ok(found) if found.first().is_some_and(|message| message.author != author.id) => role.require(Permission::Redact),
+                            Ok(_) => Ok(()),
+                            Err(e) => Err(e.into()),
+                        };
+
+                        let removed = match allowed {
+                            Ok(()) => chat::Message::delete(&db, &chat, id, &author.id, role.allows(Permission::Redact)).await.map_err(ApiError::from),
+                            Err(e) => Err(e),
+                        };
+
+                        match removed {
+                            Ok(message) => {
+                                rooms.send(&chat, ServerFrame::Removed {
+                                    chat: chat.clone(),
+                                    id,
+                                    redacted: message.is_redacted(),
+                                }).await;
+
+                                ServerFrame::Ack {
+                                    client_id,
+                                    chat,
+                                    id,
+                                    sent: message.sent,
+                                    source: None,
+                                }
+                            }
+                            Err(e) => ServerFrame::error(client_id, e),
+                        }
+                    }
+                };
 
-                if sink.send(Message::Text(serde_json::to_string(&msg).unwrap())).await.is_err() {
+                if commands.send(SocketCommand::Reply(reply)).is_err() {
                     break;
                 }
             }
         });
+
+        // The reader holds the only sender of `commands`, so the writer stops once the client goes away
+        tokio::spawn(room::forward(sink, command_receiver, rooms));
     }).into_response()
 }
 
-#[handler]
-async fn user_chat(Path(id): Path<String>, jar: &CookieJar, state: Data<&Arc<ServerState>>) -> Response {
-    let Some(mut sid) = jar.get("SSID").map(|cookie| (&cookie.to_string()[5..]).to_string()) else {
-        return Redirect::temporary("/login").into_response();
-    };
+/// Render messages as they're shown in the chat page, looking up each author once.
+async fn render_messages(messages: &[chat::Message], user: &User) -> Result<Vec<serde_json::Value>, ApiError> {
+    let author_ids = messages.iter().filter(|m| !m.is_system()).map(|m| m.author.clone()).collect::<HashSet<_>>();
 
-    sid = sid.replace("%22", "");
-    sid = sid.replace("%2F", "/");
+    let authors = join_all(author_ids.into_iter().map(async |id| {
+        let db = KolloquyDB::new();
+        let query = UserQuery::GetByID(id.clone());
 
-    let state_read = state.open_sessions.read().await;
+        // Messages from deleted users are shown without an author
+        let author = match db.execute(&query).await {
+            Ok(Some(author)) => author,
+            Ok(None) | Err(QueryError::NotFound) => return Ok(None),
+            Err(e) => return Err(ApiError::from(e)),
+        };
 
-    let Some((user, session_started)) = state_read.get(&sid) else {
-        return Redirect::temporary("/login").into_response();
-    };
+        Ok(Some((id, json!({
+            "handle": author.handle,
+            "id": author.user_id,
+            "avatar": user_avatar(&author).await?,
+        }))))
+    })).await.into_iter().collect::<Result<Vec<_>, _>>()?.into_iter().flatten().collect::<HashMap<_, _>>();
 
-    if Utc::now().naive_local() - session_started.naive_local() > TimeDelta::minutes(30) {
-        state.open_sessions.write().await.remove(&sid);
+    Ok(messages.iter().map(|m| message_json(m, &user.user_id, &authors)).collect())
+}
 
-        return Redirect::temporary("/login").into_response();
-    }
+/// A message as the chat page shows it.
+///
+/// `content` was sanitised before it was stored. Only the user's own messages have the `source` they were written in,
+/// which the template puts in an (escaped) attribute so it can be edited.
+fn message_json(m: &chat::Message, user_id: &str, authors: &HashMap<String, serde_json::Value>) -> serde_json::Value {
+    json!({
+        "id": m.id,
+        "sent": m.sent.to_rfc3339(),
+        "is_sender": m.author == user_id,
+        "author": authors.get(&m.author),
+        "content": m.current(),
+        "source": (m.author == user_id).then(|| m.current_source()),
+        "edited": m.edited.first().map(|edited| edited.to_rfc3339()),
+        "deleted": m.deleted.is_some(),
+        "redacted": m.is_redacted(),
+        "system": m.is_system(),
+        "attachments": m.attachments,
+    })
+}
 
-    if !user.enrolled_chats.contains(&id) {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 1,
-                "message": "This user is not a part of this chat.",
-            }
-        });
+/// A user's avatar, as SVG.
+async fn user_avatar(user: &User) -> Result<String, ApiError> {
+    let compressed = USER_AVATAR_BUCKET.execute(&UserQuery::GetAvatar(user.clone())).await?;
+
+    Ok(decompress_string(&compressed.into_bytes())?)
+}
+
+/// Render a page from one of the Handlebars templates.
+fn render_page(template: &str, context: serde_json::Value) -> Result<Response, ApiError> {
+    let rendered = Handlebars::new().render_template_with_context(template, &Context::from(context))?;
+
+    Ok(Response::builder()
+        .body(rendered)
+        .set_content_type("text/html")
+        .with_status(StatusCode::OK)
+        .into_response())
+}
+
+/// A user's current role in a chat, if it has `permission`, according to the chat rather than their session.
+async fn require_role(user_id: &str, chat: &str, permission: Permission) -> Result<Role, ApiError> {
+    require_role_in(&KolloquyDB::new(), &KOLLOQUY_CHATS_BUCKET, chat, user_id, permission).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/chat/{id}/messages",
+    tag = "messages",
+    params(("id" = String, Path), MessagePageParams),
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "A page of rendered `messages`, oldest first, and `has_more`"),
+        (status = 400, body = ErrorResponse, description = "Both before and after were given (207)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+    ),
+)]
+#[handler]
+async fn chat_messages(Path(id): Path<String>, Query(params): Query<MessagePageParams>, AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    let chat = Chat::from_remote(id.clone()).await?;
+    chat.require(&user.user_id, Permission::Read)?;
 
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
+    if params.before.is_some() && params.after.is_some() {
+        return Err(ApiError::ConflictingCursors);
     }
 
-    let Some(chat) = Chat::from_remote(id.clone()).await else {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 205,
-                "message": "A chat with this ID does not exist.",
-            }
-        });
+    let (messages, has_more) = chat.message_page(&params).await?;
 
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
-    };
+    let success_json = json!({
+        "success": true,
+        "messages": render_messages(&messages, &user).await?,
+        "has_more": has_more,
+    });
 
-    let messages_json = join_all(chat.messages.iter().map(async |m| {
-        let db = KolloquyDB::new();
-        let query = UserQuery::GetByID(m.author.clone());
-        let author = db.execute(&query).await.unwrap().unwrap();
+    Ok(Response::builder()
+        .body(serde_json::to_string(&success_json).unwrap())
+        .set_content_type("application/json")
+        .with_status(StatusCode::OK)
+        .into_response())
+}
 
-        let r2 = KolloquyR2::new(*USER_AVATAR_BUCKET.clone());
-        let query = UserQuery::GetAvatar(author.clone());
+#[utoipa::path(
+    patch,
+    path = "/chat/{id}/messages/{message}",
+    tag = "messages",
+    params(("id" = String, Path), ("message" = u64, Path)),
+    request_body = EditMessageBody,
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The message's `id`, sanitised `content`, the `source` it was written in and `edited` time"),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200), empty message (221) or message too long (222)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1), not the author (2), read only (8) or too late to edit (210)"),
+        (status = 404, body = ErrorResponse, description = "No message with this id (209)"),
+    ),
+)]
+#[handler]
+async fn edit_message(Path((id, message_id)): Path<(String, u64)>, body: Body, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    let body_str = body.into_string().await?;
+    let body = serde_json::from_str::<EditMessageBody>(&body_str).map_err(|_| openapi::invalid_body::<EditMessageBody>(&body_str))?;
 
-        let mut compressed_avatar = Cursor::new(r2.execute(&query).await.unwrap().to_vec());
+    require_role(&user.user_id, &id, Permission::Send).await?;
 
-        let mut avatar = Vec::new();
+    let db = KolloquyDB::new();
+    let message = chat::Message::edit(&db, &id, message_id, &user.user_id, body.content, *MESSAGE_EDIT_WINDOW).await?;
 
-        BrotliDecompress(&mut compressed_avatar, &mut avatar).unwrap();
+    state.rooms.send(&id, ServerFrame::Edited {
+        chat: id.clone(),
+        id: message_id,
+        content: message.current().to_string(),
+        edited: message.edited[0],
+    }).await;
 
-        json!({
-            "is_sender": author.user_id == user.user_id,
-            "author": {
-                "handle": author.handle,
-                "id": author.user_id,
-                "avatar": String::from_utf8(avatar).unwrap()
-            },
-            "content": m.content[0].clone()
-        })
-    })).await;
+    let success_json = json!({
+        "success": true,
+        "id": message.id,
+        "content": message.current(),
+        "source": message.current_source(),
+        "edited": message.edited[0].to_rfc3339(),
+    });
 
-    let context = Context::from(json!({
-        "messages": messages_json,
-        "id": chat.id,
-        "self": {
-            "id": user.user_id,
-            "handle": user.handle
-        },
-    }));
+    Ok(Response::builder()
+        .body(serde_json::to_string(&success_json).unwrap())
+        .set_content_type("application/json")
+        .with_status(StatusCode::OK)
+        .into_response())
+}
 
-    let engine = Handlebars::new();
+#[utoipa::path(
+    delete,
+    path = "/chat/{id}/messages/{message}",
+    tag = "messages",
+    params(("id" = String, Path), ("message" = u64, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The message's `id` and whether it was `redacted`"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1), or not the author and unable to redact (2)"),
+        (status = 404, body = ErrorResponse, description = "No message with this id (209)"),
+    ),
+)]
+#[handler]
+async fn delete_message(Path((id, message_id)): Path<(String, u64)>, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    // Read only participants can still remove their own messages
+    let role = require_role(&user.user_id, &id, Permission::Read).await?;
 
-    let rendered = engine.render_template_with_context(CHAT_TEMPLATE, &context).unwrap();
+    let db = KolloquyDB::new();
+    let message = chat::Message::delete(&db, &id, message_id, &user.user_id, role.allows(Permission::Redact)).await?;
 
-    Response::builder()
-        .body(rendered)
-        .set_content_type("text/html")
+    state.rooms.send(&id, ServerFrame::Removed {
+        chat: id.clone(),
+        id: message_id,
+        redacted: message.is_redacted(),
+    }).await;
+
+    let success_json = json!({
+        "success": true,
+        "id": message.id,
+        "redacted": message.is_redacted(),
+    });
+
+    Ok(Response::builder()
+        .body(serde_json::to_string(&success_json).unwrap())
+        .set_content_type("application/json")
         .with_status(StatusCode::OK)
-        .into_response()
+        .into_response())
 }
 
+#[utoipa::path(
+    get,
+    path = "/chat/{id}/messages/{message}/history",
+    tag = "messages",
+    params(("id" = String, Path), ("message" = u64, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, description = "The message's `id`, `author` and `revisions`, oldest first"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+        (status = 404, body = ErrorResponse, description = "No message with this id (209)"),
+    ),
+)]
 #[handler]
-async fn create_chat(body: Body, jar: &CookieJar, state: Data<&Arc<ServerState>>) -> Response {
-    let body_str = body.into_string().await.unwrap();
-
-    let Ok(body) = serde_json::from_str::<CreateChatBody>(&*body_str) else {
-        let binding = format!(r#"
-Expected JSON to match schema:
-{{
-    "participants": "string[]",
-    "name": "string",
-}}
-
-Got JSON:
-{}
-"#, body_str);
-
-        let details = binding.trim();
-
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 200,
-                "message": "Invalid schema for JSON body",
-                "details": details,
-            }
-        });
+async fn message_history(Path((id, message_id)): Path<(String, u64)>, AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    require_role(&user.user_id, &id, Permission::Read).await?;
 
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
-    };
+    let db = KolloquyDB::new();
+    let query = MessageQuery::Get { chat: id.clone(), id: message_id };
 
-    let Some(mut sid) = jar.get("SSID").map(|cookie| (&cookie.to_string()[5..]).to_string()) else {
-        return Redirect::temporary("/login").into_response();
-    };
+    let message = db.execute(&query).await?.pop().ok_or(ApiError::MessageNotFound)?;
+
+    let success_json = json!({
+        "success": true,
+        "id": message.id,
+        "author": message.author,
+        "revisions": message.revisions().into_iter().map(|(content, written)| json!({
+            "content": content,
+            "written": written.to_rfc3339(),
+        })).collect::<Vec<_>>(),
+    });
 
-    sid = sid.replace("%22", "");
-    sid = sid.replace("%2F", "/");
+    Ok(Response::builder()
+        .body(serde_json::to_string(&success_json).unwrap())
+        .set_content_type("application/json")
+        .with_status(StatusCode::OK)
+        .into_response())
+}
 
-    let mut state_read = state.open_sessions.write().await;
+/// A chat as it's listed on the chats page, with its latest message as plain text rather than markup.
+fn chat_summary(name: &str, id: &str, icon: String, latest: &[chat::Message]) -> serde_json::Value {
+    json!({
+        "name": name,
+        "messages": latest.iter().map(chat::Message::text).collect::<Vec<String>>(),
+        "icon": icon,
+        "id": id,
+    })
+}
 
-    let Some(&mut (ref mut user, ref mut session_started)) = state_read.get_mut(&sid) else {
-        return Redirect::temporary("/login").into_response();
-    };
+#[handler]
+async fn user_chat(Path(id): Path<String>, AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    let chat = Chat::from_remote(id.clone()).await?;
+    let role = chat.require(&user.user_id, Permission::Read)?;
+
+    let (messages, has_more) = chat.message_page(&MessagePageParams::default()).await?;
+
+    render_page(CHAT_TEMPLATE, json!({
+        "messages": render_messages(&messages, &user).await?,
+        "has_more": has_more,
+        "oldest": messages.first().map(|m| m.id),
+        "id": chat.id,
+        "read_only": !role.allows(Permission::Send),
+        "self": {
+            "id": user.user_id,
+            "handle": user.handle
+        },
+    }))
+}
 
-    if Utc::now().naive_local() - session_started.naive_local() > TimeDelta::minutes(30) {
-        state.open_sessions.write().await.remove(&sid);
+#[utoipa::path(
+    post,
+    path = "/create",
+    tag = "chats",
+    request_body = CreateChatBody,
+    security(("session" = [])),
+    responses(
+        (status = 201, description = "The new chat's `id` and `icon`"),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200) or name too long (206)"),
+        (status = 403, body = ErrorResponse, description = "Email not verified (5)"),
+        (status = 404, body = ErrorResponse, description = "A participant doesn't exist (102)"),
+    ),
+)]
+#[handler]
+async fn create_chat(body: Body, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    let body_str = body.into_string().await?;
+    let body = serde_json::from_str::<CreateChatBody>(&body_str).map_err(|_| openapi::invalid_body::<CreateChatBody>(&body_str))?;
 
-        return Redirect::temporary("/login").into_response();
+    if !user.email_verified {
+        return Err(ApiError::EmailNotVerified);
     }
 
     if body.name.len() > 20 {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 206,
-                "message": "Chat name is too long.",
-            }
-        });
-
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
+        return Err(ApiError::ChatNameTooLong);
     }
 
     let cleaned_name = ammonia::clean_text(&body.name);
@@ -601,18 +750,16 @@ Got JSON:
         let db = KolloquyDB::new();
         let query = UserQuery::GetByHandle(handle);
 
-        db.execute(&query).await.unwrap().unwrap()
-    })).await;
-
-    let (mut chat, ref icon) = Chat::new(cleaned_name).await;
+        match db.execute(&query).await {
+            Ok(Some(user)) => Ok(user),
+            Ok(None) | Err(QueryError::NotFound) => Err(ApiError::UserNotFound),
+            Err(e) => Err(e.into()),
+        }
+    })).await.into_iter().collect::<Result<Vec<_>, _>>()?;
 
-    chat.execute(&mut ChatQuery::PutChat).await;
-    chat.execute(&mut ChatQuery::PutIcon(Clone::clone(&icon))).await;
-    chat.execute(&mut ChatQuery::AddParticipant(user)).await;
+    let (chat, ref icon) = Chat::create(cleaned_name, &user, cleaned_participants).await?;
 
-    for mut user in cleaned_participants {
-        chat.execute(&mut ChatQuery::AddParticipant(&mut user)).await;
-    }
+    api::join_sockets(&state, &chat).await;
 
     let success_json = json!({
         "success": true,
@@ -620,243 +767,412 @@ Got JSON:
         "icon": icon.to_string(),
     });
 
-    Response::builder()
+    Ok(Response::builder()
         .body(serde_json::to_string(&success_json).unwrap())
         .set_content_type("application/json")
         .with_status(StatusCode::CREATED)
-        .into_response()
+        .into_response())
 }
 
+#[utoipa::path(
+    post,
+    path = "/chat/{id}/attachments",
+    tag = "attachments",
+    params(("id" = String, Path), UploadParams),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream", description = "The file's contents"),
+    security(("session" = [])),
+    responses(
+        (status = 201, body = AttachmentResponse, description = "The attachment, whose `id` can be sent with a message"),
+        (status = 400, body = ErrorResponse, description = "An image that couldn't be read (226)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or read only (8)"),
+        (status = 413, body = ErrorResponse, description = "The file is too large (224)"),
+        (status = 415, body = ErrorResponse, description = "The type of file can't be attached (225)"),
+    ),
+)]
 #[handler]
-async fn account_page(jar: &CookieJar, state: Data<&Arc<ServerState>>) -> Response {
-    let Some(mut sid) = jar.get("SSID").map(|cookie| (&cookie.to_string()[5..]).to_string()) else {
-        return Redirect::temporary("/login").into_response();
-    };
+async fn upload_attachment(Path(id): Path<String>, Query(params): Query<UploadParams>, body: Body, AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    api::upload_attachment_response(&user, &id, params, body).await
+}
 
-    sid = sid.replace("%22", "");
-    sid = sid.replace("%2F", "/");
+#[utoipa::path(
+    get,
+    path = "/chat/{id}/attachments/{attachment}",
+    tag = "attachments",
+    params(("id" = String, Path), ("attachment" = String, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, content_type = "application/octet-stream", body = Vec<u8>, description = "The file, sent as its own type"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+        (status = 404, body = ErrorResponse, description = "No attachment with this id (223)"),
+    ),
+)]
+#[handler]
+async fn get_attachment(Path((id, attachment)): Path<(String, String)>, AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    api::attachment_response(&user, &id, &attachment, false).await
+}
 
-    let state_read = state.open_sessions.read().await;
+#[utoipa::path(
+    get,
+    path = "/chat/{id}/attachments/{attachment}/thumbnail",
+    tag = "attachments",
+    params(("id" = String, Path), ("attachment" = String, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, content_type = "image/png", body = Vec<u8>, description = "The thumbnail, at most 320 pixels wide and tall"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1)"),
+        (status = 404, body = ErrorResponse, description = "No attachment with this id, or it isn't an image (223)"),
+    ),
+)]
+#[handler]
+async fn get_thumbnail(Path((id, attachment)): Path<(String, String)>, AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    api::attachment_response(&user, &id, &attachment, true).await
+}
 
-    let Some((user, session_started)) = state_read.get(&sid) else {
-        return Redirect::temporary("/login").into_response();
-    };
+#[utoipa::path(
+    post,
+    path = "/chat/{id}/participants",
+    tag = "chats",
+    params(("id" = String, Path)),
+    request_body = AddParticipantBody,
+    security(("session" = [])),
+    responses(
+        (status = 201, body = ParticipantsResponse, description = "Everyone who is now a part of the chat"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or not allowed by their role (8)"),
+        (status = 404, body = ErrorResponse, description = "No user with this handle (102)"),
+        (status = 409, body = ErrorResponse, description = "Already a part of this chat (219)"),
+    ),
+)]
+#[handler]
+async fn add_participant(Path(id): Path<String>, body: Body, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api::add_participant_response(&state, &user, &id, body).await
+}
 
-    if Utc::now().naive_local() - session_started.naive_local() > TimeDelta::minutes(30) {
-        state.open_sessions.write().await.remove(&sid);
+#[utoipa::path(
+    delete,
+    path = "/chat/{id}/participants/{handle}",
+    tag = "chats",
+    params(("id" = String, Path), ("handle" = String, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, body = ParticipantsResponse, description = "Everyone who is still a part of the chat"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1), not allowed by their role (8) or the owner leaving (9)"),
+        (status = 404, body = ErrorResponse, description = "No user with this handle (102), or they aren't a part of this chat (220)"),
+    ),
+)]
+#[handler]
+async fn remove_participant(Path((id, handle)): Path<(String, String)>, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api::remove_participant_response(&state, &user, &id, &handle).await
+}
 
-        return Redirect::temporary("/login").into_response();
-    }
-    
-    let r2 = KolloquyR2::new(*USER_AVATAR_BUCKET.clone());
-    let query = UserQuery::GetAvatar(user.clone());
-    
-    let mut compressed_avatar = Cursor::new(r2.execute(&query).await.unwrap().to_vec());
-    let mut avatar = Cursor::new(Vec::new());
-    
-    BrotliDecompress(&mut compressed_avatar, &mut avatar).unwrap();
+#[utoipa::path(
+    put,
+    path = "/chat/{id}/participants/{handle}/role",
+    tag = "chats",
+    params(("id" = String, Path), ("handle" = String, Path)),
+    request_body = SetRoleBody,
+    security(("session" = [])),
+    responses(
+        (status = 200, body = ParticipantsResponse, description = "Everyone who is a part of the chat"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or not allowed by their role (8)"),
+        (status = 404, body = ErrorResponse, description = "No user with this handle (102), or they aren't a part of this chat (220)"),
+    ),
+)]
+#[handler]
+async fn set_role(Path((id, handle)): Path<(String, String)>, body: Body, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api::set_role_response(&state, &user, &id, &handle, body).await
+}
+
+#[utoipa::path(
+    patch,
+    path = "/chat/{id}",
+    tag = "chats",
+    params(("id" = String, Path)),
+    request_body = RenameChatBody,
+    security(("session" = [])),
+    responses(
+        (status = 200, body = ChatResponse),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200) or name too long (206)"),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or not allowed by their role (8)"),
+        (status = 404, body = ErrorResponse, description = "No chat with this id (205)"),
+    ),
+)]
+#[handler]
+async fn rename_chat(Path(id): Path<String>, body: Body, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api::rename_chat_response(&state, &user, &id, body).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/chat/{id}",
+    tag = "chats",
+    params(("id" = String, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, body = SuccessResponse),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or not its owner (8)"),
+        (status = 404, body = ErrorResponse, description = "No chat with this id (205)"),
+    ),
+)]
+#[handler]
+async fn delete_chat(Path(id): Path<String>, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api::delete_chat_response(&state, &user, &id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/chat/{id}/leave",
+    tag = "chats",
+    params(("id" = String, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, body = SuccessResponse),
+        (status = 403, body = ErrorResponse, description = "Not a part of this chat (1) or its owner (9)"),
+    ),
+)]
+#[handler]
+async fn leave_chat(Path(id): Path<String>, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    api::leave_chat_response(&state, &user, &id).await
+}
 
-    let avatar_string = String::from_utf8(avatar.into_inner()).unwrap();
+#[handler]
+async fn account_page(AuthenticatedUser { session, user }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    let sessions = state.sessions.list(&user.user_id).await.unwrap_or_default();
+    let tokens = KolloquyDB::new().execute(&AccessTokenQuery::ListForUser(user.user_id.clone())).await.unwrap_or_default();
 
-    let engine = Handlebars::new();
-    let context = Context::from(json!({
+    render_page(ACCOUNT_TEMPLATE, json!({
         "user": {
-            "avatar": avatar_string,
+            "avatar": user_avatar(&user).await?,
             "handle": user.handle,
             "joined": user.joined.naive_local().to_string(),
         },
+        "sessions": sessions.iter().map(|s| render_session(s, &session)).collect::<Vec<_>>(),
+        "tokens": tokens.iter().map(api::TokenView::from).collect::<Vec<_>>(),
         "not_self": false,
-    }));
+    }))
+}
 
-    let rendered = engine.render_template_with_context(ACCOUNT_TEMPLATE, &context).unwrap();
+/// Render a session as it's shown to its user.
+fn render_session(session: &Session, current: &Session) -> serde_json::Value {
+    json!({
+        "id": session.id,
+        "created": session.created.to_rfc3339(),
+        "last_seen": session.last_seen.to_rfc3339(),
+        "user_agent": session.user_agent,
+        "ip": session.ip,
+        "current": session.id == current.id,
+    })
+}
 
-    Response::builder()
-        .body(rendered)
-        .set_content_type("text/html")
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    tag = "accounts",
+    security(("session" = [])),
+    responses((status = 200, description = "The user's `sessions`")),
+)]
+#[handler]
+async fn list_sessions(AuthenticatedUser { session, user }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    let sessions = state.sessions.list(&user.user_id).await?;
+
+    let success_json = json!({
+        "success": true,
+        "sessions": sessions.iter().map(|s| render_session(s, &session)).collect::<Vec<_>>(),
+    });
+
+    Ok(Response::builder()
+        .body(serde_json::to_string(&success_json).unwrap())
+        .set_content_type("application/json")
         .with_status(StatusCode::OK)
-        .into_response()
+        .into_response())
 }
 
+#[utoipa::path(
+    delete,
+    path = "/sessions/{id}",
+    tag = "accounts",
+    params(("id" = String, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, body = SuccessResponse),
+        (status = 404, body = ErrorResponse, description = "No session with this id (211)"),
+    ),
+)]
 #[handler]
-async fn authenticate_user(body: Body, jar: &CookieJar, state: Data<&Arc<ServerState>>) -> Response {
-    let body_str = body.into_string().await.unwrap();
-
-    let Ok(body) = serde_json::from_str::<AuthenticateBody>(&*body_str) else {
-        let binding = format!(r#"
-Expected JSON to match schema:
-{{
-    "email": "string",
-    "password": "string",
-    "redirect": "url",
-}}
-
-Got JSON:
-{}
-"#, body_str);
-
-        let details = binding.trim();
-
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 200,
-                "message": "Invalid schema for JSON body",
-                "details": details,
-            }
-        });
+async fn revoke_session(Path(id): Path<String>, AuthenticatedUser { user, .. }: AuthenticatedUser, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    if !state.sessions.revoke(&user.user_id, &id).await? {
+        return Err(ApiError::SessionNotFound);
+    }
 
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
-    };
+    state.sockets.close(&user.user_id, Some(&id)).await;
 
-    if !REDIRECT_REGEX.deref().is_match(&body.redirect) {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 204,
-                "message": "Invalid redirect URL.",
-            }
-        });
+    let success_json = json!({
+        "success": true,
+    });
+
+    Ok(Response::builder()
+        .body(serde_json::to_string(&success_json).unwrap())
+        .set_content_type("application/json")
+        .with_status(StatusCode::OK)
+        .into_response())
+}
+
+#[utoipa::path(
+    get,
+    path = "/tokens",
+    tag = "tokens",
+    security(("session" = [])),
+    responses((status = 200, body = TokensResponse)),
+)]
+#[handler]
+async fn list_tokens(AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    api::tokens_response(&user.user_id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/tokens",
+    tag = "tokens",
+    request_body = CreateTokenBody,
+    security(("session" = [])),
+    responses(
+        (status = 201, body = TokenResponse),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200), name (217) or scopes (218)"),
+    ),
+)]
+#[handler]
+async fn create_token(body: Body, AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    api::create_token_response(&user.user_id, body).await
+}
 
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
+#[utoipa::path(
+    delete,
+    path = "/tokens/{id}",
+    tag = "tokens",
+    params(("id" = String, Path)),
+    security(("session" = [])),
+    responses(
+        (status = 200, body = SuccessResponse),
+        (status = 404, body = ErrorResponse, description = "No access token with this id (216)"),
+    ),
+)]
+#[handler]
+async fn revoke_token(Path(id): Path<String>, AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    api::revoke_token_response(&user.user_id, &id).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/auth",
+    tag = "accounts",
+    request_body = AuthenticateBody,
+    responses(
+        (status = 200, description = "Signed in, with the `SSID` cookie set"),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200), email (201), password hash (203) or redirect (204)"),
+        (status = 403, body = ErrorResponse, description = "Incorrect password (0) or account locked (3)"),
+        (status = 404, body = ErrorResponse, description = "No user with this email (102)"),
+        (status = 429, body = ErrorResponse, description = "Too many attempts (400, 401)"),
+    ),
+)]
+#[handler]
+async fn authenticate_user(req: &Request, body: Body, signed_in: Option<AuthenticatedUser>, jar: &CookieJar, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    let body_str = body.into_string().await?;
+    let body = serde_json::from_str::<AuthenticateBody>(&body_str).map_err(|_| openapi::invalid_body::<AuthenticateBody>(&body_str))?;
+
+    if !REDIRECT_REGEX.deref().is_match(&body.redirect) {
+        return Err(ApiError::InvalidRedirect);
     }
 
     // Check the email against the RFC 5233 regex
     if !EMAIL_REGEX.deref().is_match(&body.email) {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 201,
-                "message": "Invalid email address.",
-                "details": "Email address did not match the (partial) RFC 5233 regex."
-            }
-        });
-
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
+        return Err(ApiError::InvalidEmail);
     }
 
     if !PASSWORD_REGEX.deref().is_match(&body.password) {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 203,
-                "message": "Invalid password hash.",
-                "details": "Hash did not match required length and encoding.",
-            }
-        });
-
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
+        return Err(ApiError::InvalidPasswordHash);
     }
 
     // Then, check if the user is already signed in and session is valid (if yes, do nothing)
-    if let Some(sid) = jar.get("SSID").map(|cookie| (&cookie.to_string()[5..]).to_string()) {
-        let state_read = state.open_sessions.read().await;
-
-        if let Some((_, session_started)) = state_read.get(&sid) {
-            if Utc::now().naive_local() - session_started.naive_local() > TimeDelta::minutes(30) {
-                state.open_sessions.write().await.remove(&sid);
-            } else {
-                return Redirect::permanent(body.redirect)
-                    .with_status(StatusCode::OK)
-                    .into_response();
-            }
-        };
+    if signed_in.is_some() {
+        return Ok(Redirect::permanent(body.redirect)
+            .with_status(StatusCode::OK)
+            .into_response());
+    }
+
+    // Only attempts that could sign in count towards the limit, so junk requests can't lock anyone out
+    if let Err(retry_after) = LOGIN_EMAIL_LIMITER.check(&body.email) {
+        return Err(ApiError::TooManyAttempts { retry_after });
     }
 
     let db = KolloquyDB::new();
     let query = UserQuery::GetByEmail(body.email);
 
     let user = match db.execute(&query).await {
-        Ok(user) => user.unwrap(),
-        Err(QueryError::NotFound) => {
-            let error_json = json!({
-                "success": false,
-                "error": {
-                    "code": 100,
-                    "message": "A user with this email does not exist."
-                }
-            });
-
-            return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
-        },
-        Err(e) => {
-            let error_json = json!({
-                "success": false,
-                "error": {
-                    "code": 300,
-                    "message": "Could not access database.",
-                    "details": format!("{:?}", e)
-                }
-            });
-
-            return (StatusCode::INTERNAL_SERVER_ERROR, serde_json::to_string(&error_json).unwrap()).into_response();
-        }
+        Ok(Some(user)) => user,
+        Ok(None) | Err(QueryError::NotFound) => return Err(ApiError::UserNotFound),
+        Err(e) => return Err(e.into()),
     };
 
+    if let Some(retry_after) = user.locked_for() {
+        return Err(ApiError::AccountLocked { retry_after });
+    }
+
     // Compare the password hashes
-    if user.password != body.password {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 0,
-                "message": "Incorrect password."
-            }
-        });
+    let stored_password = user.password.clone();
+    let client_password = body.password.clone();
+
+    let check = tokio::task::spawn_blocking(move || verify_password(&stored_password, &client_password)).await.unwrap();
+
+    if !check.is_correct() {
+        let failed = db.execute(&UserQuery::RecordFailedLogin(user.user_id.clone())).await?
+            .ok_or(ApiError::UserNotFound)?;
+
+        if let Some(lock) = LOCKOUT_POLICY.lock_duration(failed.failed_login_attempts) {
+            db.execute(&UserQuery::LockUntil { id: user.user_id.clone(), until: Utc::now() + lock }).await?;
+        }
 
-        return (StatusCode::FORBIDDEN, serde_json::to_string(&error_json).unwrap()).into_response();
+        return Err(ApiError::IncorrectPassword);
     }
 
-    let sid = random_session_id().await;
+    // Users from before server-side hashing get their password rehashed now that we know it
+    if check == PasswordCheck::CorrectLegacy {
+        let client_password = body.password.clone();
+        let hash = tokio::task::spawn_blocking(move || hash_password(&client_password)).await.unwrap();
 
-    state.open_sessions.write().await.insert(sid.clone(), (user.clone(), Utc::now()));
+        // Nothing changes if the password was reset in the meantime
+        db.execute(&UserQuery::SetPassword { id: user.user_id.clone(), previous: user.password.clone(), hash }).await?;
+    }
 
-    let mut cookie = cookie::Cookie::new("SSID", sid.clone());
+    db.execute(&UserQuery::RecordLogin(user.user_id.clone())).await?;
 
-    cookie.set_same_site(SameSite::Strict);
-    cookie.set_http_only(true);
-    cookie.set_max_age(Duration::from_secs(30 * 60));
+    let sid = start_session(&state, &user, req).await?;
 
-    jar.add(cookie);
+    jar.add(session_cookie(&sid));
 
-    Redirect::permanent(body.redirect)
+    Ok(Redirect::permanent(body.redirect)
         .with_status(StatusCode::OK)
-        .into_response()
+        .into_response())
 }
 
+#[utoipa::path(
+    post,
+    path = "/register",
+    tag = "accounts",
+    request_body = RegisterBody,
+    responses(
+        (status = 200, description = "Signed up and signed in, with the `SSID` cookie set"),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200), email (201), handle (202) or password hash (203)"),
+        (status = 409, body = ErrorResponse, description = "Email (100) or handle (101) already taken"),
+        (status = 429, body = ErrorResponse, description = "Too many attempts (400, 401)"),
+    ),
+)]
 #[handler]
-async fn register_user(body: Body, jar: &CookieJar, state: Data<&Arc<ServerState>>) -> Response {
-    let body_str = body.into_string().await.unwrap();
-    
-    let Ok(mut body) = serde_json::from_str::<RegisterBody>(&*body_str) else {
-        let binding = format!(r#"
-Expected JSON to match schema:
-{{
-    "email": "string",
-    "handle": "string",
-    "age": "u8",
-    "password": "string"
-}}
-
-Got JSON:
-{}
-"#, body_str);
-
-        let details = binding.trim();
-
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 200,
-                "message": "Invalid schema for JSON body",
-                "details": details,
-            }
-        });
-        
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
-    };
-    
+async fn register_user(req: &Request, body: Body, jar: &CookieJar, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    let body_str = body.into_string().await?;
+    let mut body = serde_json::from_str::<RegisterBody>(&body_str).map_err(|_| openapi::invalid_body::<RegisterBody>(&body_str))?;
+
     let user_id = random_user_id().await;
 
-    let user = User {
+    let mut user = User {
         email: body.email,
         handle: body.handle,
         password: body.password,
@@ -877,49 +1193,26 @@ Got JSON:
         failed_login_attempts: 0,
         locked_until: DateTime::<Utc>::from_timestamp_millis(0).unwrap(),
         timezone: "NULL".to_string(),
-        enrolled_chats: vec![],
     };
 
     // Check the email against the RFC 5233 regex
     if !EMAIL_REGEX.deref().is_match(&user.email) {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 201,
-                "message": "Invalid email address.",
-                "details": "Email address did not match the (partial) RFC 5233 regex."
-            }
-        });
-
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
+        return Err(ApiError::InvalidEmail);
     }
 
     // Check the handle
     if !HANDLE_REGEX.deref().is_match(&user.handle) {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 202,
-                "message": "Invalid handle.",
-                "details": r"Handle did not match the handle regex (/^@?[\w!$-.\\\/]{3,15}$/)"
-            }
-        });
-
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
+        return Err(ApiError::InvalidHandle);
     }
 
     // Check the password (Base64 hash)
     if !PASSWORD_REGEX.deref().is_match(&user.password) {
-        let error_json = json!({
-            "success": false,
-            "error": {
-                "code": 203,
-                "message": "Invalid password hash.",
-                "details": "Hash did not match required length and encoding.",
-            }
-        });
+        return Err(ApiError::InvalidPasswordHash);
+    }
 
-        return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
+    // Only well-formed requests count towards the limit, so junk requests can't lock anyone out
+    if let Err(retry_after) = LOGIN_EMAIL_LIMITER.check(&user.email) {
+        return Err(ApiError::TooManyAttempts { retry_after });
     }
 
     // Check if user already exists
@@ -927,79 +1220,43 @@ Got JSON:
     let query = UserQuery::GetByEmail(user.email.clone());
 
     match db.execute(&query).await {
-        Ok(_) => {
-            let error_json = json!({
-                "success": false,
-                "error": {
-                    "code": 100,
-                    "message": "A user with this email already exists."
-                }
-            });
-
-            return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
-        }
-        Err(QueryError::NotFound) => (),
-        Err(e) => {
-            let error_json = json!({
-                "success": false,
-                "error": {
-                    "code": 300,
-                    "message": "Could not access database.",
-                    "details": format!("{:?}", e)
-                }
-            });
-
-            return (StatusCode::INTERNAL_SERVER_ERROR, serde_json::to_string(&error_json).unwrap()).into_response();
-        }
+        Ok(Some(_)) => return Err(ApiError::EmailTaken),
+        Ok(None) | Err(QueryError::NotFound) => (),
+        Err(e) => return Err(e.into()),
     };
 
     let query = UserQuery::GetByHandle(user.handle.clone());
 
     match db.execute(&query).await {
-        Ok(_) => {
-            let error_json = json!({
-                "success": false,
-                "error": {
-                    "code": 101,
-                    "message": "A user with this handle already exists."
-                }
-            });
-
-            return (StatusCode::BAD_REQUEST, serde_json::to_string(&error_json).unwrap()).into_response();
-        }
-        Err(QueryError::NotFound) => (),
-        Err(e) => {
-            let error_json = json!({
-                "success": false,
-                "error": {
-                    "code": 300,
-                    "message": "Could not access database.",
-                    "details": format!("{:?}", e)
-                }
-            });
-
-            return (StatusCode::INTERNAL_SERVER_ERROR, serde_json::to_string(&error_json).unwrap()).into_response();
-        }
+        Ok(Some(_)) => return Err(ApiError::HandleTaken),
+        Ok(None) | Err(QueryError::NotFound) => (),
+        Err(e) => return Err(e.into()),
     };
 
-
     let avatar = create_avatar();
 
     // Upload the user's avatar
-    let bucket = USER_AVATAR_BUCKET.clone();
     let query = UserQuery::UploadAvatar(user.clone(), Clone::clone(&avatar));
-    let r2 = KolloquyR2::new(*bucket);
+    let r2 = USER_AVATAR_BUCKET.clone();
+
+    r2.execute(&query).await?;
 
-    r2.execute(&query).await.unwrap();
+    // Only ever store an Argon2 hash of what the client sent
+    let client_password = user.password.clone();
+
+    user.password = tokio::task::spawn_blocking(move || hash_password(&client_password)).await.unwrap();
 
     // Put the user to the database
     let query = UserQuery::PutToDB(user.clone());
 
-    db.execute(&query).await.unwrap();
-    
-    let sid = random_session_id().await;
+    db.execute(&query).await?;
 
-    state.open_sessions.write().await.insert(sid.clone(), (user.clone(), Utc::now()));
+    // Registering still works if the email can't be sent, since it can be sent again from /verify/resend
+    if let Err(e) = send_verification_email(&user).await {
+        ApiError::from(e).log();
+    }
+    
+    let sid = start_session(&state, &user, req).await?;
 
     let success_json = json!({
         "success": true,
@@ -1007,23 +1264,208 @@ Got JSON:
         "avatar": avatar.to_string(),
     });
 
-    let mut cookie = cookie::Cookie::new("SSID", sid.clone());
-
-    cookie.set_same_site(SameSite::Strict);
-    cookie.set_http_only(true);
-    cookie.set_secure(true);
-    cookie.set_domain("kolloquy.com");
-    cookie.set_path("/");
-    cookie.set_max_age(Duration::from_secs(30 * 60));
-
-    jar.add(cookie);
+    jar.add(session_cookie(&sid));
 
-    Response::builder()
+    Ok(Response::builder()
         .body(serde_json::to_string(&success_json).unwrap())
         .set_content_type("application/json")
         .with_status(StatusCode::CREATED)
-        .into_response()
+        .into_response())
+}
+
+/// Email a user a link to `/verify`, which only works for the address it was sent to.
+async fn send_verification_email(user: &User) -> Result<(), mail::MailError> {
+    let token = issue_token(TokenPurpose::VerifyEmail, &user.user_id, &user.email, *EMAIL_VERIFICATION_TTL);
+
+    KOLLOQUY_MAILER.send(Mail {
+        to: user.email.clone(),
+        subject: "Verify your email for Kolloquy".to_string(),
+        body: format!(
+            "Hi @{},\n\nOpen this link to verify your email address:\n{}/verify?token={token}\n\nIt stops working after {} hours. If you didn't sign up for Kolloquy, you can ignore this email.",
+            user.handle,
+            *KOLLOQUY_PUBLIC_URL,
+            EMAIL_VERIFICATION_TTL.num_hours(),
+        ),
+    }).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/verify",
+    tag = "accounts",
+    params(VerifyParams),
+    responses(
+        (status = 303, description = "Verified, redirecting to `/account`"),
+        (status = 400, body = ErrorResponse, description = "Invalid (212) or expired (213) link"),
+    ),
+)]
+#[handler]
+async fn verify_email(Query(params): Query<VerifyParams>) -> Result<Response, ApiError> {
+    let user_id = token_subject(&params.token).ok_or(ApiError::InvalidVerificationLink)?;
+
+    let db = KolloquyDB::new();
+    let query = UserQuery::GetByID(user_id.to_string());
+
+    let user = match db.execute(&query).await {
+        Ok(Some(user)) => user,
+        Ok(None) | Err(QueryError::NotFound) => return Err(ApiError::InvalidVerificationLink),
+        Err(e) => return Err(e.into()),
+    };
+
+    match check_token(TokenPurpose::VerifyEmail, &params.token, &user.email) {
+        Ok(()) => (),
+        Err(TokenError::Invalid) => return Err(ApiError::InvalidVerificationLink),
+        Err(TokenError::Expired) => return Err(ApiError::ExpiredVerificationLink),
+    }
+
+    if !user.email_verified {
+        db.execute(&UserQuery::VerifyEmail(user.user_id)).await?;
+    }
+
+    Ok(Redirect::see_other("/account").into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/verify/resend",
+    tag = "accounts",
+    security(("session" = [])),
+    responses(
+        (status = 202, description = "A new link was sent"),
+        (status = 204, description = "The email address is already verified"),
+        (status = 500, body = ErrorResponse, description = "Could not send email (301)"),
+    ),
+)]
+#[handler]
+async fn resend_verification(AuthenticatedUser { user, .. }: AuthenticatedUser) -> Result<Response, ApiError> {
+    if user.email_verified {
+        return Ok(StatusCode::NO_CONTENT.into_response());
+    }
+
+    if let Err(retry_after) = LOGIN_EMAIL_LIMITER.check(&user.email) {
+        return Err(ApiError::TooManyAttempts { retry_after });
+    }
+
+    send_verification_email(&user).await?;
+
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/forgot",
+    tag = "accounts",
+    request_body = ForgotPasswordBody,
+    responses(
+        (status = 202, description = "A link was sent, if there's an account with this email"),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200)"),
+        (status = 429, body = ErrorResponse, description = "Too many attempts (400, 401)"),
+    ),
+)]
+#[handler]
+async fn forgot_password(body: Body) -> Result<Response, ApiError> {
+    let body_str = body.into_string().await?;
+    let body = serde_json::from_str::<ForgotPasswordBody>(&body_str).map_err(|_| openapi::invalid_body::<ForgotPasswordBody>(&body_str))?;
+
+    if let Err(retry_after) = LOGIN_EMAIL_LIMITER.check(&body.email) {
+        return Err(ApiError::TooManyAttempts { retry_after });
+    }
+
+    let db = KolloquyDB::new();
+    let query = UserQuery::GetByEmail(body.email);
+
+    let user = match db.execute(&query).await {
+        Ok(user) => user,
+        Err(QueryError::NotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    // Respond the same whether or not the account exists, so this can't be used to find out
+    if let Some(user) = user {
+        // The token is bound to the current password hash, so it stops working once it's used
+        let token = issue_token(TokenPurpose::ResetPassword, &user.user_id, &user.password, *PASSWORD_RESET_TTL);
+
+        let mail = Mail {
+            to: user.email.clone(),
+            subject: "Reset your Kolloquy password".to_string(),
+            body: format!(
+                "Hi @{},\n\nOpen this link to choose a new password:\n{}/reset?token={token}\n\nIt stops working after {} minutes, or once it's been used. If you didn't ask to reset your password, you can ignore this email.",
+                user.handle,
+                *KOLLOQUY_PUBLIC_URL,
+                PASSWORD_RESET_TTL.num_minutes(),
+            ),
+        };
+
+        if let Err(e) = KOLLOQUY_MAILER.send(mail).await {
+            ApiError::from(e).log();
+        }
+    }
+
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+#[utoipa::path(
+    post,
+    path = "/reset",
+    tag = "accounts",
+    request_body = ResetPasswordBody,
+    responses(
+        (status = 200, body = SuccessResponse, description = "Reset, and every session signed out"),
+        (status = 400, body = ErrorResponse, description = "Invalid body (200), password hash (203), or invalid (214) or expired (215) link"),
+        (status = 429, body = ErrorResponse, description = "Too many attempts (400)"),
+    ),
+)]
+#[handler]
+async fn reset_password(body: Body, state: Data<&Arc<ServerState>>) -> Result<Response, ApiError> {
+    let body_str = body.into_string().await?;
+    let body = serde_json::from_str::<ResetPasswordBody>(&body_str).map_err(|_| openapi::invalid_body::<ResetPasswordBody>(&body_str))?;
+
+    // Check the password (Base64 hash)
+    if !PASSWORD_REGEX.deref().is_match(&body.password) {
+        return Err(ApiError::InvalidPasswordHash);
+    }
+
+    let user_id = token_subject(&body.token).ok_or(ApiError::InvalidResetLink)?;
+
+    let db = KolloquyDB::new();
+    let query = UserQuery::GetByID(user_id.to_string());
+
+    let user = match db.execute(&query).await {
+        Ok(Some(user)) => user,
+        Ok(None) | Err(QueryError::NotFound) => return Err(ApiError::InvalidResetLink),
+        Err(e) => return Err(e.into()),
+    };
+
+    match check_token(TokenPurpose::ResetPassword, &body.token, &user.password) {
+        Ok(()) => (),
+        Err(TokenError::Invalid) => return Err(ApiError::InvalidResetLink),
+        Err(TokenError::Expired) => return Err(ApiError::ExpiredResetLink),
+    }
+
+    let client_password = body.password;
+
+    let hash = tokio::task::spawn_blocking(move || hash_password(&client_password)).await.unwrap();
+
+    // Whoever had the email can get back in, even if the account was locked.
+    // Links are tied to the old password, so if two uses of one race only the first changes it
+    let query = UserQuery::ResetPassword { id: user.user_id.clone(), previous: user.password, hash };
+
+    if db.execute(&query).await?.is_none() {
+        return Err(ApiError::InvalidResetLink);
+    }
+
+    state.sessions.revoke_all(&user.user_id).await?;
+    state.sockets.close(&user.user_id, None).await;
+
+    let success_json = json!({
+        "success": true,
+    });
 
+    Ok(Response::builder()
+        .body(serde_json::to_string(&success_json).unwrap())
+        .set_content_type("application/json")
+        .with_status(StatusCode::OK)
+        .into_response())
 }
 
 /// Allows origins matching the regex /(https|wss):\/\/(www\.)kolloquy\.com/
@@ -1042,7 +1484,21 @@ async fn index() -> Response {
 #[tokio::main]
 async fn main() -> Result<(), std::io::Error> {
     dotenv().ok();
-    
+
+    // Pick the database backend and object store now, rather than on the first request
+    LazyLock::force(&KOLLOQUY_DB_BACKEND);
+    LazyLock::force(&USER_AVATAR_BUCKET);
+    LazyLock::force(&KOLLOQUY_CHATS_BUCKET);
+
+    // `server migrate` moves chats from before membership was kept in the database into it
+    if env::args().nth(1).as_deref() == Some("migrate") {
+        let (chats, users) = migrate_legacy_chats(&KolloquyDB::new(), &KOLLOQUY_CHATS_BUCKET).await.map_err(std::io::Error::other)?;
+
+        eprintln!("Migrated {chats} chats for {users} users");
+
+        return Ok(());
+    }
+
     let user_facing = Route::new()
         .at("/", get(index))
         .at("/signup", get(signup_page))
@@ -1055,25 +1511,48 @@ async fn main() -> Result<(), std::io::Error> {
         .at("/dist/chats.js", get(chats_js))
         .at("/dist/chat.js", get(chat_js))
         .at("/account", get(account_page))
+        .at("/sessions", get(list_sessions))
+        .at("/sessions/:id", delete(revoke_session))
+        .at("/tokens", get(list_tokens).post(create_token))
+        .at("/tokens/:id", delete(revoke_token))
         .at("/user/:handle", get(user_page))
         .at("/chats.css", get(chats_css))
         .at("/chat.css", get(chat_css))
         .at("/chats", get(user_chats))
         .at("/icons/icon.svg", get(icon_svg))
         .at("/manifest.json", get(manifest_json))
-        .at("/chat/:id", get(user_chat));
+        .at("/chat/:id", get(user_chat).patch(rename_chat).delete(delete_chat))
+        .at("/chat/:id/messages", get(chat_messages))
+        .at("/chat/:id/messages/:message", patch(edit_message).delete(delete_message))
+        .at("/chat/:id/messages/:message/history", get(message_history))
+        .at("/chat/:id/attachments", post(upload_attachment))
+        .at("/chat/:id/attachments/:attachment", get(get_attachment))
+        .at("/chat/:id/attachments/:attachment/thumbnail", get(get_thumbnail))
+        .at("/chat/:id/participants", post(add_participant))
+        .at("/chat/:id/participants/:handle", delete(remove_participant))
+        .at("/chat/:id/participants/:handle/role", put(set_role))
+        .at("/chat/:id/leave", post(leave_chat));
 
     tracing_subscriber::fmt::init();
+
+    // Clear out attachments nobody sent, so they don't keep taking up space or count against their uploader
+    tokio::spawn(Attachment::sweep_periodically());
     
     let app = apply_cors(Route::new()
         .nest(
             "/",
             user_facing,
         )
-        .at("/register", register_user)
-        .at("/auth", authenticate_user)
+        .nest("/api/v1", api::routes())
+        .at("/register", register_user.with(RateLimitMiddleware(&LOGIN_IP_LIMITER)))
+        .at("/auth", authenticate_user.with(RateLimitMiddleware(&LOGIN_IP_LIMITER)))
+        .at("/forgot", post(forgot_password.with(RateLimitMiddleware(&LOGIN_IP_LIMITER))))
+        .at("/reset", get(reset_page).post(reset_password.with(RateLimitMiddleware(&LOGIN_IP_LIMITER))))
+        .at("/verify", get(verify_email))
+        .at("/verify/resend", post(resend_verification))
+        .at("/openapi.json", get(openapi::openapi_json))
         .at("/create", create_chat)
-        .at("/chatws", get(chat_socket.data(broadcast::channel::<SocketChatBody>(500).0))))
+        .at("/chatws", get(chat_socket)))
         .with(LoggingMiddleware {
             persistence: LoggingPersistence::LogFileOnly(PathBuf::from("logs/log.txt")),
             format: LoggingFormat::LBL,
@@ -1082,11 +1561,40 @@ async fn main() -> Result<(), std::io::Error> {
         .with(CookieJarManager::new());
 
     let local_ip = env::var("IPV6").unwrap();
-    let addr = env::var("ACTIVE_SERVER").unwrap().replace("@", &*format!("[{local_ip}]"));
+    let addr = env::var("ACTIVE_SERVER").unwrap().replace("@", &format!("[{local_ip}]"));
 
     eprintln!("Server running at {addr}");
 
     Server::new(TcpListener::bind(addr))
         .run(app)
         .await
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn message_content_never_reaches_a_page_as_html() {
+        let hostile = chat::clean_content("<script>alert(1)</script><img src=x onerror=alert(1)> *hi*").unwrap();
+        let message = chat::Message::new("abc1234", hostile);
+
+        let chat_page = render_page(CHAT_TEMPLATE, json!({
+            "messages": [message_json(&message, "abc1234", &HashMap::new())],
+            "id": "ab12cde",
+            "self": { "id": "abc1234", "handle": "tester" },
+        })).unwrap().into_body().into_string().await.unwrap();
+
+        let chats_page = render_page(CHATS_TEMPLATE, json!({
+            "chats": [chat_summary("Friends", "ab12cde", String::new(), std::slice::from_ref(&message))],
+        })).unwrap().into_body().into_string().await.unwrap();
+
+        for page in [&chat_page, &chats_page] {
+            assert!(!page.contains("<script>alert"));
+            assert!(!page.contains("<img src=x"));
+        }
+
+        assert!(chat_page.contains("<strong>hi</strong>"));
+        assert!(chats_page.contains("alert(1) hi</p>"));
+    }
+}
@@ -0,0 +1,545 @@
+//! Kolloquy Markup, as described in `markup/syntax.md`, parsed into [`Node`]s and rendered as sanitised HTML or plain text.
+//!
+//! * `<text>` emphasises
+//! * `*text*` shouts
+//! * `_text_` underlines
+//! * `-text-` strikes through
+//! * `(display)<link>` links
+//! * `%MD` at the very start of a message switches the rest of it to Markdown (CommonMark, with tables and strikethrough)
+//!
+//! A backslash before any of `\ < > * _ - ( ) %` writes that character as it is. So does anything that never closes,
+//! so every message renders as something. Styles don't span lines, and `*`, `_` and `-` only open at the start of a
+//! word and close at the end of one, so `snake_case_names` and `well-known` stay as they are.
+
+use ammonia::{Builder, UrlRelative};
+use pulldown_cmark::{Event, Options, Parser as MarkdownParser, TagEnd};
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+/// Starts a message written in Markdown rather than Kolloquy Markup.
+pub const MARKDOWN_SWITCH: &str = "%MD";
+
+/// How deeply styles can be nested before the rest is written as text.
+const MAX_DEPTH: usize = 16;
+
+/// Characters that a backslash writes as they are.
+const ESCAPABLE: [char; 9] = ['\\', '<', '>', '*', '_', '-', '(', ')', '%'];
+
+/// The only HTML that rendered markup is allowed to contain, whatever ends up in it.
+static SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::empty();
+
+    builder
+        .tags(HashSet::from(["em", "strong", "u", "s", "a", "br"]))
+        .add_tag_attributes("a", &["href"])
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .url_relative(UrlRelative::Deny)
+        .link_rel(Some("noopener noreferrer nofollow"));
+
+    builder
+});
+
+/// What Markdown messages are allowed to contain. There are no images, so reading a message never loads anything.
+static MARKDOWN_SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::empty();
+
+    builder
+        .tags(HashSet::from([
+            "p", "br", "hr", "em", "strong", "del", "s", "a", "code", "pre", "blockquote", "ul", "ol", "li",
+            "h1", "h2", "h3", "h4", "h5", "h6", "table", "thead", "tbody", "tr", "th", "td",
+        ]))
+        .add_tag_attributes("a", &["href"])
+        .add_tag_attributes("ol", &["start"])
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .url_relative(UrlRelative::Deny)
+        .link_rel(Some("noopener noreferrer nofollow"));
+
+    builder
+});
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    Text(String),
+    /// `<text>`
+    Emphasis(Vec<Node>),
+    /// `*text*`
+    Shout(Vec<Node>),
+    /// `_text_`
+    Underline(Vec<Node>),
+    /// `-text-`
+    Strike(Vec<Node>),
+    /// `(display)<href>`
+    Link { display: Vec<Node>, href: String },
+    /// A new line
+    Break,
+    /// Everything after [`MARKDOWN_SWITCH`]
+    Markdown(String),
+}
+
+/// Parse a message into its nodes. This never fails; anything that isn't markup is text.
+pub fn parse(source: &str) -> Vec<Node> {
+    if let Some(markdown) = markdown_source(source) {
+        return vec![Node::Markdown(markdown.to_string())];
+    }
+
+    let parser = Parser { chars: source.chars().collect() };
+
+    parser.inline(0, parser.chars.len(), 0)
+}
+
+/// Render a message as HTML that's safe to put straight into a page.
+pub fn render_html(source: &str) -> String {
+    to_html(&parse(source))
+}
+
+/// Render a message as text, without any of its markup.
+pub fn render_text(source: &str) -> String {
+    to_text(&parse(source))
+}
+
+pub fn to_html(nodes: &[Node]) -> String {
+    let mut html = String::new();
+
+    write_html(nodes, &mut html);
+
+    sanitizer(nodes).clean(&html).to_string()
+}
+
+/// Markdown can contain blocks, like tables and lists, which the rest of markup can't.
+fn sanitizer(nodes: &[Node]) -> &'static Builder<'static> {
+    if nodes.iter().any(|node| matches!(node, Node::Markdown(_))) {
+        &MARKDOWN_SANITIZER
+    } else {
+        &SANITIZER
+    }
+}
+
+pub fn to_text(nodes: &[Node]) -> String {
+    let mut text = String::new();
+
+    write_text(nodes, &mut text);
+
+    text
+}
+
+/// Escape text so that it's shown exactly as it is when rendered as markup.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if ESCAPABLE.contains(&c) {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// The Markdown in a message, if it starts with the switch as a word of its own.
+fn markdown_source(source: &str) -> Option<&str> {
+    let rest = source.strip_prefix(MARKDOWN_SWITCH)?;
+
+    match rest.chars().next() {
+        None => Some(rest),
+        Some(c) if c.is_whitespace() => Some(&rest[c.len_utf8()..]),
+        Some(_) => None,
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+}
+
+impl Parser {
+    /// Parse `chars[start..end]`, inside `depth` styles.
+    fn inline(&self, start: usize, end: usize, depth: usize) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        let mut text = String::new();
+        let mut i = start;
+
+        while i < end {
+            let c = self.chars[i];
+
+            if self.is_escape(i, end) {
+                text.push(self.chars[i + 1]);
+                i += 2;
+
+                continue;
+            }
+
+            if c == '\n' {
+                flush(&mut text, &mut nodes);
+                nodes.push(Node::Break);
+                i += 1;
+
+                continue;
+            }
+
+            if depth < MAX_DEPTH && let Some((node, next)) = self.styled(i, end, depth) {
+                flush(&mut text, &mut nodes);
+                nodes.push(node);
+                i = next;
+
+                continue;
+            }
+
+            text.push(c);
+            i += 1;
+        }
+
+        flush(&mut text, &mut nodes);
+
+        nodes
+    }
+
+    /// The styled span or link that starts at `i`, and where it ends.
+    fn styled(&self, i: usize, end: usize, depth: usize) -> Option<(Node, usize)> {
+        let style: fn(Vec<Node>) -> Node = match self.chars[i] {
+            '(' => return self.link(i, end, depth),
+            '<' => {
+                let close = self.closing(i, end, '>', false)?;
+
+                return Some((Node::Emphasis(self.inline(i + 1, close, depth + 1)), close + 1));
+            }
+            '*' => Node::Shout,
+            '_' => Node::Underline,
+            '-' => Node::Strike,
+            _ => return None,
+        };
+
+        let close = self.closing(i, end, self.chars[i], true)?;
+
+        Some((style(self.inline(i + 1, close, depth + 1)), close + 1))
+    }
+
+    /// A link starting at `i`, if the display text is followed straight away by a target with a scheme we allow.
+    fn link(&self, i: usize, end: usize, depth: usize) -> Option<(Node, usize)> {
+        let display_end = self.closing(i, end, ')', false)?;
+        let target_start = display_end + 1;
+
+        if self.chars.get(target_start) != Some(&'<') || target_start >= end {
+            return None;
+        }
+
+        // Targets are taken as they are, up to the first `>`, and can't contain spaces
+        let target_end = (target_start + 1..end)
+            .take_while(|j| !self.chars[*j].is_whitespace())
+            .find(|j| self.chars[*j] == '>')?;
+
+        let target = self.chars[target_start + 1..target_end].iter().collect::<String>();
+        let href = link_href(&target)?;
+
+        Some((Node::Link { display: self.inline(i + 1, display_end, depth + 1), href }, target_end + 1))
+    }
+
+    /// Where the span opened at `open` closes with `delimiter`, on the same line.
+    ///
+    /// Spans can't be empty, and can't start or end with whitespace. Word spans (`*`, `_` and `-`) also have to
+    /// open at the start of a word and close at the end of one.
+    fn closing(&self, open: usize, end: usize, delimiter: char, word: bool) -> Option<usize> {
+        let first = *self.chars.get(open + 1).filter(|_| open + 1 < end)?;
+
+        if first.is_whitespace() || (word && first == delimiter) {
+            return None;
+        }
+
+        if word && open > 0 && self.chars[open - 1].is_alphanumeric() {
+            return None;
+        }
+
+        let mut j = open + 2;
+
+        while j < end {
+            let c = self.chars[j];
+
+            if c == '\n' {
+                return None;
+            }
+
+            if self.is_escape(j, end) {
+                j += 2;
+
+                continue;
+            }
+
+            let closes = c == delimiter
+                && !self.chars[j - 1].is_whitespace()
+                && (!word || j + 1 >= end || !self.chars[j + 1].is_alphanumeric());
+
+            if closes {
+                return Some(j);
+            }
+
+            j += 1;
+        }
+
+        None
+    }
+
+    fn is_escape(&self, i: usize, end: usize) -> bool {
+        self.chars[i] == '\\' && i + 1 < end && ESCAPABLE.contains(&self.chars[i + 1])
+    }
+}
+
+fn flush(text: &mut String, nodes: &mut Vec<Node>) {
+    if !text.is_empty() {
+        nodes.push(Node::Text(std::mem::take(text)));
+    }
+}
+
+/// Where a link goes, with `https://` added if it has no scheme, as long as it starts with a host name.
+/// Links with any other scheme aren't links.
+fn link_href(target: &str) -> Option<String> {
+    if let Some((scheme, _)) = target.split_once(':')
+        && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    {
+        return ["http", "https", "mailto"]
+            .contains(&scheme.to_ascii_lowercase().as_str())
+            .then(|| target.to_string());
+    }
+
+    let host = target.split('/').next()?;
+
+    if !target.starts_with(|c: char| c.is_alphanumeric()) || !host.contains('.') {
+        return None;
+    }
+
+    Some(format!("https://{target}"))
+}
+
+fn write_html(nodes: &[Node], html: &mut String) {
+    for node in nodes {
+        let (tag, children) = match node {
+            Node::Text(text) => {
+                escape_html(text, html);
+
+                continue;
+            }
+            Node::Break => {
+                html.push_str("<br>");
+
+                continue;
+            }
+            Node::Link { display, href } => {
+                html.push_str("<a href=\"");
+                escape_html(href, html);
+                html.push_str("\">");
+                write_html(display, html);
+                html.push_str("</a>");
+
+                continue;
+            }
+            Node::Markdown(source) => {
+                // HTML written in the Markdown is shown as it was written, rather than left to the sanitiser to remove
+                let events = markdown_events(source).map(|event| match event {
+                    Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+                    event => event,
+                });
+
+                pulldown_cmark::html::push_html(html, events);
+
+                continue;
+            }
+            Node::Emphasis(children) => ("em", children),
+            Node::Shout(children) => ("strong", children),
+            Node::Underline(children) => ("u", children),
+            Node::Strike(children) => ("s", children),
+        };
+
+        html.push_str(&format!("<{tag}>"));
+        write_html(children, html);
+        html.push_str(&format!("</{tag}>"));
+    }
+}
+
+fn write_text(nodes: &[Node], text: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(content) => text.push_str(content),
+            Node::Markdown(source) => write_markdown_text(source, text),
+            Node::Break => text.push('\n'),
+            Node::Emphasis(children) | Node::Shout(children) | Node::Underline(children) | Node::Strike(children) => write_text(children, text),
+            Node::Link { display, href } => {
+                let start = text.len();
+
+                write_text(display, text);
+
+                if text[start..] != *href {
+                    text.push_str(&format!(" ({href})"));
+                }
+            }
+        }
+    }
+}
+
+fn markdown_events(source: &str) -> MarkdownParser<'_> {
+    MarkdownParser::new_ext(source, Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH)
+}
+
+/// The text of a Markdown message, with a line for each block and tabs between table cells.
+fn write_markdown_text(source: &str, text: &mut String) {
+    let start = text.len();
+
+    for event in markdown_events(source) {
+        match event {
+            Event::Text(content) | Event::Code(content) | Event::Html(content) | Event::InlineHtml(content) => text.push_str(&content),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::End(TagEnd::TableCell) => text.push('\t'),
+            Event::End(TagEnd::Paragraph | TagEnd::Heading(_) | TagEnd::Item | TagEnd::TableHead | TagEnd::TableRow | TagEnd::CodeBlock) => {
+                let line = text.trim_end_matches(['\t', '\n']).len().max(start);
+
+                text.truncate(line);
+                text.push('\n');
+            }
+            _ => {}
+        }
+    }
+
+    text.truncate(text.trim_end().len().max(start));
+}
+
+fn escape_html(text: &str, html: &mut String) {
+    for c in text.chars() {
+        match c {
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    fn text(content: &str) -> Node {
+        Node::Text(content.to_string())
+    }
+
+    /// Random messages, mostly made of markup and the characters around it
+    fn random_messages(seed: u64, count: usize) -> Vec<String> {
+        const PIECES: [&str; 24] = [
+            "<", ">", "*", "_", "-", "(", ")", "\\", "%MD", " ", "\n", "a", "word", "é", "🙂", "&", "\"", "'",
+            "<script>", "javascript:", "https://kolloquy.com", "<a href=x>", "\u{0}", "mailto:a@b.c",
+        ];
+
+        let mut random = ChaCha8Rng::seed_from_u64(seed);
+
+        (0..count).map(|_| {
+            let length = random.random_range(0..40);
+
+            (0..length).map(|_| PIECES[random.random_range(0..PIECES.len())]).collect()
+        }).collect()
+    }
+
+    #[test]
+    fn parses_each_style() {
+        assert_eq!(parse("<hi> *there*"), vec![Node::Emphasis(vec![text("hi")]), text(" "), Node::Shout(vec![text("there")])]);
+        assert_eq!(parse("_under_ -struck-"), vec![Node::Underline(vec![text("under")]), text(" "), Node::Strike(vec![text("struck")])]);
+        assert_eq!(parse("*<both>*"), vec![Node::Shout(vec![Node::Emphasis(vec![text("both")])])]);
+        assert_eq!(parse("a\nb"), vec![text("a"), Node::Break, text("b")]);
+        assert_eq!(parse("%MD # Title"), vec![Node::Markdown("# Title".to_string())]);
+        assert_eq!(parse("%MDX"), vec![text("%MDX")]);
+    }
+
+    #[test]
+    fn parses_links() {
+        assert_eq!(parse("(Kolloquy)<https://kolloquy.com>"), vec![Node::Link {
+            display: vec![text("Kolloquy")],
+            href: "https://kolloquy.com".to_string(),
+        }]);
+
+        assert_eq!(parse("(*me*)<kolloquy.com/user/me>"), vec![Node::Link {
+            display: vec![Node::Shout(vec![text("me")])],
+            href: "https://kolloquy.com/user/me".to_string(),
+        }]);
+
+        // Other schemes, and parentheses that aren't followed by a target, aren't links
+        assert_eq!(parse("(x)<javascript:alert(1)>"), vec![text("(x)"), Node::Emphasis(vec![text("javascript:alert(1)")])]);
+        assert_eq!(parse("(an aside)"), vec![text("(an aside)")]);
+    }
+
+    #[test]
+    fn leaves_ordinary_text_alone() {
+        for source in ["snake_case_name", "well-known", "2 * 3 * 4", "a - b - c", "1 < 2 > 0", "<3", "**", "---", "a_", "x)<y"] {
+            assert_eq!(parse(source), vec![text(source)], "{source}");
+        }
+    }
+
+    #[test]
+    fn escapes_markup() {
+        assert_eq!(parse(r"\*not shouting\*"), vec![text("*not shouting*")]);
+        assert_eq!(parse(r"\%MD"), vec![text("%MD")]);
+        assert_eq!(parse(r"a \ b"), vec![text(r"a \ b")]);
+        assert_eq!(parse(r"*a \* b*"), vec![Node::Shout(vec![text("a * b")])]);
+    }
+
+    #[test]
+    fn renders_html_and_text() {
+        let source = "<hi> & (*you*)<kolloquy.com>";
+
+        assert_eq!(render_html(source), r#"<em>hi</em> &amp; <a href="https://kolloquy.com" rel="noopener noreferrer nofollow"><strong>you</strong></a>"#);
+        assert_eq!(render_text(source), "hi & you (https://kolloquy.com)");
+        assert_eq!(render_html("<script>alert(1)</script>"), "<em>script</em>alert(1)<em>/script</em>");
+    }
+
+    #[test]
+    fn random_messages_render_safely() {
+        for source in random_messages(22, 5000) {
+            let html = render_html(&source);
+
+            // Nothing gets through that the sanitiser would change, and no tags but our own
+            assert_eq!(sanitizer(&parse(&source)).clean(&html).to_string(), html, "{source:?}");
+            assert!(!html.contains("<script") && !html.contains("href=\"javascript"), "{source:?} rendered {html:?}");
+
+            render_text(&source);
+        }
+    }
+
+    #[test]
+    fn escaped_messages_render_as_they_are() {
+        for source in random_messages(23, 5000) {
+            let escaped = escape(&source);
+
+            assert_eq!(render_text(&escaped), source, "{source:?}");
+            assert!(parse(&escaped).iter().all(|node| matches!(node, Node::Text(_) | Node::Break)), "{source:?}");
+        }
+    }
+
+    #[test]
+    fn renders_markdown() {
+        let source = "%MD\n# Plan\n\n| Day | What |\n|-----|------|\n| Mon | *rest* |\n\n1. one\n2. ~~two~~\n\n```\nlet x = 1 < 2;\n```";
+        let html = render_html(source);
+
+        for expected in ["<h1>Plan</h1>", "<table>", "<th>Day</th>", "<td><em>rest</em></td>", "<ol>", "<li><del>two</del></li>", "<pre><code>let x = 1 &lt; 2;\n</code></pre>"] {
+            assert!(html.contains(expected), "{expected} not in {html}");
+        }
+
+        assert_eq!(render_text(source), "Plan\nDay\tWhat\nMon\trest\none\ntwo\nlet x = 1 < 2;");
+    }
+
+    #[test]
+    fn markdown_is_sanitised() {
+        let html = render_html("%MD [x](javascript:alert(1)) ![tracker](https://example.com/a.png) <script>alert(1)</script>");
+
+        assert_eq!(html, "<p><a rel=\"noopener noreferrer nofollow\">x</a>  &lt;script&gt;alert(1)&lt;/script&gt;</p>\n");
+
+        // Kolloquy Markup is only markup outside of Markdown
+        assert_eq!(render_html("%MD *hi* <hi>"), "<p><em>hi</em> &lt;hi&gt;</p>\n");
+    }
+
+    #[test]
+    fn long_unclosed_messages_render() {
+        let source = "<".repeat(5000);
+
+        assert_eq!(render_text(&source), source);
+        render_html(&"*_-<(".repeat(1000));
+    }
+}
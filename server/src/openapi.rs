@@ -0,0 +1,125 @@
+//! The OpenAPI document for the HTTP API, built from the handlers and their request and response types.
+
+use crate::error::ApiError;
+use poem::http::StatusCode;
+use poem::{handler, IntoResponse, Response};
+use serde::Serialize;
+use std::sync::LazyLock;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+/// The envelope every error is sent in.
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// Always `false`
+    pub success: bool,
+    pub error: ErrorDetails,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ErrorDetails {
+    pub code: u32,
+    pub message: String,
+    pub details: Option<String>,
+}
+
+/// A response with nothing to say besides that it worked.
+#[derive(Serialize, ToSchema)]
+pub struct SuccessResponse {
+    /// Always `true`
+    pub success: bool,
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Kolloquy", description = "The Kolloquy HTTP API. Pages, the chat socket and static files aren't described here; see `api.md`."),
+    paths(
+        crate::register_user,
+        crate::authenticate_user,
+        crate::verify_email,
+        crate::resend_verification,
+        crate::forgot_password,
+        crate::reset_password,
+        crate::create_chat,
+        crate::chat_messages,
+        crate::edit_message,
+        crate::delete_message,
+        crate::message_history,
+        crate::upload_attachment,
+        crate::get_attachment,
+        crate::get_thumbnail,
+        crate::rename_chat,
+        crate::delete_chat,
+        crate::add_participant,
+        crate::remove_participant,
+        crate::set_role,
+        crate::leave_chat,
+        crate::list_sessions,
+        crate::revoke_session,
+        crate::list_tokens,
+        crate::create_token,
+        crate::revoke_token,
+    ),
+    components(schemas(ErrorResponse, ErrorDetails, SuccessResponse)),
+    nest((path = "/api/v1", api = crate::api::ApiV1Doc)),
+    modifiers(&SecuritySchemes),
+)]
+pub struct ApiDoc;
+
+/// Sessions use the `SSID` cookie, and `/api/v1` uses personal access tokens.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+
+        components.add_security_scheme("session", SecurityScheme::ApiKey(ApiKey::Cookie(ApiKeyValue::new("SSID"))));
+        components.add_security_scheme("token", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+    }
+}
+
+static OPENAPI_JSON: LazyLock<String> = LazyLock::new(|| ApiDoc::openapi().to_pretty_json().unwrap());
+
+/// The schema a JSON body is expected to match, as shown when it doesn't.
+pub fn expected_schema<T: ToSchema>() -> String {
+    serde_json::to_string_pretty(&T::schema()).unwrap()
+}
+
+/// The error for a JSON body that doesn't match `T`, describing its schema.
+pub fn invalid_body<T: ToSchema>(body: &str) -> ApiError {
+    ApiError::InvalidBody(format!("Expected JSON to match schema:\n{}\n\nGot JSON:\n{body}", expected_schema::<T>()))
+}
+
+#[handler]
+pub async fn openapi_json() -> Response {
+    Response::builder()
+        .body(OPENAPI_JSON.as_str())
+        .set_content_type("application/json")
+        .with_status(StatusCode::OK)
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::user::RegisterBody;
+
+    #[test]
+    fn describes_cookie_and_token_routes() {
+        let spec: serde_json::Value = serde_json::from_str(&OPENAPI_JSON).unwrap();
+
+        assert!(spec["paths"]["/register"]["post"].is_object());
+        assert!(spec["paths"]["/api/v1/chats/{id}/messages"]["post"].is_object());
+        assert!(spec["components"]["schemas"]["RegisterBody"].is_object());
+        assert!(spec["components"]["securitySchemes"]["token"].is_object());
+    }
+
+    #[test]
+    fn schema_errors_describe_the_body_type() {
+        let schema = expected_schema::<RegisterBody>();
+
+        for field in ["email", "handle", "age", "password"] {
+            assert!(schema.contains(&format!("\"{field}\"")), "{field} is missing from {schema}");
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use subtle::ConstantTimeEq;
+
+/// How a password sent by a client compares to the one stored for a user.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordCheck {
+    Correct,
+
+    /// Correct, but stored as the client's hash rather than an Argon2 hash, so it should be rehashed
+    CorrectLegacy,
+
+    Incorrect,
+}
+
+impl PasswordCheck {
+    pub fn is_correct(&self) -> bool {
+        !matches!(self, Self::Incorrect)
+    }
+}
+
+/// Hash the password hash sent by the client (see `PASSWORD_REGEX`) with Argon2id and a random salt.
+///
+/// The result is a PHC string, which holds the salt and parameters along with the hash.
+/// This is slow on purpose, so run it with [`tokio::task::spawn_blocking`].
+pub fn hash_password(client_hash: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Argon2::default()
+        .hash_password(client_hash.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// Check the password hash sent by the client against the one stored for a user, in constant time.
+///
+/// Users who registered before passwords were hashed on the server have the client's hash stored as is.
+pub fn verify_password(stored: &str, client_hash: &str) -> PasswordCheck {
+    match PasswordHash::new(stored) {
+        Ok(hash) => match Argon2::default().verify_password(client_hash.as_bytes(), &hash) {
+            Ok(()) => PasswordCheck::Correct,
+            Err(_) => PasswordCheck::Incorrect,
+        },
+
+        Err(_) if bool::from(stored.as_bytes().ct_eq(client_hash.as_bytes())) => PasswordCheck::CorrectLegacy,
+        Err(_) => PasswordCheck::Incorrect,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLIENT_HASH: &str = "n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=";
+
+    #[test]
+    fn hashes_are_salted_and_verify() {
+        let first = hash_password(CLIENT_HASH);
+        let second = hash_password(CLIENT_HASH);
+
+        assert!(first.starts_with("$argon2id$"));
+        assert_ne!(first, second);
+
+        assert_eq!(verify_password(&first, CLIENT_HASH), PasswordCheck::Correct);
+        assert_eq!(verify_password(&first, "LCa0a2j/xo/5m0U8HTBBNBNCLXBkg7+g+YpeiGJm564="), PasswordCheck::Incorrect);
+    }
+
+    #[test]
+    fn legacy_hashes_need_upgrading() {
+        assert_eq!(verify_password(CLIENT_HASH, CLIENT_HASH), PasswordCheck::CorrectLegacy);
+        assert_eq!(verify_password(CLIENT_HASH, "LCa0a2j/xo/5m0U8HTBBNBNCLXBkg7+g+YpeiGJm564="), PasswordCheck::Incorrect);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::attachment::Attachment;
+use crate::chat::SocketChatAuthor;
+use crate::error::ApiError;
+use crate::role::Permission;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// The version of the socket protocol this server speaks.
+///
+/// Every frame carries it as `v`, and frames from other versions are rejected.
+pub const PROTOCOL_VERSION: u64 = 1;
+
+/// A frame sent by a client over `/chatws`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientFrame {
+    /// Send a message to a chat
+    Put {
+        chat: String,
+        content: String,
+        /// The ids of files the client uploaded to the chat to send with the message
+        #[serde(default)]
+        attachments: Vec<String>,
+        /// Chosen by the client to match this message to its [`ServerFrame::Ack`] or [`ServerFrame::Error`]
+        client_id: Option<String>,
+    },
+
+    /// Push a new revision of one of the client's own messages
+    Edit {
+        chat: String,
+        id: u64,
+        content: String,
+        client_id: Option<String>,
+    },
+
+    /// Remove one of the client's own messages, or anyone's if the client's role allows redacting
+    Delete {
+        chat: String,
+        id: u64,
+        client_id: Option<String>,
+    },
+
+    /// Keep the socket open
+    Renew,
+}
+
+/// A frame sent by the server over `/chatws`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerFrame {
+    /// A message was sent to one of the socket's chats
+    Message {
+        chat: String,
+        id: u64,
+        sent: DateTime<Utc>,
+        author: SocketChatAuthor,
+        /// The message as HTML, rendered from Kolloquy Markup and sanitised before it was stored
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<Attachment>,
+    },
+
+    /// A message in one of the socket's chats was edited
+    Edited {
+        chat: String,
+        id: u64,
+        /// The new revision, rendered and sanitised like [`ServerFrame::Message`]'s
+        content: String,
+        edited: DateTime<Utc>,
+    },
+
+    /// A message in one of the socket's chats was removed, leaving a tombstone
+    Removed {
+        chat: String,
+        id: u64,
+        /// Whether it was removed by someone other than its author
+        redacted: bool,
+    },
+
+    /// The client's message, edit or removal was stored
+    Ack {
+        client_id: Option<String>,
+        chat: String,
+        id: u64,
+        sent: DateTime<Utc>,
+        /// The Kolloquy Markup a sent or edited message now reads as, which only its author is sent so they can edit it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<String>,
+    },
+
+    /// The socket's user was added to a chat, whose messages are now sent to the socket
+    Joined {
+        chat: String,
+    },
+
+    /// The socket's user left or was removed from a chat, whose messages are no longer sent to the socket
+    Left {
+        chat: String,
+    },
+
+    /// Reply to [`ClientFrame::Renew`]
+    Renewed,
+
+    /// Something the client sent could not be handled.
+    ///
+    /// Codes are the same as those returned by the HTTP handlers.
+    Error {
+        client_id: Option<String>,
+        code: u32,
+        message: String,
+        details: Option<String>,
+    },
+}
+
+impl ClientFrame {
+    /// The chat this frame acts on, if any
+    pub fn chat(&self) -> Option<&str> {
+        match self {
+            Self::Put { chat, .. } | Self::Edit { chat, .. } | Self::Delete { chat, .. } => Some(chat),
+            Self::Renew => None,
+        }
+    }
+
+    /// What the client's role has to allow in [`ClientFrame::chat`] for this frame to be acted on.
+    ///
+    /// Anyone in a chat can remove their own messages; removing someone else's also needs [`Permission::Redact`].
+    pub fn permission(&self) -> Permission {
+        match self {
+            Self::Put { .. } | Self::Edit { .. } => Permission::Send,
+            Self::Delete { .. } | Self::Renew => Permission::Read,
+        }
+    }
+
+    pub fn client_id(&self) -> Option<String> {
+        match self {
+            Self::Put { client_id, .. } | Self::Edit { client_id, .. } | Self::Delete { client_id, .. } => client_id.clone(),
+            Self::Renew => None,
+        }
+    }
+}
+
+/// A frame, along with the protocol version.
+#[derive(Serialize, Debug)]
+pub struct Versioned<'a, T> {
+    pub v: u64,
+    #[serde(flatten)]
+    pub frame: &'a T,
+}
+
+impl ServerFrame {
+    pub fn error(client_id: Option<String>, error: ApiError) -> Self {
+        error.log();
+
+        Self::Error {
+            client_id,
+            code: error.code(),
+            message: error.message().to_string(),
+            details: error.details(),
+        }
+    }
+
+    /// Serialise this frame with the protocol version.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&Versioned { v: PROTOCOL_VERSION, frame: self }).unwrap()
+    }
+}
+
+/// Parse a client's frame, or produce the error frame to reply with.
+pub fn parse_client_frame(json: &str) -> Result<ClientFrame, Box<ServerFrame>> {
+    let invalid = |client_id: Option<String>, details: String| Box::new(ServerFrame::error(client_id, ApiError::InvalidBody(details)));
+
+    let value: Value = serde_json::from_str(json).map_err(|e| invalid(None, e.to_string()))?;
+
+    // Try to keep the client id, so the client knows which message failed
+    let client_id = value.get("client_id").and_then(Value::as_str).map(str::to_string);
+
+    match value.get("v").and_then(Value::as_u64) {
+        Some(PROTOCOL_VERSION) => (),
+        Some(version) => {
+            let details = format!("Expected version {PROTOCOL_VERSION}, got {version}.");
+
+            return Err(Box::new(ServerFrame::error(client_id, ApiError::UnsupportedProtocol(details))));
+        }
+        None => return Err(invalid(client_id, "Frame is missing its protocol version (v).".to_string())),
+    }
+
+    serde_json::from_value(value).map_err(|e| invalid(client_id, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_frames() {
+        assert_eq!(parse_client_frame(r#"{"v":1,"type":"renew"}"#), Ok(ClientFrame::Renew));
+        assert_eq!(parse_client_frame(r#"{"v":1,"type":"put","chat":"ab12cde","content":"hi","client_id":"7"}"#), Ok(ClientFrame::Put {
+            chat: "ab12cde".to_string(),
+            content: "hi".to_string(),
+            attachments: Vec::new(),
+            client_id: Some("7".to_string()),
+        }));
+        assert_eq!(parse_client_frame(r#"{"v":1,"type":"edit","chat":"ab12cde","id":3,"content":"hello"}"#), Ok(ClientFrame::Edit {
+            chat: "ab12cde".to_string(),
+            id: 3,
+            content: "hello".to_string(),
+            client_id: None,
+        }));
+    }
+
+    #[test]
+    fn only_sending_needs_more_than_read() {
+        let delete = parse_client_frame(r#"{"v":1,"type":"delete","chat":"ab12cde","id":3}"#).unwrap();
+        let edit = parse_client_frame(r#"{"v":1,"type":"edit","chat":"ab12cde","id":3,"content":"hello"}"#).unwrap();
+
+        assert_eq!(delete.permission(), Permission::Read);
+        assert_eq!(edit.permission(), Permission::Send);
+    }
+
+    #[test]
+    fn rejects_bad_frames() {
+        assert!(matches!(parse_client_frame("not json"), Err(frame) if matches!(*frame, ServerFrame::Error { code: 200, .. })));
+        assert!(matches!(parse_client_frame(r#"{"type":"renew"}"#), Err(frame) if matches!(*frame, ServerFrame::Error { code: 200, .. })));
+        assert!(matches!(parse_client_frame(r#"{"v":2,"type":"renew"}"#), Err(frame) if matches!(*frame, ServerFrame::Error { code: 208, .. })));
+
+        let Err(frame) = parse_client_frame(r#"{"v":1,"type":"shout","client_id":"9"}"#) else {
+            panic!("unknown frame types should be rejected");
+        };
+        let ServerFrame::Error { code, client_id, .. } = *frame else {
+            panic!("unknown frame types should be rejected");
+        };
+
+        assert_eq!(code, 200);
+        assert_eq!(client_id.as_deref(), Some("9"));
+    }
+
+    #[test]
+    fn server_frames_carry_version() {
+        let json: Value = serde_json::from_str(&ServerFrame::Renewed.to_json()).unwrap();
+
+        assert_eq!(json, serde_json::json!({ "v": 1, "type": "renewed" }));
+    }
+}
@@ -0,0 +1,154 @@
+use crate::error::ApiError;
+use chrono::{DateTime, TimeDelta, Utc};
+use poem::{Endpoint, IntoResponse, Middleware, Request, Response};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, LazyLock, Mutex};
+
+/// How many keys a limiter tracks before it clears out the ones that have gone quiet.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Limits requests to `/auth` and `/register` from each IP address, from `KOLLOQUY_IP_RATE_LIMIT` (per minute).
+pub static LOGIN_IP_LIMITER: LazyLock<RateLimiter> = LazyLock::new(|| RateLimiter::from_env("KOLLOQUY_IP_RATE_LIMIT", 20, TimeDelta::minutes(1)));
+
+/// Limits requests to `/auth` and `/register` for each email address, from `KOLLOQUY_EMAIL_RATE_LIMIT` (per minute).
+pub static LOGIN_EMAIL_LIMITER: LazyLock<RateLimiter> = LazyLock::new(|| RateLimiter::from_env("KOLLOQUY_EMAIL_RATE_LIMIT", 5, TimeDelta::minutes(1)));
+
+/// Whether requests come through a proxy that sets `CF-Connecting-IP`, from `KOLLOQUY_TRUST_PROXY`.
+///
+/// Anyone can send the header, so it's only believed when the server can't be reached except through Cloudflare.
+pub static TRUST_PROXY: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_TRUST_PROXY").is_ok_and(|trust| trust == "true" || trust == "1")
+});
+
+/// A sliding window limit on how often something can happen for each key.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: usize,
+    window: TimeDelta,
+    hits: Arc<Mutex<HashMap<String, VecDeque<DateTime<Utc>>>>>,
+}
+
+impl RateLimiter {
+    /// Allow `limit` hits per window, which has to be at least one.
+    pub fn new(limit: usize, window: TimeDelta) -> Self {
+        assert!(limit > 0, "A rate limit must allow at least one hit.");
+
+        Self {
+            limit,
+            window,
+            hits: Default::default(),
+        }
+    }
+
+    /// Allow `limit` hits per window, unless overridden by the environment variable `var`.
+    ///
+    /// A limit of zero would refuse everything, so it's ignored like any other invalid value.
+    pub fn from_env(var: &str, limit: usize, window: TimeDelta) -> Self {
+        let limit = std::env::var(var).ok()
+            .and_then(|limit| limit.parse().ok())
+            .filter(|limit| *limit > 0)
+            .unwrap_or(limit);
+
+        Self::new(limit, window)
+    }
+
+    /// Record a hit for `key`, or return how long until it's allowed again if it's over the limit.
+    pub fn check(&self, key: &str) -> Result<(), TimeDelta> {
+        self.check_at(key, Utc::now())
+    }
+
+    fn check_at(&self, key: &str, now: DateTime<Utc>) -> Result<(), TimeDelta> {
+        let mut hits = self.hits.lock().unwrap();
+
+        if hits.len() > PRUNE_THRESHOLD {
+            hits.retain(|_, times| times.back().is_some_and(|last| now - *last < self.window));
+        }
+
+        let times = hits.entry(key.to_string()).or_default();
+
+        while times.front().is_some_and(|first| now - *first >= self.window) {
+            times.pop_front();
+        }
+
+        if times.len() >= self.limit {
+            return Err(times[0] + self.window - now);
+        }
+
+        times.push_back(now);
+
+        Ok(())
+    }
+}
+
+/// The address a request came from, preferring the one Cloudflare saw if [`TRUST_PROXY`] is set.
+pub fn client_ip(req: &Request) -> String {
+    client_ip_through(req, *TRUST_PROXY)
+}
+
+fn client_ip_through(req: &Request, trust_proxy: bool) -> String {
+    req.header("CF-Connecting-IP")
+        .filter(|_| trust_proxy)
+        .map(str::to_string)
+        .or_else(|| req.remote_addr().as_socket_addr().map(|addr| addr.ip().to_string()))
+        .unwrap_or_default()
+}
+
+/// Rejects requests from IP addresses that are over the limiter's limit.
+pub struct RateLimitMiddleware(pub &'static RateLimiter);
+
+pub struct RateLimitedEndpoint<E: Endpoint> {
+    inner: E,
+    limiter: &'static RateLimiter,
+}
+
+impl<E: Endpoint> Endpoint for RateLimitedEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, req: Request) -> poem::Result<Self::Output> {
+        if let Err(retry_after) = self.limiter.check(&client_ip(&req)) {
+            return Ok(ApiError::TooManyRequests { retry_after }.into_response());
+        }
+
+        self.inner.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimitMiddleware {
+    type Output = RateLimitedEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RateLimitedEndpoint {
+            inner: ep,
+            limiter: self.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_key_within_the_window() {
+        let limiter = RateLimiter::new(2, TimeDelta::minutes(1));
+        let start = Utc::now();
+
+        assert!(limiter.check_at("a", start).is_ok());
+        assert!(limiter.check_at("a", start + TimeDelta::seconds(10)).is_ok());
+        assert_eq!(limiter.check_at("a", start + TimeDelta::seconds(20)), Err(TimeDelta::seconds(40)));
+
+        // Other keys have their own limit
+        assert!(limiter.check_at("b", start + TimeDelta::seconds(20)).is_ok());
+
+        // And hits fall out of the window
+        assert!(limiter.check_at("a", start + TimeDelta::seconds(61)).is_ok());
+    }
+
+    #[test]
+    fn proxy_headers_are_only_trusted_when_configured() {
+        let req = Request::builder().header("CF-Connecting-IP", "203.0.113.7").finish();
+
+        assert_eq!(client_ip_through(&req, true), "203.0.113.7");
+        assert_eq!(client_ip_through(&req, false), "");
+    }
+}
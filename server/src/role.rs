@@ -0,0 +1,96 @@
+use crate::error::ApiError;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// What someone can do in a chat. Each role can do everything the ones before it can.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Read messages and remove their own, but not send them
+    ReadOnly,
+    /// Send messages, and edit their own
+    Member,
+    /// Redact messages, manage participants below them and rename the chat
+    Admin,
+    /// Delete the chat or give it to someone else. Every chat has one owner
+    Owner,
+}
+
+/// Something a participant can try to do in a chat.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Read messages, see who's a part of the chat and remove their own messages
+    Read,
+    /// Send messages, and edit their own
+    Send,
+    /// Remove anyone's messages
+    Redact,
+    /// Add participants
+    Invite,
+    /// Remove participants and change their roles, as long as they're below the one doing it
+    Manage,
+    /// Rename the chat
+    Edit,
+    /// Delete the chat, or give it to someone else
+    Delete,
+}
+
+impl Role {
+    /// The least a role has to be to have a permission
+    pub fn needed_for(permission: Permission) -> Self {
+        match permission {
+            Permission::Read => Self::ReadOnly,
+            Permission::Send => Self::Member,
+            Permission::Redact | Permission::Invite | Permission::Manage | Permission::Edit => Self::Admin,
+            Permission::Delete => Self::Owner,
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        *self >= Self::needed_for(permission)
+    }
+
+    /// Reject anything this role doesn't allow
+    pub fn require(&self, permission: Permission) -> Result<(), ApiError> {
+        if self.allows(permission) {
+            return Ok(());
+        }
+
+        Err(ApiError::NotAllowed(permission))
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ReadOnly => "read only",
+            Self::Member => "member",
+            Self::Admin => "admin",
+            Self::Owner => "owner",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_include_the_ones_below() {
+        let permissions = [Permission::Read, Permission::Send, Permission::Redact, Permission::Invite, Permission::Manage, Permission::Edit, Permission::Delete];
+        let allowed = |role: Role| permissions.iter().filter(|p| role.allows(**p)).count();
+
+        assert_eq!(allowed(Role::ReadOnly), 1);
+        assert_eq!(allowed(Role::Member), 2);
+        assert_eq!(allowed(Role::Admin), 6);
+        assert_eq!(allowed(Role::Owner), 7);
+
+        assert!(matches!(Role::ReadOnly.require(Permission::Send), Err(ApiError::NotAllowed(Permission::Send))));
+        assert!(Role::Admin.require(Permission::Redact).is_ok());
+    }
+
+    #[test]
+    fn roles_are_stored_by_name() {
+        assert_eq!(serde_json::to_string(&Role::ReadOnly).unwrap(), r#""read_only""#);
+        assert_eq!(serde_json::from_str::<Role>(r#""owner""#).unwrap(), Role::Owner);
+    }
+}
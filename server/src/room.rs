@@ -0,0 +1,443 @@
+use crate::error::ApiError;
+use crate::protocol::ServerFrame;
+use crate::role::{Permission, Role};
+use futures::stream::{self, BoxStream, SelectAll};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use poem::web::websocket::Message;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender, WeakUnboundedSender};
+use tokio::sync::RwLock;
+
+/// How many messages a room holds for subscribers that are falling behind.
+const ROOM_CAPACITY: usize = 100;
+
+/// A broadcast channel for each chat that has sockets connected to it.
+///
+/// Rooms are created when the first socket joins, and removed by [`ChatRooms::prune`] once the last one leaves.
+#[derive(Default, Clone)]
+pub struct ChatRooms {
+    rooms: Arc<RwLock<HashMap<String, Sender<ServerFrame>>>>,
+}
+
+impl ChatRooms {
+    /// Subscribe to a chat's room, creating it if nobody else is in it.
+    pub async fn join(&self, chat: &str) -> Receiver<ServerFrame> {
+        if let Some(room) = self.rooms.read().await.get(chat) {
+            return room.subscribe();
+        }
+
+        self.rooms.write().await
+            .entry(chat.to_string())
+            .or_insert_with(|| broadcast::channel(ROOM_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Send a message to every socket in a chat's room, returning how many sockets it was sent to.
+    pub async fn send(&self, chat: &str, body: ServerFrame) -> usize {
+        self.rooms.read().await
+            .get(chat)
+            .and_then(|room| room.send(body).ok())
+            .unwrap_or(0)
+    }
+
+    /// Remove a chat's room if nobody is subscribed to it anymore.
+    pub async fn prune(&self, chat: &str) {
+        let mut rooms = self.rooms.write().await;
+
+        if rooms.get(chat).is_some_and(|room| room.receiver_count() == 0) {
+            rooms.remove(chat);
+        }
+    }
+}
+
+/// Instructions from the task reading a socket to the task writing to it.
+pub enum SocketCommand {
+    /// Start forwarding a room's messages to the socket
+    Join(String, Receiver<ServerFrame>),
+
+    /// Stop forwarding a room's messages to the socket
+    Leave(String),
+
+    /// Send a message to this socket only
+    Reply(ServerFrame),
+
+    /// Close the socket, because the session it was opened with has ended
+    Close,
+}
+
+/// A socket user's role in each chat it has joined, so frames can be checked without loading the chat.
+///
+/// [`UserSockets`] keeps it up to date as the user joins, leaves or changes role in chats.
+#[derive(Default, Clone)]
+pub struct SocketRoles(Arc<Mutex<HashMap<String, Role>>>);
+
+impl SocketRoles {
+    pub fn new(roles: impl IntoIterator<Item = (String, Role)>) -> Self {
+        Self(Arc::new(Mutex::new(roles.into_iter().collect())))
+    }
+
+    /// The user's role in a chat, if they're a part of it and it allows `permission`
+    pub fn require(&self, chat: &str, permission: Permission) -> Result<Role, ApiError> {
+        let role = self.0.lock().unwrap().get(chat).copied().ok_or(ApiError::NotParticipant)?;
+
+        role.require(permission)?;
+
+        Ok(role)
+    }
+
+    fn set(&self, chat: &str, role: Role) {
+        self.0.lock().unwrap().insert(chat.to_string(), role);
+    }
+
+    fn remove(&self, chat: &str) {
+        self.0.lock().unwrap().remove(chat);
+    }
+}
+
+/// A socket that's open, and the session it was opened with.
+///
+/// Only a weak sender is kept, so the socket closes once its reader stops rather than when it's forgotten here.
+#[derive(Clone)]
+struct OpenSocket {
+    session: String,
+    commands: WeakUnboundedSender<SocketCommand>,
+    roles: SocketRoles,
+}
+
+impl OpenSocket {
+    /// Send a command to the socket, returning whether it's still open to receive it.
+    fn send(&self, command: SocketCommand) -> bool {
+        self.commands.upgrade().is_some_and(|commands| commands.send(command).is_ok())
+    }
+
+    fn is_closed(&self) -> bool {
+        self.commands.upgrade().is_none_or(|commands| commands.is_closed())
+    }
+}
+
+/// The open sockets of each user, so that joining or leaving a chat reaches sockets that were opened before.
+#[derive(Default, Clone)]
+pub struct UserSockets {
+    sockets: Arc<RwLock<HashMap<String, Vec<OpenSocket>>>>,
+}
+
+impl UserSockets {
+    /// Keep track of a socket opened by a user with `session`, until it closes.
+    pub async fn register(&self, user: &str, session: &str, commands: &UnboundedSender<SocketCommand>, roles: SocketRoles) {
+        self.sockets.write().await
+            .entry(user.to_string())
+            .or_default()
+            .push(OpenSocket { session: session.to_string(), commands: commands.downgrade(), roles });
+    }
+
+    /// Subscribe each of a user's sockets to a chat's room with `role`, telling them they were added to it.
+    pub async fn join(&self, rooms: &ChatRooms, user: &str, chat: &str, role: Role) {
+        for socket in self.open(user).await {
+            socket.roles.set(chat, role);
+            // The socket can close after it was found, leaving nobody in a room that was made for it
+            if !socket.send(SocketCommand::Join(chat.to_string(), rooms.join(chat).await)) {
+                rooms.prune(chat).await;
+
+                continue;
+            }
+
+            socket.send(SocketCommand::Reply(ServerFrame::Joined { chat: chat.to_string() }));
+        }
+    }
+
+    /// Unsubscribe each of a user's sockets from a chat's room, telling them they were removed from it.
+    pub async fn leave(&self, user: &str, chat: &str) {
+        for socket in self.open(user).await {
+            socket.roles.remove(chat);
+            socket.send(SocketCommand::Leave(chat.to_string()));
+            socket.send(SocketCommand::Reply(ServerFrame::Left { chat: chat.to_string() }));
+        }
+    }
+
+    /// Change a user's role in a chat on each of their sockets.
+    pub async fn set_role(&self, user: &str, chat: &str, role: Role) {
+        for socket in self.open(user).await {
+            socket.roles.set(chat, role);
+        }
+    }
+
+    /// Close a user's sockets that were opened with `session` once it's revoked, or all of them if `None`.
+    pub async fn close(&self, user: &str, session: Option<&str>) {
+        let open = self.sockets.read().await.get(user).cloned().unwrap_or_default();
+
+        for socket in open.iter().filter(|socket| session.is_none_or(|session| session == socket.session)) {
+            socket.send(SocketCommand::Close);
+        }
+    }
+
+    /// A user's sockets that are still open, forgetting any that have closed.
+    async fn open(&self, user: &str) -> Vec<OpenSocket> {
+        let mut sockets = self.sockets.write().await;
+
+        let Some(open) = sockets.get_mut(user) else {
+            return Vec::new();
+        };
+
+        open.retain(|socket| !socket.is_closed());
+
+        let open = open.clone();
+
+        if open.is_empty() {
+            sockets.remove(user);
+        }
+
+        open
+    }
+}
+
+/// Write room messages, replies and commands to a socket until it's closed or every sender of `commands` is gone.
+///
+/// The rooms it was subscribed to are pruned when it stops.
+pub async fn forward<S>(mut sink: S, mut commands: UnboundedReceiver<SocketCommand>, rooms: ChatRooms)
+where
+    S: Sink<Message> + Unpin,
+{
+    let mut incoming = SelectAll::<RoomStream>::new();
+
+    loop {
+        let msg = tokio::select! {
+            command = commands.recv() => match command {
+                Some(SocketCommand::Join(chat, receiver)) => {
+                    incoming.push(RoomStream::new(chat, receiver));
+
+                    continue;
+                }
+                Some(SocketCommand::Leave(chat)) => {
+                    // Drop the subscription straight away, so the room can be closed if nobody else is in it
+                    incoming = std::mem::take(&mut incoming).into_iter().filter(|room| room.chat != chat).collect();
+                    rooms.prune(&chat).await;
+
+                    continue;
+                }
+                Some(SocketCommand::Reply(msg)) => msg,
+                Some(SocketCommand::Close) | None => {
+                    sink.send(Message::Close(None)).await.ok();
+
+                    break;
+                }
+            },
+            Some(msg) = incoming.next(), if !incoming.is_empty() => msg,
+        };
+
+        if sink.send(Message::Text(msg.to_json())).await.is_err() {
+            break;
+        }
+    }
+
+    // Leave every room, closing any that are now empty
+    let joined = incoming.iter().map(|room| room.chat.clone()).collect::<Vec<_>>();
+
+    drop(incoming);
+
+    for chat in joined {
+        rooms.prune(&chat).await;
+    }
+}
+
+/// A socket's subscription to a chat's room as a stream, skipping any messages missed by falling behind.
+pub struct RoomStream {
+    pub chat: String,
+    inner: BoxStream<'static, ServerFrame>,
+}
+
+impl RoomStream {
+    pub fn new(chat: String, receiver: Receiver<ServerFrame>) -> Self {
+        let inner = Box::pin(stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(body) => return Some((body, receiver)),
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }));
+
+        Self {
+            chat,
+            inner,
+        }
+    }
+}
+
+impl Stream for RoomStream {
+    type Item = ServerFrame;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.inner.poll_next_unpin(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chat::SocketChatAuthor;
+    use chrono::Utc;
+
+    fn body(chat: &str, content: &str) -> ServerFrame {
+        ServerFrame::Message {
+            chat: chat.to_string(),
+            id: 0,
+            sent: Utc::now(),
+            author: SocketChatAuthor {
+                avatar: "".to_string(),
+                id: "xy12abc".to_string(),
+                is_self: false,
+                handle: "xyz".to_string(),
+            },
+            content: content.to_string(),
+            attachments: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_stay_in_their_room() {
+        let rooms = ChatRooms::default();
+        let mut first = rooms.join("ab12cde").await;
+        let mut second = rooms.join("zz99zzz").await;
+
+        assert_eq!(rooms.send("ab12cde", body("ab12cde", "hello")).await, 1);
+        assert_eq!(rooms.send("nobody", body("nobody", "anyone?")).await, 0);
+
+        assert!(matches!(first.recv().await.unwrap(), ServerFrame::Message { content, .. } if content == "hello"));
+        assert!(second.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn open_sockets_join_and_leave() {
+        let rooms = ChatRooms::default();
+        let sockets = UserSockets::default();
+        let (open, mut commands) = tokio::sync::mpsc::unbounded_channel();
+        let (closed, _) = tokio::sync::mpsc::unbounded_channel();
+
+        let roles = SocketRoles::default();
+
+        sockets.register("xy12abc", "session", &open, roles.clone()).await;
+        sockets.register("xy12abc", "session", &closed, SocketRoles::default()).await;
+        sockets.join(&rooms, "xy12abc", "ab12cde", Role::Member).await;
+
+        let Some(SocketCommand::Join(chat, mut receiver)) = commands.recv().await else {
+            panic!("the socket should be told to join the room");
+        };
+
+        assert_eq!(chat, "ab12cde");
+        assert!(matches!(commands.recv().await, Some(SocketCommand::Reply(ServerFrame::Joined { chat })) if chat == "ab12cde"));
+        assert_eq!(sockets.sockets.read().await["xy12abc"].len(), 1);
+
+        rooms.send("ab12cde", body("ab12cde", "welcome")).await;
+
+        assert!(matches!(receiver.recv().await.unwrap(), ServerFrame::Message { content, .. } if content == "welcome"));
+
+        // Role changes reach the socket without it loading the chat
+        assert!(roles.require("ab12cde", Permission::Send).is_ok());
+
+        sockets.set_role("xy12abc", "ab12cde", Role::ReadOnly).await;
+
+        assert!(matches!(roles.require("ab12cde", Permission::Send), Err(ApiError::NotAllowed(Permission::Send))));
+
+        sockets.leave("xy12abc", "ab12cde").await;
+
+        assert!(matches!(roles.require("ab12cde", Permission::Read), Err(ApiError::NotParticipant)));
+
+        assert!(matches!(commands.recv().await, Some(SocketCommand::Leave(chat)) if chat == "ab12cde"));
+        assert!(matches!(commands.recv().await, Some(SocketCommand::Reply(ServerFrame::Left { chat })) if chat == "ab12cde"));
+
+        drop(commands);
+        sockets.leave("xy12abc", "ab12cde").await;
+
+        assert!(sockets.sockets.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn revoked_sessions_close_their_sockets() {
+        let sockets = UserSockets::default();
+        let (revoked, mut revoked_commands) = tokio::sync::mpsc::unbounded_channel();
+        let (other, mut other_commands) = tokio::sync::mpsc::unbounded_channel();
+
+        sockets.register("xy12abc", "revoked", &revoked, SocketRoles::default()).await;
+        sockets.register("xy12abc", "other", &other, SocketRoles::default()).await;
+        sockets.close("xy12abc", Some("revoked")).await;
+
+        assert!(matches!(revoked_commands.try_recv(), Ok(SocketCommand::Close)));
+        assert!(other_commands.try_recv().is_err());
+
+        // Resetting the password signs out everywhere
+        sockets.close("xy12abc", None).await;
+
+        assert!(matches!(other_commands.try_recv(), Ok(SocketCommand::Close)));
+    }
+
+    #[tokio::test]
+    async fn leaving_drops_the_subscription() {
+        let rooms = ChatRooms::default();
+        let mut incoming = futures::stream::SelectAll::new();
+
+        incoming.push(RoomStream::new("ab12cde".to_string(), rooms.join("ab12cde").await));
+        incoming.push(RoomStream::new("zz99zzz".to_string(), rooms.join("zz99zzz").await));
+
+        incoming = incoming.into_iter().filter(|room| room.chat != "ab12cde").collect();
+        rooms.prune("ab12cde").await;
+
+        assert_eq!(rooms.rooms.read().await.keys().collect::<Vec<_>>(), vec!["zz99zzz"]);
+
+        rooms.send("zz99zzz", body("zz99zzz", "still here")).await;
+
+        assert!(matches!(incoming.next().await, Some(ServerFrame::Message { content, .. }) if content == "still here"));
+    }
+
+    #[tokio::test]
+    async fn rooms_are_pruned_when_empty() {
+        let rooms = ChatRooms::default();
+        let first = rooms.join("ab12cde").await;
+        let second = rooms.join("ab12cde").await;
+
+        assert_eq!(rooms.rooms.read().await.len(), 1);
+
+        drop(first);
+        rooms.prune("ab12cde").await;
+
+        assert_eq!(rooms.rooms.read().await.len(), 1);
+
+        drop(second);
+        rooms.prune("ab12cde").await;
+
+        assert_eq!(rooms.rooms.read().await.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn disconnected_sockets_are_closed_and_leave_their_rooms() {
+        let rooms = ChatRooms::default();
+        let sockets = UserSockets::default();
+        let (commands, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (sink, mut written) = futures::channel::mpsc::unbounded::<Message>();
+
+        sockets.register("xy12abc", "session", &commands, SocketRoles::default()).await;
+
+        let writer = tokio::spawn(forward(sink, receiver, rooms.clone()));
+
+        sockets.join(&rooms, "xy12abc", "ab12cde", Role::Member).await;
+
+        assert!(matches!(written.next().await, Some(Message::Text(json)) if json.contains("ab12cde")));
+
+        // The reader holds the only sender, and drops it when the client goes away
+        drop(commands);
+
+        writer.await.unwrap();
+
+        assert!(matches!(written.next().await, Some(Message::Close(None))));
+        assert!(rooms.rooms.read().await.is_empty());
+
+        sockets.leave("xy12abc", "ab12cde").await;
+
+        assert!(sockets.sockets.read().await.is_empty());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::data::{column, datetime_column, DBBackend, DBQuery, FromRow, KolloquyDB, Query, QueryError, Row, KOLLOQUY_DB_BACKEND};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// A signed in browser, identified by the `SSID` cookie.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Session {
+    pub id: String,
+    pub user_id: String,
+    pub created: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    pub user_agent: String,
+    pub ip: String,
+}
+
+impl FromRow for Session {
+    fn from_row(row: &Row) -> Result<Self, QueryError<'static>> {
+        Ok(Self {
+            id: column(row, "id")?,
+            user_id: column(row, "user_id")?,
+            created: datetime_column(row, "created")?,
+            last_seen: datetime_column(row, "last_seen")?,
+            user_agent: column(row, "user_agent")?,
+            ip: column(row, "ip")?,
+        })
+    }
+}
+
+/// Somewhere to keep sessions, so they can outlive the server and be shared between instances.
+///
+/// Sessions only hold the user's id, so the user is always looked up fresh.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    async fn create(&self, session: Session) -> Result<(), QueryError<'static>>;
+
+    async fn get(&self, id: &str) -> Result<Option<Session>, QueryError<'static>>;
+
+    /// Record that the session was just used
+    async fn touch(&self, id: &str, at: DateTime<Utc>) -> Result<(), QueryError<'static>>;
+
+    /// Every session of a user, most recently used first
+    async fn list(&self, user_id: &str) -> Result<Vec<Session>, QueryError<'static>>;
+
+    /// End one of a user's sessions, returning whether it existed
+    async fn revoke(&self, user_id: &str, id: &str) -> Result<bool, QueryError<'static>>;
+
+    /// End every session of a user, e.g. after their password changes
+    async fn revoke_all(&self, user_id: &str) -> Result<(), QueryError<'static>>;
+}
+
+/// Pick a session store with `KOLLOQUY_SESSION_STORE`, which can be `db` (the default) or `memory`.
+pub fn session_store_from_env() -> Arc<dyn SessionStore> {
+    match std::env::var("KOLLOQUY_SESSION_STORE").as_deref() {
+        Ok("memory") => Arc::new(MemorySessionStore::default()),
+        Ok("db") | Err(_) => Arc::new(DBSessionStore::new()),
+        Ok(other) => panic!("Unknown session store '{other}', expected 'db' or 'memory'."),
+    }
+}
+
+/// Keeps sessions in memory, so everyone is signed out when the server restarts.
+#[derive(Default)]
+pub struct MemorySessionStore {
+    sessions: RwLock<HashMap<String, Session>>,
+}
+
+#[async_trait]
+impl SessionStore for MemorySessionStore {
+    async fn create(&self, session: Session) -> Result<(), QueryError<'static>> {
+        self.sessions.write().await.insert(session.id.clone(), session);
+
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Session>, QueryError<'static>> {
+        Ok(self.sessions.read().await.get(id).cloned())
+    }
+
+    async fn touch(&self, id: &str, at: DateTime<Utc>) -> Result<(), QueryError<'static>> {
+        if let Some(session) = self.sessions.write().await.get_mut(id) {
+            session.last_seen = at;
+        }
+
+        Ok(())
+    }
+
+    async fn list(&self, user_id: &str) -> Result<Vec<Session>, QueryError<'static>> {
+        let mut sessions = self.sessions.read().await
+            .values()
+            .filter(|session| session.user_id == user_id)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        sessions.sort_by_key(|session| Reverse(session.last_seen));
+
+        Ok(sessions)
+    }
+
+    async fn revoke(&self, user_id: &str, id: &str) -> Result<bool, QueryError<'static>> {
+        let mut sessions = self.sessions.write().await;
+
+        if sessions.get(id).is_some_and(|session| session.user_id == user_id) {
+            sessions.remove(id);
+
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    async fn revoke_all(&self, user_id: &str) -> Result<(), QueryError<'static>> {
+        self.sessions.write().await.retain(|_, session| session.user_id != user_id);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionQuery {
+    Create(Session),
+    Get(String),
+    Touch(String, DateTime<Utc>),
+    ListForUser(String),
+    /// Delete a session, only if it belongs to the user (user id, session id)
+    Revoke(String, String),
+    RevokeAll(String),
+}
+
+impl Query for SessionQuery {
+    fn has_result(&self) -> bool {
+        false
+    }
+}
+
+impl DBQuery for SessionQuery {
+    type Output = Vec<Session>;
+
+    fn to_sql_query_string(&self) -> (String, Vec<String>) {
+        match self {
+            Self::Create(session) => (
+                "INSERT INTO sessions (id, user_id, created, last_seen, user_agent, ip) VALUES (?, ?, ?, ?, ?, ?);".to_string(),
+                vec![
+                    session.id.clone(),
+                    session.user_id.clone(),
+                    session.created.to_rfc3339(),
+                    session.last_seen.to_rfc3339(),
+                    session.user_agent.clone(),
+                    session.ip.clone(),
+                ]
+            ),
+            Self::Get(id) => ("SELECT * FROM sessions WHERE id = ?;".to_string(), vec![id.clone()]),
+            Self::Touch(id, at) => ("UPDATE sessions SET last_seen = ? WHERE id = ?;".to_string(), vec![at.to_rfc3339(), id.clone()]),
+            Self::ListForUser(user_id) => ("SELECT * FROM sessions WHERE user_id = ? ORDER BY last_seen DESC;".to_string(), vec![user_id.clone()]),
+            Self::Revoke(user_id, id) => ("DELETE FROM sessions WHERE id = ? AND user_id = ?\nRETURNING *;".to_string(), vec![id.clone(), user_id.clone()]),
+            Self::RevokeAll(user_id) => ("DELETE FROM sessions WHERE user_id = ?;".to_string(), vec![user_id.clone()]),
+        }
+    }
+}
+
+/// Keeps sessions in the `sessions` table of a database backend.
+pub struct DBSessionStore {
+    backend: &'static dyn DBBackend,
+}
+
+impl DBSessionStore {
+    /// Use the server's database backend
+    pub fn new() -> Self {
+        Self::with_backend(&**KOLLOQUY_DB_BACKEND)
+    }
+
+    pub fn with_backend(backend: &'static dyn DBBackend) -> Self {
+        Self { backend }
+    }
+
+    fn db(&self) -> KolloquyDB<'static> {
+        KolloquyDB::with_backend(self.backend)
+    }
+}
+
+#[async_trait]
+impl SessionStore for DBSessionStore {
+    async fn create(&self, session: Session) -> Result<(), QueryError<'static>> {
+        self.db().execute(&SessionQuery::Create(session)).await.map(|_| ())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Session>, QueryError<'static>> {
+        Ok(self.db().execute(&SessionQuery::Get(id.to_string())).await?.pop())
+    }
+
+    async fn touch(&self, id: &str, at: DateTime<Utc>) -> Result<(), QueryError<'static>> {
+        self.db().execute(&SessionQuery::Touch(id.to_string(), at)).await.map(|_| ())
+    }
+
+    async fn list(&self, user_id: &str) -> Result<Vec<Session>, QueryError<'static>> {
+        self.db().execute(&SessionQuery::ListForUser(user_id.to_string())).await
+    }
+
+    async fn revoke(&self, user_id: &str, id: &str) -> Result<bool, QueryError<'static>> {
+        Ok(!self.db().execute(&SessionQuery::Revoke(user_id.to_string(), id.to_string())).await?.is_empty())
+    }
+
+    async fn revoke_all(&self, user_id: &str) -> Result<(), QueryError<'static>> {
+        self.db().execute(&SessionQuery::RevokeAll(user_id.to_string())).await.map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::memory_backend;
+    use chrono::TimeDelta;
+
+    fn session(id: &str, user_id: &str) -> Session {
+        Session {
+            id: id.to_string(),
+            user_id: user_id.to_string(),
+            created: Utc::now(),
+            last_seen: Utc::now(),
+            user_agent: "Mozilla/5.0".to_string(),
+            ip: "::1".to_string(),
+        }
+    }
+
+    async fn exercise_store(store: &dyn SessionStore) {
+        store.create(session("first", "xy12abc")).await.unwrap();
+        store.create(session("second", "xy12abc")).await.unwrap();
+        store.create(session("other", "zz99zzz")).await.unwrap();
+
+        store.touch("first", Utc::now() + TimeDelta::minutes(1)).await.unwrap();
+
+        let sessions = store.list("xy12abc").await.unwrap();
+
+        assert_eq!(sessions.iter().map(|s| s.id.as_str()).collect::<Vec<_>>(), vec!["first", "second"]);
+        assert_eq!(store.get("other").await.unwrap().map(|s| s.user_id), Some("zz99zzz".to_string()));
+
+        // Users can only revoke their own sessions
+        assert!(!store.revoke("xy12abc", "other").await.unwrap());
+        assert!(store.revoke("xy12abc", "second").await.unwrap());
+        assert_eq!(store.get("second").await.unwrap(), None);
+
+        assert_eq!(store.list("xy12abc").await.unwrap().len(), 1);
+
+        store.revoke_all("xy12abc").await.unwrap();
+
+        assert!(store.list("xy12abc").await.unwrap().is_empty());
+        assert!(store.get("other").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn memory_session_store() {
+        exercise_store(&MemorySessionStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn db_session_store() {
+        exercise_store(&DBSessionStore::with_backend(memory_backend())).await;
+    }
+}
@@ -0,0 +1,41 @@
+//! Fixtures shared by the tests in every module.
+
+use crate::data::{KolloquyDB, SQLiteBackend};
+use crate::user::User;
+use chrono::{DateTime, Utc};
+
+/// A new in-memory database with the schema applied, which is kept until the tests finish.
+pub fn memory_backend() -> &'static SQLiteBackend {
+    Box::leak(Box::new(SQLiteBackend::open(":memory:").unwrap()))
+}
+
+/// A handle to a new [`memory_backend`].
+pub fn memory_db() -> KolloquyDB<'static> {
+    KolloquyDB::with_backend(memory_backend())
+}
+
+/// A user with `user_id` as their id and handle, who hasn't been stored anywhere.
+pub fn test_user(user_id: &str) -> User {
+    User {
+        email: format!("{user_id}@kolloquy.com"),
+        handle: user_id.to_string(),
+        password: "n4bQgYhMfWWaL+qgxVrQFaO/TxsrC4Is0V1sFbDwCgg=".to_string(),
+        age: 19,
+        country: "NULL".to_string(),
+        preferences: "{}".to_string(),
+        suspended: false,
+        age_verified: false,
+        user_id: user_id.to_string(),
+        phone_number: "".to_string(),
+        joined: DateTime::<Utc>::from_timestamp_millis(0).unwrap(),
+        description: "".to_string(),
+        last_agent: "".to_string(),
+        last_approx_country: "".to_string(),
+        avatar_url: format!("{user_id}.svg.br"),
+        email_verified: false,
+        last_login: DateTime::<Utc>::from_timestamp_millis(0).unwrap(),
+        failed_login_attempts: 0,
+        locked_until: DateTime::<Utc>::from_timestamp_millis(0).unwrap(),
+        timezone: "NULL".to_string(),
+    }
+}
@@ -0,0 +1,123 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeDelta, Utc};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::sync::LazyLock;
+
+/// The key tokens are signed with, from `KOLLOQUY_TOKEN_SECRET`.
+///
+/// Without one, a random key is used, so tokens stop working when the server restarts.
+static TOKEN_SECRET: LazyLock<Vec<u8>> = LazyLock::new(|| match std::env::var("KOLLOQUY_TOKEN_SECRET") {
+    Ok(secret) if !secret.is_empty() => secret.into_bytes(),
+    _ => {
+        eprintln!("KOLLOQUY_TOKEN_SECRET is not set, so emailed links will stop working when the server restarts.");
+
+        let mut secret = vec![0; 32];
+
+        rand::rng().fill_bytes(&mut secret);
+
+        secret
+    }
+});
+
+/// What a token can be used for, so one can't stand in for another.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenPurpose {
+    VerifyEmail,
+    ResetPassword,
+}
+
+impl TokenPurpose {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::VerifyEmail => "verify-email",
+            Self::ResetPassword => "reset-password",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenError {
+    /// Malformed, tampered with, or for something else
+    Invalid,
+    Expired,
+}
+
+/// A signed token for `subject` (a user id) that expires after `ttl`, in the form `subject.expiry.signature`.
+///
+/// The signature also covers `binding`, something about the subject that the token stops working when it changes,
+/// such as the email address being verified.
+pub fn issue_token(purpose: TokenPurpose, subject: &str, binding: &str, ttl: TimeDelta) -> String {
+    let expires = (Utc::now() + ttl).timestamp();
+    let signature = URL_SAFE_NO_PAD.encode(sign(purpose, subject, expires, binding).finalize().into_bytes());
+
+    format!("{subject}.{expires}.{signature}")
+}
+
+/// Who a token is for, so its binding can be looked up before checking it.
+pub fn token_subject(token: &str) -> Option<&str> {
+    token.split('.').next().filter(|subject| !subject.is_empty())
+}
+
+/// Check a token's signature (in constant time) and expiry.
+pub fn check_token(purpose: TokenPurpose, token: &str, binding: &str) -> Result<(), TokenError> {
+    let [subject, expires, signature] = token.split('.').collect::<Vec<_>>()[..] else {
+        return Err(TokenError::Invalid);
+    };
+
+    let expires = expires.parse::<i64>().map_err(|_| TokenError::Invalid)?;
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| TokenError::Invalid)?;
+
+    sign(purpose, subject, expires, binding)
+        .verify_slice(&signature)
+        .map_err(|_| TokenError::Invalid)?;
+
+    match DateTime::<Utc>::from_timestamp(expires, 0) {
+        Some(expires) if expires > Utc::now() => Ok(()),
+        _ => Err(TokenError::Expired),
+    }
+}
+
+fn sign(purpose: TokenPurpose, subject: &str, expires: i64, binding: &str) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&TOKEN_SECRET).unwrap();
+
+    for part in [purpose.as_str(), subject, &expires.to_string(), binding] {
+        // Length prefixed, so parts can't run into each other
+        mac.update(&(part.len() as u64).to_be_bytes());
+        mac.update(part.as_bytes());
+    }
+
+    mac
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_bound_and_expire() {
+        let token = issue_token(TokenPurpose::VerifyEmail, "xy12abc", "someone@example.com", TimeDelta::hours(1));
+
+        assert_eq!(token_subject(&token), Some("xy12abc"));
+        assert_eq!(check_token(TokenPurpose::VerifyEmail, &token, "someone@example.com"), Ok(()));
+        assert_eq!(check_token(TokenPurpose::VerifyEmail, &token, "someone.else@example.com"), Err(TokenError::Invalid));
+
+        let expired = issue_token(TokenPurpose::VerifyEmail, "xy12abc", "someone@example.com", TimeDelta::hours(-1));
+
+        assert_eq!(check_token(TokenPurpose::VerifyEmail, &expired, "someone@example.com"), Err(TokenError::Expired));
+    }
+
+    #[test]
+    fn tampered_tokens_are_invalid() {
+        let token = issue_token(TokenPurpose::VerifyEmail, "xy12abc", "someone@example.com", TimeDelta::hours(1));
+        let other_user = token.replacen("xy12abc", "zz99zzz", 1);
+
+        assert_eq!(check_token(TokenPurpose::VerifyEmail, &other_user, "someone@example.com"), Err(TokenError::Invalid));
+        assert_eq!(check_token(TokenPurpose::VerifyEmail, "xy12abc.nonsense", "someone@example.com"), Err(TokenError::Invalid));
+
+        // Tokens can't be used for something else
+        assert_eq!(check_token(TokenPurpose::ResetPassword, &token, "someone@example.com"), Err(TokenError::Invalid));
+    }
+}
@@ -1,13 +1,73 @@
-use crate::data::{DBQuery, Query, R2Query, R2QueryKind};
+use crate::data::{bool_column, column, datetime_column, DBQuery, FromRow, Query, QueryError, R2Query, R2QueryKind, Row};
 use base64::alphabet::Alphabet;
 use base64::engine::GeneralPurpose;
 use base64::Engine;
-use brotli::BrotliCompress;
-use chrono::{DateTime, Utc};
+use brotli::{BrotliCompress, BrotliDecompress};
+use chrono::{DateTime, TimeDelta, Utc};
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
-use std::rc::Rc;
+use std::sync::LazyLock;
 use svg::Document;
+use utoipa::{IntoParams, ToSchema};
+
+/// When accounts are locked after failed logins, from `KOLLOQUY_LOCKOUT_THRESHOLD` and `KOLLOQUY_LOCKOUT_SECONDS`.
+pub static LOCKOUT_POLICY: LazyLock<LockoutPolicy> = LazyLock::new(|| {
+    let var = |name: &str| std::env::var(name).ok().and_then(|value| value.parse::<i64>().ok());
+    let default = LockoutPolicy::default();
+
+    LockoutPolicy {
+        threshold: var("KOLLOQUY_LOCKOUT_THRESHOLD").map_or(default.threshold, |threshold| threshold as i32),
+        base: var("KOLLOQUY_LOCKOUT_SECONDS").map_or(default.base, TimeDelta::seconds),
+        max: default.max,
+    }
+});
+
+/// How long email verification links work for, from `KOLLOQUY_VERIFY_TTL_HOURS`.
+pub static EMAIL_VERIFICATION_TTL: LazyLock<TimeDelta> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_VERIFY_TTL_HOURS").ok()
+        .and_then(|hours| hours.parse().ok())
+        .map_or(TimeDelta::hours(24), TimeDelta::hours)
+});
+
+/// How long password reset links work for, from `KOLLOQUY_RESET_TTL_MINUTES`.
+pub static PASSWORD_RESET_TTL: LazyLock<TimeDelta> = LazyLock::new(|| {
+    std::env::var("KOLLOQUY_RESET_TTL_MINUTES").ok()
+        .and_then(|minutes| minutes.parse().ok())
+        .map_or(TimeDelta::minutes(30), TimeDelta::minutes)
+});
+
+/// How many failed logins an account can have before it's locked, and for how long.
+///
+/// The first lock lasts `base`, and each failure after that doubles it, up to `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct LockoutPolicy {
+    pub threshold: i32,
+    pub base: TimeDelta,
+    pub max: TimeDelta,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 5,
+            base: TimeDelta::seconds(30),
+            max: TimeDelta::days(1),
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// How long an account with this many failed logins is locked for, if at all.
+    pub fn lock_duration(&self, failed_attempts: i32) -> Option<TimeDelta> {
+        if failed_attempts < self.threshold {
+            return None;
+        }
+
+        let doublings = (failed_attempts - self.threshold).min(30) as u32;
+
+        Some(self.base.checked_mul(2_i32.pow(doublings)).map_or(self.max, |lock| lock.min(self.max)))
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Hash, Eq)]
 pub struct User {
@@ -32,24 +92,99 @@ pub struct User {
     pub failed_login_attempts: i32, // service input
     pub locked_until: DateTime<Utc>, // service input
     pub timezone: String, // inferrable input
-    pub enrolled_chats: Vec<String>
 }
 
-#[derive(Serialize, Deserialize)]
+impl FromRow for User {
+    fn from_row(row: &Row) -> Result<Self, QueryError<'static>> {
+        Ok(Self {
+            email: column(row, "email")?,
+            handle: column(row, "handle")?,
+            password: column(row, "password")?,
+            age: column(row, "age")?,
+            country: column(row, "country")?,
+            preferences: column(row, "preferences")?,
+            suspended: bool_column(row, "suspended")?,
+            age_verified: bool_column(row, "age_verified")?,
+            user_id: column(row, "userid")?,
+            phone_number: column(row, "phone_number")?,
+            joined: datetime_column(row, "joined")?,
+            description: decode_description(column(row, "description")?),
+            last_agent: column(row, "last_agent")?,
+            last_approx_country: column(row, "last_approx_country")?,
+            avatar_url: column(row, "avatar_url")?,
+            email_verified: bool_column(row, "email_verified")?,
+            last_login: datetime_column(row, "last_login")?,
+            failed_login_attempts: column(row, "failed_login_attempts")?,
+            locked_until: datetime_column(row, "locked_until")?,
+            timezone: column(row, "timezone")?,
+        })
+    }
+}
+
+impl User {
+    /// How long until this user can try to log in again, if their account is locked.
+    pub fn locked_for(&self) -> Option<TimeDelta> {
+        let remaining = self.locked_until - Utc::now();
+
+        (remaining > TimeDelta::zero()).then_some(remaining)
+    }
+}
+
+/// Descriptions are stored Brotli compressed and base64 encoded, but older rows may be plain text.
+fn decode_description(stored: String) -> String {
+    let engine = GeneralPurpose::new(&Alphabet::new("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890+/").unwrap(), Default::default());
+
+    let Ok(compressed) = engine.decode(&stored) else {
+        return stored;
+    };
+
+    let mut description = Vec::new();
+
+    match BrotliDecompress(&mut Cursor::new(compressed), &mut description) {
+        Ok(()) => String::from_utf8(description).unwrap_or(stored),
+        Err(_) => stored,
+    }
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct RegisterBody {
     pub email: String,
+    /// 3 to 15 characters, optionally starting with `@`
     pub handle: String,
     pub age: u8,
+    /// The base64 SHA-256 of the password, computed by the client
     pub password: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct AuthenticateBody {
     pub email: String,
+    /// The base64 SHA-256 of the password, computed by the client
     pub password: String,
+    /// Where to send the user after signing in
     pub redirect: String,
 }
 
+#[derive(Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VerifyParams {
+    /// The token from the emailed link
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ForgotPasswordBody {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct ResetPasswordBody {
+    /// The token from the emailed link
+    pub token: String,
+    /// The new password, hashed by the client like when registering
+    pub password: String,
+}
+
 #[derive(Debug, Clone)]
 pub enum UserQuery {
     GetByEmail(String),
@@ -58,25 +193,60 @@ pub enum UserQuery {
     PutToDB(User),
     UploadAvatar(User, Document),
     GetAvatar(User),
-    UpdateRemote(User),
+    /// Clear any failed logins after a successful one
+    RecordLogin(String),
+    /// Count a failed login, returning the user with their new count
+    RecordFailedLogin(String),
+    /// Lock an account until `until`, unless it's already locked for longer
+    LockUntil { id: String, until: DateTime<Utc> },
+    /// Replace a user's password hash, returning nothing if it's no longer `previous`
+    SetPassword { id: String, previous: String, hash: String },
+    /// Replace a user's password hash and unlock their account, returning nothing if the hash is no longer `previous`
+    ResetPassword { id: String, previous: String, hash: String },
+    /// Mark a user's email address as verified
+    VerifyEmail(String),
 }
 
 impl Query for UserQuery {
     fn has_result(&self) -> bool {
-        match self {
-            UserQuery::GetByEmail(_) | UserQuery::GetByHandle(_) | UserQuery::GetByID(_) => true,
-            UserQuery::GetAvatar(_) => true,
-            _ => false
-        }
+        matches!(self, UserQuery::GetByEmail(_) | UserQuery::GetByHandle(_) | UserQuery::GetByID(_) | UserQuery::GetAvatar(_) | UserQuery::RecordFailedLogin(_))
     }
 }
 
 impl DBQuery for UserQuery {
+    type Output = Option<User>;
+
     fn to_sql_query_string(&self) -> (String, Vec<String>) {
         match self {
             Self::GetByEmail(email) => ("SELECT * FROM users WHERE email = ?".to_string(), vec![email.clone()]),
             Self::GetByHandle(handle) => ("SELECT * FROM users WHERE handle = ?".to_string(), vec![handle.clone()]),
             Self::GetByID(id) => ("SELECT * FROM users WHERE userid = ?".to_string(), vec![id.clone()]),
+            Self::RecordLogin(id) => (
+                "UPDATE users SET failed_login_attempts = 0, locked_until = ?, last_login = ? WHERE userid = ?;".to_string(),
+                vec![DateTime::<Utc>::from_timestamp_millis(0).unwrap().to_rfc3339(), Utc::now().to_rfc3339(), id.clone()]
+            ),
+            // Counting in the database means failed logins made at the same time are all counted
+            Self::RecordFailedLogin(id) => (
+                "UPDATE users SET failed_login_attempts = failed_login_attempts + 1 WHERE userid = ?\nRETURNING *;".to_string(),
+                vec![id.clone()]
+            ),
+            // Both are RFC 3339 in UTC, so they compare as strings
+            Self::LockUntil { id, until } => (
+                "UPDATE users SET locked_until = MAX(locked_until, ?) WHERE userid = ?;".to_string(),
+                vec![until.to_rfc3339(), id.clone()]
+            ),
+            Self::SetPassword { id, previous, hash } => (
+                "UPDATE users SET password = ? WHERE userid = ? AND password = ?\nRETURNING *;".to_string(),
+                vec![hash.clone(), id.clone(), previous.clone()]
+            ),
+            Self::ResetPassword { id, previous, hash } => (
+                "UPDATE users SET password = ?, failed_login_attempts = 0, locked_until = ? WHERE userid = ? AND password = ?\nRETURNING *;".to_string(),
+                vec![hash.clone(), DateTime::<Utc>::from_timestamp_millis(0).unwrap().to_rfc3339(), id.clone(), previous.clone()]
+            ),
+            Self::VerifyEmail(id) => (
+                "UPDATE users SET email_verified = 1 WHERE userid = ?;".to_string(),
+                vec![id.clone()]
+            ),
             Self::PutToDB(user) => {
                 let mut read_desc = Cursor::new(user.description.clone());
                 let mut compressed_desc = Vec::with_capacity((user.description.len() as f64 / 1.3).ceil() as usize);
@@ -106,46 +276,12 @@ impl DBQuery for UserQuery {
                         user.failed_login_attempts.to_string(),
                         user.locked_until.to_rfc3339(),
                         user.timezone.to_string(),
-                        user.enrolled_chats.join(",")
+                        // Chats are enrolled in through chat_members instead
+                        String::new()
                     ]
                 )
             },
 
-            Self::UpdateRemote(user) => {
-                let mut read_desc = Cursor::new(user.description.clone());
-                let mut compressed_desc = Vec::with_capacity((user.description.len() as f64 / 1.3).ceil() as usize);
-
-                BrotliCompress(&mut read_desc, &mut compressed_desc, &Default::default()).unwrap();
-
-                (
-                    "UPDATE users\nSET email = ?, handle = ?, password = ?, age = ?, country = ?, preferences = ?, suspended = ?, age_verified = ?, userid = ?, phone_number = ?, joined = ?, description = ?, last_agent = ?, last_approx_country = ?, avatar_url = ?, email_verified = ?, last_login = ?, failed_login_attempts = ?, locked_until = ?, timezone = ?, enrolled_chats = ?\nWHERE userid = ?;".to_string(),
-                    vec![
-                        user.email.clone(),
-                        user.handle.clone(),
-                        user.password.clone(),
-                        user.age.to_string(),
-                        user.country.clone(),
-                        user.preferences.clone(),
-                        (user.suspended as u8).to_string(),
-                        (user.age_verified as u8).to_string(),
-                        user.user_id.clone(),
-                        user.phone_number.clone(),
-                        user.joined.to_rfc3339(),
-                        GeneralPurpose::new(&Alphabet::new("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890+/").unwrap(), Default::default()).encode(compressed_desc),
-                        user.last_agent.clone(),
-                        user.last_approx_country.clone(),
-                        user.avatar_url.clone(),
-                        (user.email_verified as u8).to_string(),
-                        user.last_login.to_rfc3339(),
-                        user.failed_login_attempts.to_string(),
-                        user.locked_until.to_rfc3339(),
-                        user.timezone.to_string(),
-                        user.enrolled_chats.join(","),
-                        user.user_id.clone()
-                    ]
-                )
-            },
-            
             _ => panic!("Cannot convert to SQL query string for this query type.")
         }
     }
@@ -176,4 +312,106 @@ impl R2Query for UserQuery {
             _ => panic!("Cannot make R2 query for this query type.")
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::KolloquyDB;
+    use crate::test_support::{memory_db, test_user};
+
+    /// A handle to a new database with `user_id` stored in it.
+    async fn db_with_user(user_id: &str) -> (KolloquyDB<'static>, User) {
+        let db = memory_db();
+        let user = test_user(user_id);
+
+        db.execute(&UserQuery::PutToDB(user.clone())).await.unwrap();
+
+        (db, user)
+    }
+
+    #[tokio::test]
+    async fn failed_logins_are_counted_until_one_succeeds() {
+        let (db, user) = db_with_user("xy12abc").await;
+
+        // Failed logins at the same time are each counted
+        let failed = UserQuery::RecordFailedLogin(user.user_id.clone());
+
+        futures::future::join_all((0..3).map(|_| db.execute(&failed))).await;
+
+        let fetched = db.execute(&UserQuery::GetByID(user.user_id.clone())).await.unwrap().unwrap();
+
+        assert_eq!(fetched.failed_login_attempts, 3);
+
+        // Locks are only ever extended
+        let until = Utc::now() + TimeDelta::minutes(5);
+
+        db.execute(&UserQuery::LockUntil { id: user.user_id.clone(), until }).await.unwrap();
+        db.execute(&UserQuery::LockUntil { id: user.user_id.clone(), until: Utc::now() }).await.unwrap();
+
+        let fetched = db.execute(&UserQuery::GetByID(user.user_id.clone())).await.unwrap().unwrap();
+
+        assert_eq!(fetched.locked_until.timestamp(), until.timestamp());
+
+        db.execute(&UserQuery::RecordLogin(user.user_id.clone())).await.unwrap();
+
+        let fetched = db.execute(&UserQuery::GetByID(user.user_id.clone())).await.unwrap().unwrap();
+
+        assert_eq!(fetched.failed_login_attempts, 0);
+        assert!(fetched.locked_for().is_none());
+    }
+
+    #[tokio::test]
+    async fn reset_links_change_the_password_once() {
+        let (db, user) = db_with_user("xy12abc").await;
+
+        // Only the first of two resets from the same link changes the password
+        let reset = |hash: &str| UserQuery::ResetPassword { id: user.user_id.clone(), previous: user.password.clone(), hash: hash.to_string() };
+
+        assert!(db.execute(&reset("first")).await.unwrap().is_some());
+        assert!(db.execute(&reset("second")).await.unwrap().is_none());
+
+        let fetched = db.execute(&UserQuery::GetByID(user.user_id.clone())).await.unwrap().unwrap();
+
+        assert_eq!(fetched.password, "first");
+    }
+
+    #[tokio::test]
+    async fn emails_are_verified() {
+        let (db, user) = db_with_user("xy12abc").await;
+
+        db.execute(&UserQuery::VerifyEmail(user.user_id.clone())).await.unwrap();
+
+        let fetched = db.execute(&UserQuery::GetByID(user.user_id.clone())).await.unwrap().unwrap();
+
+        assert!(fetched.email_verified);
+    }
+
+    #[test]
+    fn lockouts_back_off_exponentially() {
+        let policy = LockoutPolicy {
+            threshold: 3,
+            base: TimeDelta::seconds(30),
+            max: TimeDelta::minutes(5),
+        };
+
+        assert_eq!(policy.lock_duration(2), None);
+        assert_eq!(policy.lock_duration(3), Some(TimeDelta::seconds(30)));
+        assert_eq!(policy.lock_duration(4), Some(TimeDelta::seconds(60)));
+        assert_eq!(policy.lock_duration(6), Some(TimeDelta::seconds(240)));
+        assert_eq!(policy.lock_duration(7), Some(TimeDelta::minutes(5)));
+        assert_eq!(policy.lock_duration(100), Some(TimeDelta::minutes(5)));
+    }
+
+    #[test]
+    fn descriptions_are_decoded() {
+        let mut compressed = Vec::new();
+
+        BrotliCompress(&mut Cursor::new("hello there"), &mut compressed, &Default::default()).unwrap();
+
+        let engine = GeneralPurpose::new(&Alphabet::new("abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ1234567890+/").unwrap(), Default::default());
+
+        assert_eq!(decode_description(engine.encode(compressed)), "hello there");
+        assert_eq!(decode_description("".to_string()), "");
+    }
+}